@@ -0,0 +1,155 @@
+//! Time-range lookup benchmark over a synthetic locations table.
+//!
+//! Compares the original unindexed range query with the bounded query DB::get_locations
+//! issues since schema version 7. Run with `cargo run --release` from bench/.
+
+use rusqlite::{params, Connection, Result};
+use std::time::{Duration, Instant};
+
+const ROWS: i64 = 100_000;
+const OWNERS: i64 = 200;
+const QUERIES: usize = 1_000;
+const DAY: i64 = 86_400;
+const EPOCH: i64 = 1_500_000_000;
+const SPAN: i64 = 10 * 365 * DAY; // ten years of trips
+
+// mirrors migration 1 (and the columns added by 2 and 3)
+const CREATE_LOCATIONS_TABLE: &str = "
+CREATE TABLE locations (
+    uuid TEXT PRIMARY KEY,
+    start_date INTEGER NOT NULL,
+    end_date INTEGER,
+    owner TEXT NOT NULL,
+    description TEXT,
+    latitude REAL NOT NULL,
+    longitude REAL NOT NULL,
+    photos TEXT,
+    visibility TEXT,
+    public_summary TEXT
+);";
+
+// mirrors migration 7
+const CREATE_INDEXES: &str = "
+CREATE INDEX idx_locations_owner ON locations (owner);
+CREATE INDEX idx_locations_start_end ON locations (start_date, end_date);
+CREATE INDEX idx_locations_end_date ON locations (end_date);
+CREATE INDEX idx_locations_duration ON locations ((end_date - start_date));";
+
+const UNBOUNDED: &str =
+    "SELECT * FROM locations WHERE start_date <= ?1 AND (end_date >= ?2 OR end_date IS NULL)";
+
+// mirrors DB::get_locations with both start and end set
+const BOUNDED: &str = "SELECT * FROM locations WHERE \
+    ((start_date BETWEEN ?3 AND ?1 AND end_date >= ?2) OR (end_date IS NULL AND start_date <= ?1))";
+
+/// xorshift64, deterministic so runs are comparable.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, below: i64) -> i64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % below as u64) as i64
+    }
+}
+
+fn populate(db: &mut Connection) -> Result<()> {
+    db.execute_batch(CREATE_LOCATIONS_TABLE)?;
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let tx = db.transaction()?;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO locations (uuid, start_date, end_date, owner, description, latitude, longitude, photos)
+             VALUES (?, ?, ?, ?, '', ?, ?, '[]')",
+        )?;
+        for i in 0..ROWS {
+            let start = EPOCH + rng.next(SPAN);
+            // mostly short stays, the odd multi-month one
+            let duration = if rng.next(100) == 0 {
+                rng.next(180 * DAY)
+            } else {
+                rng.next(14 * DAY)
+            };
+            insert.execute(params![
+                format!("{i:08}"),
+                start,
+                start + duration,
+                format!("owner{}.os", rng.next(OWNERS)),
+                rng.next(180) as f64 - 90.0,
+                rng.next(360) as f64 - 180.0,
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Time-slider style windows: one to thirty days anywhere in the data.
+fn windows() -> Vec<(i64, i64)> {
+    let mut rng = Rng(42);
+    (0..QUERIES)
+        .map(|_| {
+            let start = EPOCH + rng.next(SPAN);
+            (start, start + DAY + rng.next(30 * DAY))
+        })
+        .collect()
+}
+
+fn run(db: &Connection, sql: &str, windows: &[(i64, i64)]) -> Result<(Duration, usize)> {
+    let max_duration: i64 = db.query_row(
+        "SELECT COALESCE(MAX(end_date - start_date), 0) FROM locations",
+        [],
+        |row| row.get(0),
+    )?;
+    let mut statement = db.prepare(sql)?;
+    let mut rows = 0;
+    let started = Instant::now();
+    for &(start, end) in windows {
+        let all = [end, start, start - max_duration];
+        // the unbounded query only takes ?1 and ?2
+        let bound = &all[..statement.parameter_count()];
+        rows += statement
+            .query_map(rusqlite::params_from_iter(bound), |_| Ok(()))?
+            .count();
+    }
+    Ok((started.elapsed(), rows))
+}
+
+fn plan(db: &Connection, sql: &str) -> Result<Vec<String>> {
+    let mut statement = db.prepare(&format!("EXPLAIN QUERY PLAN {sql}"))?;
+    let zeros = vec![0; statement.parameter_count()];
+    let rows = statement.query_map(rusqlite::params_from_iter(zeros), |row| {
+        row.get::<_, String>(3)
+    })?;
+    rows.collect()
+}
+
+fn report(db: &Connection, label: &str, windows: &[(i64, i64)]) -> Result<()> {
+    println!("{label}");
+    for (name, sql) in [("unbounded", UNBOUNDED), ("bounded", BOUNDED)] {
+        let (elapsed, rows) = run(db, sql, windows)?;
+        println!(
+            "  {name:<10} {:>9.1} us/query  ({rows} rows over {} queries)  plan: {}",
+            elapsed.as_secs_f64() * 1e6 / windows.len() as f64,
+            windows.len(),
+            plan(db, sql)?.join("; "),
+        );
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let mut db = Connection::open_in_memory()?;
+    populate(&mut db)?;
+    let windows = windows();
+
+    report(&db, &format!("{ROWS} rows, no indexes"), &windows)?;
+    db.execute_batch(CREATE_INDEXES)?;
+    db.execute_batch("ANALYZE")?;
+    report(
+        &db,
+        &format!("{ROWS} rows, schema version 7 indexes"),
+        &windows,
+    )?;
+    Ok(())
+}
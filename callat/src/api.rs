@@ -0,0 +1,96 @@
+use kinode_process_lib::NodeId;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{Friend, TierName};
+
+// request bodies, anything not matching its struct is a 400
+
+/// `{"node_id": "bob.os"}`, for pings and friend requests that need no tier.
+#[derive(Debug, Deserialize)]
+pub struct NodeIdRequest {
+    pub node_id: NodeId,
+}
+
+/// Sending or accepting a friend request, `friend_type` must be a known tier.
+#[derive(Debug, Deserialize)]
+pub struct FriendTypeRequest {
+    pub node_id: NodeId,
+    pub friend_type: TierName,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CustomListRequest {
+    pub list_name: String,
+    pub node_id: NodeId,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TierNameRequest {
+    pub name: TierName,
+}
+
+/// `{"ids": [1, 2]}` marks those read, `{}` marks everything read.
+#[derive(Debug, Deserialize)]
+pub struct MarkReadRequest {
+    #[serde(default)]
+    pub ids: Option<Vec<i64>>,
+}
+
+/// Where new notifications are forwarded, `{"process": null}` to stop. Also the response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotifyPushRequest {
+    pub process: Option<String>,
+}
+
+// response bodies
+
+#[derive(Debug, Serialize)]
+pub struct MessageResponse {
+    pub message: String,
+}
+
+impl MessageResponse {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PendingFriendsResponse<'a> {
+    pub incoming: Vec<&'a Friend>,
+    pub outgoing: Vec<&'a Friend>,
+}
+
+/// Ids of the uploaded photos in upload order, to list in `Location.photos`.
+#[derive(Debug, Serialize)]
+pub struct PhotoUploadResponse {
+    pub photos: Vec<String>,
+    /// None if no photo's EXIF tells when or where it was taken
+    pub suggestion: Option<LocationSuggestion>,
+}
+
+/// A location for uploaded photos from their EXIF, to fill in a new one with: from the
+/// first to the last taken, where the first with GPS was. A field no photo tells is null.
+#[derive(Debug, Serialize)]
+pub struct LocationSuggestion {
+    pub start_date: Option<i64>,
+    pub end_date: Option<i64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub city: Option<String>, // nearest to the position
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+}
+
+/// The body of every error, `code` is one of bad_request, not_found, conflict,
+/// invalid or internal.
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub code: &'static str,
+}
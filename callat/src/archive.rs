@@ -0,0 +1,472 @@
+use anyhow::Result;
+use chrono::Utc;
+use kinode_process_lib::{Address, NodeId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::error::ApiError;
+use crate::state::{
+    validate_tier, CustomLists, Friend, Location, State, Tier, CALENDAR_TOKEN, DEFAULT_TIER,
+    FUZZ_KEY, SAVED_STATE_IMPORTED,
+};
+
+/// Bump when the archive layout changes, and keep `import` reading older versions.
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// Everything needed to move an account to a new node.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Archive {
+    pub version: u32,
+    pub exported_at: i64,
+    pub node: NodeId, // the node that exported it, own locations are re-owned on import
+    pub locations: Vec<Location>,
+    pub friends: Vec<Friend>,
+    pub custom_lists: CustomLists,
+    pub tiers: Vec<Tier>,
+    /// includes the fuzz key, so friends keep seeing the same fuzzed points after a move
+    pub settings: HashMap<String, String>,
+}
+
+/// What to do with an archived item that already exists on this node.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OnConflict {
+    #[default]
+    Skip, // keep what's here
+    Overwrite, // take the archive's version
+    Fail,      // import nothing if anything conflicts
+}
+
+impl OnConflict {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            "fail" => Ok(Self::Fail),
+            _ => Err(ApiError::BadRequest(
+                "invalid on_conflict: expected skip, overwrite or fail".into(),
+            )
+            .into()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct ImportCounts {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct ImportReport {
+    pub locations: ImportCounts,
+    pub friends: ImportCounts,
+    pub tiers: ImportCounts,
+    pub settings: ImportCounts,
+    pub custom_list_members: usize, // lists are merged, only members added are counted
+}
+
+pub fn export(our: &Address, state: &State) -> Result<Archive> {
+    let mut settings = state.db.get_settings()?;
+    settings.remove(SAVED_STATE_IMPORTED); // bookkeeping for this node only
+    settings.remove(CALENDAR_TOKEN); // subscribed URLs point at this node anyway
+    let mut tiers: Vec<Tier> = state.tiers.values().cloned().collect();
+    tiers.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(Archive {
+        version: ARCHIVE_VERSION,
+        exported_at: Utc::now().timestamp(),
+        node: our.node().to_string(),
+        // friends' locations come back on the next sync, only ours are irreplaceable
+        locations: state.get_locations_by_owner(&our.node().to_string())?,
+        friends: state.friends.values().cloned().collect(),
+        custom_lists: state.custom_lists.clone(),
+        tiers,
+        settings,
+    })
+}
+
+/// Restores an archive into this node in one transaction.
+///
+/// Conflicts are found before anything is written, so `OnConflict::Fail` leaves the
+/// node untouched.
+pub fn import(
+    our: &Address,
+    state: &mut State,
+    archive: Archive,
+    on_conflict: OnConflict,
+) -> Result<ImportReport> {
+    if archive.version > ARCHIVE_VERSION {
+        return Err(ApiError::Invalid(format!(
+            "invalid archive: version {} is newer than this node supports ({})",
+            archive.version, ARCHIVE_VERSION
+        ))
+        .into());
+    }
+    for tier in &archive.tiers {
+        validate_tier(tier)?;
+    }
+    let our_node = our.node().to_string();
+    let settings = state.db.get_settings()?;
+
+    let locations: Vec<(Location, bool)> = archive
+        .locations
+        .into_iter()
+        .filter(|location| location.owner == archive.node)
+        .map(|location| {
+            let exists = state.get_location(&location.uuid)?.is_some();
+            Ok((
+                Location {
+                    owner: our_node.clone(),
+                    ..location
+                },
+                exists,
+            ))
+        })
+        .collect::<Result<_>>()?;
+    let friends: Vec<(Friend, bool)> = archive
+        .friends
+        .into_iter()
+        .filter(|friend| friend.node_id != our_node)
+        .map(|friend| {
+            let exists = state.friends.contains_key(&friend.node_id);
+            (friend, exists)
+        })
+        .collect();
+    let tiers: Vec<(Tier, bool)> = archive
+        .tiers
+        .into_iter()
+        .map(|tier| {
+            let exists = state.tiers.contains_key(&tier.name);
+            (tier, exists)
+        })
+        .collect();
+    let archived_settings: Vec<(String, String, bool)> = archive
+        .settings
+        .into_iter()
+        .filter(|(key, _)| key != SAVED_STATE_IMPORTED && key != CALENDAR_TOKEN)
+        .map(|(key, value)| {
+            // an identical value isn't a conflict, nor is a fuzz key no friend has seen output from
+            let exists = settings.get(&key).is_some_and(|current| current != &value)
+                && !(key == FUZZ_KEY && state.friends.is_empty());
+            (key, value, exists)
+        })
+        .collect();
+
+    let conflicts = locations.iter().filter(|(_, exists)| *exists).count()
+        + friends.iter().filter(|(_, exists)| *exists).count()
+        + tiers.iter().filter(|(_, exists)| *exists).count()
+        + archived_settings
+            .iter()
+            .filter(|(_, _, exists)| *exists)
+            .count();
+    if on_conflict == OnConflict::Fail && conflicts > 0 {
+        return Err(
+            ApiError::Conflict(format!("{} items already exist on this node", conflicts)).into(),
+        );
+    }
+    let should_write = |exists: bool, counts: &mut ImportCounts| {
+        let write = !exists || on_conflict == OnConflict::Overwrite;
+        if write {
+            counts.imported += 1;
+        } else {
+            counts.skipped += 1;
+        }
+        write
+    };
+
+    let mut report = ImportReport::default();
+    let tx_id = state.db.inner.begin_tx()?;
+    for (tier, exists) in &tiers {
+        if should_write(*exists, &mut report.tiers) {
+            state.db.upsert_tier(tier, Some(tx_id))?;
+        }
+    }
+    let known_tiers: Vec<&String> = state
+        .tiers
+        .keys()
+        .chain(tiers.iter().map(|(tier, _)| &tier.name))
+        .collect();
+    for (friend, exists) in friends {
+        if should_write(exists, &mut report.friends) {
+            let friend = if known_tiers.contains(&&friend.friend_type) {
+                friend
+            } else {
+                Friend {
+                    friend_type: DEFAULT_TIER.to_string(),
+                    ..friend
+                }
+            };
+            state.db.upsert_friend(&friend, Some(tx_id))?;
+        }
+    }
+    for (list_name, node_ids) in &archive.custom_lists {
+        let mut members = state
+            .custom_lists
+            .get(list_name)
+            .cloned()
+            .unwrap_or_default();
+        for node_id in node_ids {
+            if members.contains(node_id) {
+                continue;
+            }
+            state
+                .db
+                .add_to_custom_list(list_name, node_id, Some(tx_id))?;
+            members.push(node_id.clone());
+            report.custom_list_members += 1;
+        }
+    }
+    for (location, exists) in &locations {
+        if should_write(*exists, &mut report.locations) {
+            state.db.upsert_location(location, Some(tx_id))?;
+        }
+    }
+    for (key, value, exists) in &archived_settings {
+        if should_write(*exists, &mut report.settings) {
+            state.db.set_setting(key, value, Some(tx_id))?;
+        }
+    }
+    state.db.inner.commit_tx(tx_id)?;
+    state.backfill_timezones()?; // archives from before locations had one
+
+    // the in-memory copies are stale now
+    state.friends = state.db.get_friends()?;
+    state.custom_lists = state.db.get_custom_lists()?;
+    state.tiers = state.db.get_tiers()?;
+    if let Some(fuzz_key) = state.db.get_setting(FUZZ_KEY)? {
+        state.geo_protocol.fuzz_key = serde_json::from_str(&fuzz_key)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geocity::GranularityProtocol;
+    use crate::photos::PhotoSize;
+    use crate::state::{
+        DescriptionPolicy, LocationGranularity, PhotoPolicy, TimeGranularity, Visibility,
+        NOTIFY_PROCESS,
+    };
+    use kinode_process_lib::ProcessId;
+    use uuid::Uuid;
+
+    const MOVED: Uuid = Uuid::from_u128(1);
+
+    fn our() -> Address {
+        Address::new(
+            "new.os",
+            ProcessId::new(Some("callat"), "callat", "template.os"),
+        )
+    }
+
+    fn fuzz_key(byte: u8) -> String {
+        serde_json::to_string(&[byte; 32]).unwrap()
+    }
+
+    fn location(uuid: Uuid, owner: &str, description: &str) -> Location {
+        Location {
+            uuid,
+            start_date: 1_720_000_000,
+            end_date: 1_720_100_000,
+            owner: owner.into(),
+            description: description.into(),
+            latitude: 38.72,
+            longitude: -9.14,
+            photos: vec![],
+            visibility: Visibility::default(),
+            public_summary: String::new(),
+            timezone: "Europe/Lisbon".into(),
+            shared_as: None,
+            recurrence: None,
+            is_home: false,
+        }
+    }
+
+    fn friend(node_id: &str, tier: &str) -> Friend {
+        Friend {
+            node_id: node_id.into(),
+            friend_type: tier.into(),
+            last_pinged: 0,
+        }
+    }
+
+    fn tier(name: &str) -> Tier {
+        Tier {
+            name: name.into(),
+            location: LocationGranularity::Country,
+            time: TimeGranularity::Month,
+            description: DescriptionPolicy::Hidden,
+            photos: PhotoPolicy::Hidden,
+            photo_size: PhotoSize::Thumb,
+        }
+    }
+
+    /// A node with one location, bob as a friend on the hiking list, and settings.
+    fn existing() -> State {
+        let mut state = State::in_memory(GranularityProtocol::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            [7; 32],
+        ));
+        state
+            .db
+            .insert_location(&location(MOVED, "new.os", "here"))
+            .unwrap();
+        let bob = friend("bob.os", "Acquaintance");
+        state.db.upsert_friend(&bob, None).unwrap();
+        state.friends.insert(bob.node_id.clone(), bob);
+        state
+            .add_to_custom_list("hiking".into(), "bob.os".into())
+            .unwrap();
+        state.db.set_setting(FUZZ_KEY, &fuzz_key(7), None).unwrap();
+        state
+            .db
+            .set_setting(NOTIFY_PROCESS, "notify:notify:sys", None)
+            .unwrap();
+        state
+    }
+
+    fn archive() -> Archive {
+        Archive {
+            version: ARCHIVE_VERSION,
+            exported_at: 1_720_200_000,
+            node: "old.os".into(),
+            locations: vec![
+                location(MOVED, "old.os", "archived"),
+                location(Uuid::from_u128(2), "old.os", "new"),
+                // a friend's, they'll sync it again
+                location(Uuid::from_u128(3), "bob.os", "theirs"),
+            ],
+            friends: vec![
+                friend("bob.os", "CloseFriend"),
+                friend("carol.os", "Climbing"),
+                friend("dave.os", "Family"),
+            ],
+            custom_lists: HashMap::from([
+                ("hiking".into(), vec!["bob.os".into(), "carol.os".into()]),
+                ("climbing".into(), vec!["carol.os".into()]),
+            ]),
+            tiers: vec![tier("Acquaintance"), tier("Family")],
+            settings: HashMap::from([
+                (FUZZ_KEY.into(), fuzz_key(9)),
+                (NOTIFY_PROCESS.into(), "other:other:sys".into()),
+                (SAVED_STATE_IMPORTED.into(), "true".into()),
+            ]),
+        }
+    }
+
+    fn counts(counts: &ImportCounts) -> (usize, usize) {
+        (counts.imported, counts.skipped)
+    }
+
+    fn description(state: &State, uuid: Uuid) -> String {
+        state.get_location(&uuid).unwrap().unwrap().description
+    }
+
+    #[test]
+    fn skip_keeps_what_is_here() {
+        let mut state = existing();
+        let report = import(&our(), &mut state, archive(), OnConflict::Skip).unwrap();
+        assert_eq!(counts(&report.locations), (1, 1));
+        assert_eq!(counts(&report.friends), (2, 1));
+        assert_eq!(counts(&report.tiers), (1, 1));
+        // bob has seen points fuzzed with our key, so theirs is a conflict too
+        assert_eq!(counts(&report.settings), (0, 2));
+        // bob was already hiking
+        assert_eq!(report.custom_list_members, 2);
+
+        assert_eq!(description(&state, MOVED), "here");
+        let new = state.get_location(&Uuid::from_u128(2)).unwrap().unwrap();
+        assert_eq!(new.owner, "new.os");
+        assert!(state.get_location(&Uuid::from_u128(3)).unwrap().is_none());
+        assert_eq!(state.friends["bob.os"].friend_type, "Acquaintance");
+        // a tier the archive doesn't define falls back to the default, one it does is kept
+        assert_eq!(state.friends["carol.os"].friend_type, DEFAULT_TIER);
+        assert_eq!(state.friends["dave.os"].friend_type, "Family");
+        assert_eq!(
+            state.tiers["Acquaintance"].location,
+            LocationGranularity::Country
+        );
+        assert_eq!(state.custom_lists["hiking"].len(), 2);
+        assert_eq!(state.geo_protocol.fuzz_key, [7; 32]);
+    }
+
+    #[test]
+    fn overwrite_takes_the_archives_version() {
+        let mut state = existing();
+        let report = import(&our(), &mut state, archive(), OnConflict::Overwrite).unwrap();
+        assert_eq!(counts(&report.locations), (2, 0));
+        assert_eq!(counts(&report.friends), (3, 0));
+        assert_eq!(counts(&report.tiers), (2, 0));
+        assert_eq!(counts(&report.settings), (2, 0));
+        assert_eq!(report.custom_list_members, 2);
+
+        assert_eq!(description(&state, MOVED), "archived");
+        assert_eq!(state.friends["bob.os"].friend_type, "CloseFriend");
+        assert_eq!(
+            state.tiers["Acquaintance"].location,
+            LocationGranularity::Country
+        );
+        assert_eq!(
+            state.db.get_setting(NOTIFY_PROCESS).unwrap().as_deref(),
+            Some("other:other:sys")
+        );
+        assert_eq!(state.geo_protocol.fuzz_key, [9; 32]);
+        assert!(state
+            .db
+            .get_setting(SAVED_STATE_IMPORTED)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn fail_writes_nothing_on_a_conflict() {
+        let mut state = existing();
+        let e = import(&our(), &mut state, archive(), OnConflict::Fail).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(ApiError::Conflict(_))));
+        assert!(e.to_string().contains("5 items"));
+
+        assert_eq!(description(&state, MOVED), "here");
+        assert!(state.get_location(&Uuid::from_u128(2)).unwrap().is_none());
+        assert_eq!(state.db.get_friends().unwrap().len(), 1);
+        assert!(!state.db.get_tiers().unwrap().contains_key("Family"));
+        assert_eq!(state.db.get_custom_lists().unwrap().len(), 1);
+        assert_eq!(state.db.get_setting(FUZZ_KEY).unwrap(), Some(fuzz_key(7)));
+    }
+
+    #[test]
+    fn a_fuzz_key_only_conflicts_once_friends_have_seen_it() {
+        let archive = || Archive {
+            locations: vec![],
+            friends: vec![],
+            custom_lists: HashMap::new(),
+            tiers: vec![],
+            settings: HashMap::from([(FUZZ_KEY.into(), fuzz_key(9))]),
+            ..archive()
+        };
+
+        let mut state = existing();
+        let e = import(&our(), &mut state, archive(), OnConflict::Fail).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(ApiError::Conflict(_))));
+
+        state.friends.clear();
+        let report = import(&our(), &mut state, archive(), OnConflict::Fail).unwrap();
+        assert_eq!(counts(&report.settings), (1, 0));
+        assert_eq!(state.geo_protocol.fuzz_key, [9; 32]);
+    }
+
+    #[test]
+    fn rejects_archives_from_a_newer_version() {
+        let mut state = existing();
+        let newer = Archive {
+            version: ARCHIVE_VERSION + 1,
+            ..archive()
+        };
+        let e = import(&our(), &mut state, newer, OnConflict::Overwrite).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(ApiError::Invalid(_))));
+        assert_eq!(description(&state, MOVED), "here");
+    }
+}
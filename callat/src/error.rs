@@ -0,0 +1,52 @@
+use kinode_process_lib::http::StatusCode;
+use std::fmt;
+
+/// An error the HTTP API answers with a specific status. Anything that isn't one
+/// of these, a failed database write say, is a 500.
+///
+/// Raise them where the problem is known, as `Err(ApiError::NotFound(..).into())`,
+/// and let `?` carry them through anyhow to `frontend::error_response`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApiError {
+    /// the request can't be read: missing or malformed body, a parameter that doesn't parse
+    BadRequest(String),
+    NotFound(String),
+    /// fine on its own but clashes with what's there, e.g. removing a tier still in use
+    Conflict(String),
+    /// well-formed but invalid: a latitude out of range, an end before its start
+    Invalid(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    /// Machine-readable `code` of the error body.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Invalid(_) => "invalid",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
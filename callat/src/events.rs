@@ -0,0 +1,23 @@
+use kinode_process_lib::NodeId;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{notifications::Notification, state::Location};
+
+/// Where the UI opens its websocket for live updates.
+pub const WS_PATH: &str = "/ws";
+
+/// Something the UI should hear about without polling, sent as JSON tagged by `type`,
+/// e.g. `{"type": "friend_request", "node_id": "bob.os"}`.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    FriendRequest { node_id: NodeId },
+    FriendAccepted { node_id: NodeId },
+    // friends' locations as they arrive, ours only change through the UI itself
+    LocationAdded { location: Location },
+    LocationUpdated { location: Location },
+    LocationDeleted { uuid: Uuid, owner: NodeId },
+    SyncCompleted { node_id: NodeId, locations: usize },
+    Notification { notification: Notification },
+}
@@ -1,42 +1,409 @@
-use crate::state::{Friend, FriendType, Location, NewLocation, State};
-use anyhow::{anyhow, Result};
-use kinode_process_lib::{get_blob, http, println, Address, LazyLoadBlob, Message};
-use serde_json::json;
+use crate::api::{
+    CustomListRequest, ErrorResponse, FriendTypeRequest, LocationSuggestion, MarkReadRequest,
+    MessageResponse, NodeIdRequest, NotifyPushRequest, PendingFriendsResponse, PhotoUploadResponse,
+    TierNameRequest, TokenResponse,
+};
+use crate::archive::{self, Archive, ImportReport, OnConflict};
+use crate::error::ApiError;
+use crate::events::WS_PATH;
+use crate::ics::{self, IcsImportReport};
+use crate::notifications::Notification;
+use crate::openapi::{self, json, json_array, param, Content, Param};
+use crate::overlap::{self, Overlap, PlanOverlaps, Window};
+use crate::photos::{self, Photo, PhotoSize};
+use crate::planner::{self, FreeWindow, PlanRequest};
+use crate::state::{
+    validate_location, validate_tier, BoundingBox, Circle, Friend, LocationFilter, LocationView,
+    NewLocation, SortBy, SortOrder, State, Tier, MAX_PAGE_SIZE, NOTIFY_PROCESS,
+};
+use anyhow::Result;
+use chrono_tz::Tz;
+use kinode_process_lib::{get_blob, http, println, Address, LazyLoadBlob, Message, ProcessId};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::str::FromStr;
 use uuid::Uuid;
 
+type Handler = fn(
+    http::server::IncomingHttpRequest,
+    &mut State,
+    &Address,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)>;
+
+/// An endpoint, bound, dispatched and documented from this one declaration. A `:name`
+/// segment in `path` matches any single segment, as in the http server's own bindings.
+pub struct Route {
+    pub method: &'static str,
+    pub path: &'static str,
+    pub authenticated: bool,
+    handler: Handler,
+    // for the OpenAPI document
+    pub query: &'static [Param],
+    pub request: Option<Content>,
+    pub response: Option<Content>,
+    /// may answer 202 while it fetches from a friend, to be asked again
+    pub deferred: bool,
+}
+
+const fn route(method: &'static str, path: &'static str, handler: Handler) -> Route {
+    Route {
+        method,
+        path,
+        authenticated: true,
+        handler,
+        query: &[],
+        request: None,
+        response: None,
+        deferred: false,
+    }
+}
+
+impl Route {
+    const fn query(self, query: &'static [Param]) -> Self {
+        Route { query, ..self }
+    }
+
+    const fn body(self, request: Content) -> Self {
+        Route {
+            request: Some(request),
+            ..self
+        }
+    }
+
+    const fn returns(self, response: Content) -> Self {
+        Route {
+            response: Some(response),
+            ..self
+        }
+    }
+
+    const fn deferred(self) -> Self {
+        Route {
+            deferred: true,
+            ..self
+        }
+    }
+
+    const fn public(self) -> Self {
+        Route {
+            authenticated: false,
+            ..self
+        }
+    }
+}
+
+const TZ: Param = param(
+    "tz",
+    "string",
+    "IANA zone to also render times in, as `viewer`",
+);
+
+static ROUTES: &[Route] = &[
+    route("GET", "/api/locations", |req, state, _| {
+        handle_get_locations(req, state)
+    })
+    .query(&[
+        param("start", "integer", "overlapping this range, unix seconds"),
+        param("end", "integer", "overlapping this range, unix seconds"),
+        param("bbox", "string", "west,south,east,north in degrees"),
+        param("lat", "number", "with lon and radius, within radius metres"),
+        param("lon", "number", "with lat and radius"),
+        param("radius", "number", "metres"),
+        param("owner", "string", "node id"),
+        param("tier", "string", "friends in this tier"),
+        param("list", "string", "friends in this custom list"),
+        param("q", "string", "full-text search of descriptions"),
+        param("sort", "string", "start_date or end_date"),
+        param("order", "string", "asc or desc"),
+        param(
+            "limit",
+            "integer",
+            "page size, the next page's cursor is in X-Next-Cursor",
+        ),
+        param("cursor", "string", "from X-Next-Cursor"),
+        TZ,
+    ])
+    .returns(json_array::<LocationView>),
+    route("POST", "/api/locations", handle_add_location)
+        .body(json::<NewLocation>)
+        .returns(json::<MessageResponse>),
+    route("GET", "/api/locations/:id", |req, state, _| {
+        handle_get_location(req, state)
+    })
+    .query(&[TZ])
+    .returns(json::<LocationView>),
+    route("PUT", "/api/locations/:id", handle_update_location)
+        .body(json::<NewLocation>)
+        .returns(json::<MessageResponse>),
+    route("DELETE", "/api/locations/:id", handle_delete_location).returns(json::<MessageResponse>),
+    route("POST", "/api/photos", |req, state, _| {
+        handle_upload_photos(req, state)
+    })
+    .query(&[param(
+        "tz",
+        "string",
+        "IANA zone camera clocks are read in for photos without GPS, UTC by default",
+    )])
+    .body(openapi::image_upload)
+    .returns(json::<PhotoUploadResponse>),
+    route("GET", "/api/photos/:id", |req, state, _| {
+        handle_get_photo(req, state)
+    })
+    .query(&[param(
+        "size",
+        "string",
+        "original (default), web or thumb, a friend's photo no larger than their tier for us",
+    )])
+    .returns(openapi::image)
+    .deferred(),
+    route("GET", "/api/photos/:id/info", |req, state, _| {
+        handle_get_photo_info(req, state)
+    })
+    .returns(json::<Photo>)
+    .deferred(),
+    route("GET", "/api/friends", |_, state, _| {
+        handle_get_friends(state)
+    })
+    .returns(json_array::<Friend>),
+    route("POST", "/api/friends", |_, state, _| {
+        handle_add_friend(state)
+    })
+    .body(json::<FriendTypeRequest>)
+    .returns(json::<MessageResponse>),
+    // the UI removes with DELETE, older clients POST to /remove
+    route("DELETE", "/api/friends", |_, state, _| {
+        handle_remove_friend(state)
+    })
+    .body(json::<NodeIdRequest>)
+    .returns(json::<MessageResponse>),
+    route("GET", "/api/friends/pending", |_, state, _| {
+        handle_get_pending_friends(state)
+    })
+    .returns(json::<PendingFriendsResponse<'static>>),
+    route("POST", "/api/friends/accept", |_, state, _| {
+        handle_accept_friend(state)
+    })
+    .body(json::<FriendTypeRequest>)
+    .returns(json::<MessageResponse>),
+    route("POST", "/api/friends/reject", |_, state, _| {
+        handle_reject_friend(state)
+    })
+    .body(json::<NodeIdRequest>)
+    .returns(json::<MessageResponse>),
+    route("POST", "/api/friends/remove", |_, state, _| {
+        handle_remove_friend(state)
+    })
+    .body(json::<NodeIdRequest>)
+    .returns(json::<MessageResponse>),
+    route("POST", "/api/friends/cancel", |_, state, _| {
+        handle_cancel_friend(state)
+    })
+    .body(json::<NodeIdRequest>)
+    .returns(json::<MessageResponse>),
+    route("POST", "/api/friends/ping", |_, state, _| {
+        handle_ping_friend(state)
+    })
+    .body(json::<NodeIdRequest>)
+    .returns(json::<MessageResponse>),
+    route("GET", "/api/tiers", |_, state, _| handle_get_tiers(state)).returns(json_array::<Tier>),
+    route("POST", "/api/tiers", |_, state, _| {
+        handle_upsert_tier(state)
+    })
+    .body(json::<Tier>)
+    .returns(json::<MessageResponse>),
+    route("POST", "/api/tiers/remove", |_, state, _| {
+        handle_remove_tier(state)
+    })
+    .body(json::<TierNameRequest>)
+    .returns(json::<MessageResponse>),
+    route("GET", "/api/export", |_, state, our| {
+        handle_export(state, our)
+    })
+    .returns(json::<Archive>),
+    route("POST", "/api/import", handle_import)
+        .query(&[param(
+            "on_conflict",
+            "string",
+            "skip (default), overwrite or fail",
+        )])
+        .body(json::<Archive>)
+        .returns(json::<ImportReport>),
+    route("POST", "/api/import/ics", |_, state, our| {
+        handle_import_ics(state, our)
+    })
+    .body(openapi::calendar)
+    .returns(json::<IcsImportReport>),
+    route("GET", "/api/overlaps", |req, state, our| {
+        handle_get_overlaps(req, state, our)
+    })
+    .query(&[
+        param("location", "string", "uuid of one of our locations"),
+        param(
+            "lat",
+            "number",
+            "with lon, start and end, an ad hoc window instead",
+        ),
+        param("lon", "number", "with lat, start and end"),
+        param("start", "integer", "with lat, lon and end, unix seconds"),
+        param("end", "integer", "with lat, lon and start, unix seconds"),
+        param("radius_km", "number", "how near counts as nearby"),
+    ])
+    // for a location or window its overlaps, otherwise every upcoming location of ours with its own
+    .returns(|components| {
+        openapi::content(serde_json::json!({
+            "oneOf": [
+                { "type": "array", "items": components.reference::<Overlap>() },
+                { "type": "array", "items": components.reference::<PlanOverlaps>() },
+            ]
+        }))
+    }),
+    route("GET", "/api/planner", |req, state, our| {
+        handle_get_free_windows(req, state, our)
+    })
+    .query(&[
+        openapi::required(param("start", "integer", "unix seconds")),
+        openapi::required(param("end", "integer", "unix seconds")),
+        param("friends", "string", "comma-separated node ids"),
+        param("include_self", "boolean", "true unless false"),
+        param("min_length", "integer", "seconds, a day by default"),
+        param(
+            "lat",
+            "number",
+            "with lon and radius_km, a destination that doesn't count as away",
+        ),
+        param("lon", "number", "with lat and radius_km"),
+        param("radius_km", "number", "with lat and lon"),
+    ])
+    .returns(json_array::<FreeWindow>),
+    route("GET", "/api/notifications", |req, state, _| {
+        handle_get_notifications(req, state)
+    })
+    .query(&[
+        param("unread", "boolean", "only unread ones"),
+        param("limit", "integer", "100 by default"),
+    ])
+    .returns(json_array::<Notification>),
+    route("POST", "/api/notifications/read", |_, state, _| {
+        handle_mark_notifications_read(state)
+    })
+    .body(json::<MarkReadRequest>)
+    .returns(json::<MessageResponse>),
+    route("GET", "/api/notifications/push", |_, state, _| {
+        handle_get_notification_push(state)
+    })
+    .returns(json::<NotifyPushRequest>),
+    route("POST", "/api/notifications/push", |_, state, _| {
+        handle_set_notification_push(state)
+    })
+    .body(json::<NotifyPushRequest>)
+    .returns(json::<NotifyPushRequest>),
+    route("GET", "/api/calendar/token", |_, state, _| {
+        handle_get_calendar_token(state)
+    })
+    .returns(json::<TokenResponse>),
+    route("POST", "/api/calendar/token", |_, state, _| {
+        handle_rotate_calendar_token(state)
+    })
+    .returns(json::<TokenResponse>),
+    // calendar apps can't log in, the feed checks its own token instead
+    route("GET", "/api/calendar.ics", |req, state, our| {
+        handle_calendar_feed(req, state, our)
+    })
+    .public()
+    .query(&[
+        openapi::required(param("token", "string", "from /api/calendar/token")),
+        param("friends", "boolean", "include friends' locations"),
+    ])
+    .returns(openapi::calendar),
+    route("POST", "/api/ping", |_, state, _| handle_ping(state))
+        .body(json::<NodeIdRequest>)
+        .returns(json::<MessageResponse>),
+    route("GET", "/api/custom_lists", |_, state, _| {
+        handle_get_custom_lists(state)
+    })
+    .returns(|_| {
+        openapi::content(serde_json::json!({
+            "type": "object",
+            "additionalProperties": { "type": "array", "items": { "type": "string" } },
+        }))
+    }),
+    route("POST", "/api/custom_lists", |_, state, _| {
+        handle_add_custom_list(state)
+    })
+    .body(json::<CustomListRequest>)
+    .returns(json::<MessageResponse>),
+    route("GET", "/api/openapi.json", |_, _, our| handle_openapi(our))
+        .returns(|_| openapi::content(serde_json::json!({ "type": "object" }))),
+];
+
+/// Each path to bind once, with whether it needs a login. Methods sharing a path share its binding.
+fn bound_paths() -> Vec<(&'static str, bool)> {
+    let mut paths: Vec<(&'static str, bool)> = Vec::new();
+    for route in ROUTES {
+        if !paths.iter().any(|(path, _)| *path == route.path) {
+            paths.push((route.path, route.authenticated));
+        }
+    }
+    paths
+}
+
+fn find_route(method: &str, path: &str) -> Option<&'static Route> {
+    ROUTES
+        .iter()
+        .find(|route| route.method == method && path_matches(route.path, path))
+}
+
+fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut pattern = pattern.split('/');
+    let mut path = path.split('/');
+    loop {
+        match (pattern.next(), path.next()) {
+            (None, None) => return true,
+            (Some(expected), Some(segment)) => {
+                let wildcard = expected.starts_with(':') && !segment.is_empty();
+                if !wildcard && expected != segment {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
 pub fn serve(our: &Address) -> http::server::HttpServer {
     let mut server = http::server::HttpServer::new(10);
     let config = http::server::HttpBindingConfig::default();
     server
         .serve_ui(our, "ui", vec!["/"], config.clone())
         .expect("failed to serve ui");
+    for (path, authenticated) in bound_paths() {
+        server
+            .bind_http_path(path, config.clone().authenticated(authenticated))
+            .unwrap_or_else(|e| panic!("failed to bind {}: {:?}", path, e));
+    }
     server
-        .bind_http_path("/api/locations", config.clone())
-        .expect("failed to bind /api/locations");
-    server
-        .bind_http_path("/api/friends/pending", config.clone())
-        .expect("failed to bind /api/friends/pending");
-    server
-        .bind_http_path("/api/friends", config.clone())
-        .expect("failed to bind /api/friends");
-    server
-        .bind_http_path("/api/friends/sync", config.clone())
-        .expect("failed to bind /api/friends/ping");
-    server
-        .bind_http_path("/api/friends/accept", config.clone())
-        .expect("failed to bind /api/friends/accept");
-    server
-        .bind_http_path("/api/friends/reject", config.clone())
-        .expect("failed to bind /api/friends/reject");
-    server
-        .bind_http_path("/api/friends/cancel", config.clone())
-        .expect("failed to bind /api/friends/cancel");
-    server
-        .bind_http_path("/api/locations/:id", config)
-        .expect("failed to bind /api/locations/:id");
+        .bind_ws_path(WS_PATH, http::server::WsBindingConfig::default())
+        .expect("failed to bind websocket");
     server
 }
 
+/// Sends the events queued while handling the last message to every open UI.
+pub fn push_events(server: &http::server::HttpServer, state: &mut State) {
+    for event in state.events.drain(..) {
+        match serde_json::to_vec(&event) {
+            Ok(bytes) => server.ws_push_all_channels(
+                WS_PATH,
+                http::server::WsMessageType::Text,
+                LazyLoadBlob {
+                    mime: Some("application/json".into()),
+                    bytes,
+                },
+            ),
+            Err(e) => println!("failed to serialize event: {:?}", e),
+        }
+    }
+}
+
 pub fn handle_request(
     our: &Address,
     server: &mut http::server::HttpServer,
@@ -52,26 +419,9 @@ pub fn handle_request(
             let path = req.path().unwrap_or_default();
 
             println!("method: {:?}, path: {:?}", method, path);
-            let result = match (method.as_str(), path.as_str()) {
-                ("GET", "/api/locations") => handle_get_locations(req, state),
-                ("POST", "/api/locations") => handle_add_location(req, state, our),
-                ("GET", p) if p.starts_with("/api/locations/") => handle_get_location(req, state),
-                ("PUT", p) if p.starts_with("/api/locations/") => {
-                    handle_update_location(req, state)
-                }
-                ("GET", "/api/friends") => handle_get_friends(state),
-                ("POST", "/api/friends") => handle_add_friend(state),
-                ("GET", "/api/friends/pending") => handle_get_pending_friends(state),
-                ("POST", "/api/friends/accept") => handle_accept_friend(state),
-                ("POST", "/api/friends/reject") => handle_reject_friend(state),
-                ("POST", "/api/friends/remove") => handle_remove_friend(state),
-                ("POST", "/api/friends/cancel") => handle_cancel_friend(state),
-                ("POST", "/api/friends/ping") => handle_ping_friend(state),
-
-                ("GET", "/api/ping") => handle_ping(state),
-                ("GET", "/api/custom_lists") => handle_get_custom_lists(state),
-                ("POST", "/api/custom_lists") => handle_add_custom_list(state),
-                _ => Err(anyhow!("Not Found")),
+            let result = match find_route(method.as_str(), &path) {
+                Some(route) => (route.handler)(req, state, our),
+                None => Err(ApiError::NotFound(format!("no route for {method} {path}")).into()),
             };
 
             match result {
@@ -79,7 +429,7 @@ pub fn handle_request(
                 Err(e) => error_response(e),
             }
         },
-        |_, _, _| {}, // ignore ws pushes for now
+        |_, _, _| {}, // the websocket only pushes, anything the UI sends is ignored
     );
 
     Ok(())
@@ -89,21 +439,60 @@ fn handle_get_locations(
     req: http::server::IncomingHttpRequest,
     state: &State,
 ) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
-    let start = req
-        .query_params()
-        .get("start")
-        .and_then(|s| s.parse::<i64>().ok());
-    let end = req
-        .query_params()
-        .get("end")
-        .and_then(|s| s.parse::<i64>().ok());
-
-    let locations = match (start, end) {
-        (Some(start), Some(end)) => state.get_locations_in_range(start, end)?,
-        _ => state.db.get_all_locations()?,
+    let params = req.query_params();
+    let near = match (
+        query_param::<f64>(params, "lat")?,
+        query_param::<f64>(params, "lon")?,
+        query_param::<f64>(params, "radius")?,
+    ) {
+        (Some(lat), Some(lon), Some(radius)) => Some(Circle::new(lat, lon, radius)?),
+        (None, None, None) => None,
+        _ => {
+            return Err(ApiError::BadRequest(
+                "invalid radius filter: lat, lon and radius go together".into(),
+            )
+            .into())
+        }
+    };
+    let filter = LocationFilter {
+        start: query_param(params, "start")?,
+        end: query_param(params, "end")?,
+        bbox: params
+            .get("bbox")
+            .map(|s| BoundingBox::parse(s))
+            .transpose()?,
+        near,
+        owner: params.get("owner").cloned(),
+        tier: params.get("tier").cloned(),
+        list: params.get("list").cloned(),
+        text: params.get("q").cloned(),
+        sort: params
+            .get("sort")
+            .map(|s| SortBy::parse(s))
+            .transpose()?
+            .unwrap_or_default(),
+        order: params
+            .get("order")
+            .map(|s| SortOrder::parse(s))
+            .transpose()?
+            .unwrap_or_default(),
+        limit: query_param::<u32>(params, "limit")?.map(|limit| limit.clamp(1, MAX_PAGE_SIZE)),
+        cursor: query_param(params, "cursor")?,
     };
+    let viewer = viewer_timezone(&req)?;
+    let page = state.get_locations(&filter)?;
+    let locations: Vec<LocationView> = page
+        .locations
+        .into_iter()
+        .map(|location| LocationView::new(location, viewer))
+        .collect();
 
-    ok_response(&locations)
+    // the body stays a plain array, the next page is linked through a header
+    let (response, blob) = ok_response(&locations)?;
+    Ok(match page.next_cursor {
+        Some(cursor) => (response.header("X-Next-Cursor", cursor.to_string()), blob),
+        None => (response, blob),
+    })
 }
 
 fn handle_add_location(
@@ -111,54 +500,29 @@ fn handle_add_location(
     state: &mut State,
     our: &Address,
 ) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
-    let body = get_blob().ok_or_else(|| anyhow!("no blob in request"))?;
-    let new_location: NewLocation = serde_json::from_slice(body.bytes())?;
-
-    let location = Location {
-        uuid: Uuid::new_v4(),
-        start_date: new_location.start_date,
-        end_date: new_location.end_date,
-        description: new_location.description,
-        latitude: new_location.latitude,
-        longitude: new_location.longitude,
-        owner: our.node().to_string(),
-        photos: new_location.photos,
-    };
-
-    println!("adding location: {:?}", location);
-    state.add_location(&location)?;
-    state.share_location(&location)?;
-    ok_response(&json!({"message": "location added successfully"}))
+    let new_location: NewLocation = parse_body()?;
+    state.create_location(&our.node().to_string(), Uuid::new_v4(), new_location)?;
+    ok_response(&MessageResponse::new("location added successfully"))
 }
 
 fn handle_ping(state: &mut State) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
-    let body = get_blob().ok_or_else(|| anyhow!("no blob in request"))?;
-    let data: serde_json::Value = serde_json::from_slice(body.bytes())?;
-    let node_id = data["node_id"]
-        .as_str()
-        .ok_or_else(|| anyhow!("invalid node ID"))?
-        .parse()?;
-
-    state.ping_node(node_id);
-
-    ok_response(&json!({"message": "ping sent successfully"}))
+    let NodeIdRequest { node_id } = parse_body()?;
+    validate_node_id(&node_id)?;
+    state.ping_node(node_id)?;
+    ok_response(&MessageResponse::new("ping sent successfully"))
 }
 
 fn handle_add_custom_list(
     state: &mut State,
 ) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
-    let body = get_blob().ok_or_else(|| anyhow!("no blob in request"))?;
-    let data: serde_json::Value = serde_json::from_slice(body.bytes())?;
-    let list_name = data["list_name"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid list name"))?;
-    let node_id = data["node_id"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid node ID"))?
-        .parse()?;
-
-    state.add_to_custom_list(list_name.to_string(), node_id);
-    ok_response(&json!({"message": "node {node_id} added to list {list_name} successfully"}))
+    let CustomListRequest { list_name, node_id } = parse_body()?;
+    if list_name.is_empty() {
+        return Err(ApiError::Invalid("invalid list_name: empty".into()).into());
+    }
+    validate_node_id(&node_id)?;
+    let message = format!("node {node_id} added to list {list_name} successfully");
+    state.add_to_custom_list(list_name, node_id)?;
+    ok_response(&MessageResponse::new(message))
 }
 
 fn handle_get_friends(state: &State) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
@@ -182,117 +546,85 @@ fn handle_get_pending_friends(
         .filter(|(_, is_local)| *is_local)
         .map(|(friend, _)| friend)
         .collect::<Vec<_>>();
-    ok_response(&json!({
-        "incoming": incoming,
-        "outgoing": outgoing
-    }))
+    ok_response(&PendingFriendsResponse { incoming, outgoing })
 }
 
 fn handle_cancel_friend(
     state: &mut State,
 ) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
-    let body = get_blob().ok_or_else(|| anyhow!("no blob in request"))?;
-    let data: serde_json::Value = serde_json::from_slice(body.bytes())?;
-    let node_id = data["node_id"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid node ID"))?;
-
-    state
-        .pending_friend_requests
-        .retain(|(friend, _)| friend.node_id != node_id);
-    let _ = state.save();
-
-    ok_response(&json!({"message": "friend request canceled successfully"}))
+    let NodeIdRequest { node_id } = parse_body()?;
+    state.cancel_friend_request(&node_id)?;
+    ok_response(&MessageResponse::new(
+        "friend request canceled successfully",
+    ))
 }
 
 fn handle_add_friend(
     state: &mut State,
 ) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
-    let body = get_blob().ok_or_else(|| anyhow!("no blob in request"))?;
-    let data: serde_json::Value = serde_json::from_slice(body.bytes())?;
-    // todo, maybe custom AddFriend type?
-    let node_id = data["node_id"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid node ID"))?
-        .parse()?;
-
-    let friend_type = data["friend_type"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid friend type"))?;
-
-    let friend_type = match friend_type {
-        "Best" => FriendType::Best,
-        "CloseFriend" => FriendType::CloseFriend,
-        "Acquaintance" => FriendType::Acquaintance,
-        _ => return Err(anyhow!("Invalid friend type")),
-    };
+    let FriendTypeRequest {
+        node_id,
+        friend_type,
+    } = parse_body()?;
+    validate_node_id(&node_id)?;
+    validate_friend_type(&friend_type, state)?;
+    state.send_friend_request(node_id, friend_type)?;
+    ok_response(&MessageResponse::new("friend added successfully"))
+}
 
-    state.send_friend_request(node_id, friend_type);
+fn validate_friend_type(friend_type: &str, state: &State) -> Result<()> {
+    if !state.tiers.contains_key(friend_type) {
+        return Err(ApiError::Invalid(format!("unknown friend_type: {}", friend_type)).into());
+    }
+    Ok(())
+}
 
-    ok_response(&json!({"message": "friend added successfully"}))
+/// Node ids are names like "bob.os", catch the obviously broken ones before messaging them.
+fn validate_node_id(node_id: &str) -> Result<()> {
+    if node_id.is_empty() || node_id.contains(|c: char| c.is_whitespace() || c == '@') {
+        return Err(ApiError::Invalid(format!("invalid node_id: {:?}", node_id)).into());
+    }
+    Ok(())
 }
 
 fn handle_accept_friend(
     state: &mut State,
 ) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
-    let body = get_blob().ok_or_else(|| anyhow!("no blob in request"))?;
-    let data: serde_json::Value = serde_json::from_slice(body.bytes())?;
-    let node_id = data["node_id"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid node ID"))?
-        .parse()?;
-
-    let friend_type = match data["friend_type"].as_str() {
-        Some("Best") => FriendType::Best,
-        Some("CloseFriend") => FriendType::CloseFriend,
-        Some("Acquaintance") => FriendType::Acquaintance,
-        _ => return Err(anyhow!("Invalid friend type")),
-    };
-
+    let FriendTypeRequest {
+        node_id,
+        friend_type,
+    } = parse_body()?;
+    validate_friend_type(&friend_type, state)?;
     state.accept_friend_request(node_id, friend_type)?;
-    ok_response(&json!({"message": "friend request accepted successfully"}))
+    ok_response(&MessageResponse::new(
+        "friend request accepted successfully",
+    ))
 }
 
 fn handle_ping_friend(
     state: &mut State,
 ) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
-    let body = get_blob().ok_or_else(|| anyhow!("no blob in request"))?;
-    let data: serde_json::Value = serde_json::from_slice(body.bytes())?;
-    let node_id = data["node_id"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid node ID"))?
-        .parse()?;
-
-    state.ping_friend(node_id);
-    ok_response(&json!({"message": "friend pinged successfully"}))
+    let NodeIdRequest { node_id } = parse_body()?;
+    state.ping_friend(node_id)?;
+    ok_response(&MessageResponse::new("friend pinged successfully"))
 }
 
 fn handle_reject_friend(
     state: &mut State,
 ) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
-    let body = get_blob().ok_or_else(|| anyhow!("no blob in request"))?;
-    let data: serde_json::Value = serde_json::from_slice(body.bytes())?;
-    let node_id = data["node_id"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid node ID"))?
-        .parse()?;
-
-    state.reject_friend_request(node_id);
-    ok_response(&json!({"message": "friend request rejected successfully"}))
+    let NodeIdRequest { node_id } = parse_body()?;
+    state.reject_friend_request(node_id)?;
+    ok_response(&MessageResponse::new(
+        "friend request rejected successfully",
+    ))
 }
 
 fn handle_remove_friend(
     state: &mut State,
 ) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
-    let body = get_blob().ok_or_else(|| anyhow!("no blob in request"))?;
-    let data: serde_json::Value = serde_json::from_slice(body.bytes())?;
-    let node_id = data["node_id"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid node ID"))?
-        .parse()?;
-
-    state.remove_friend(&node_id);
-    ok_response(&json!({"message": "friend removed successfully"}))
+    let NodeIdRequest { node_id } = parse_body()?;
+    state.remove_friend(&node_id)?;
+    ok_response(&MessageResponse::new("friend removed successfully"))
 }
 
 fn handle_get_custom_lists(
@@ -301,6 +633,28 @@ fn handle_get_custom_lists(
     ok_response(&state.custom_lists)
 }
 
+fn handle_get_tiers(state: &State) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let tiers: Vec<&Tier> = state.tiers.values().collect();
+    ok_response(&tiers)
+}
+
+fn handle_upsert_tier(
+    state: &mut State,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let tier: Tier = parse_body()?;
+    validate_tier(&tier)?;
+    state.upsert_tier(tier)?;
+    ok_response(&MessageResponse::new("tier saved successfully"))
+}
+
+fn handle_remove_tier(
+    state: &mut State,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let TierNameRequest { name } = parse_body()?;
+    state.remove_tier(&name)?;
+    ok_response(&MessageResponse::new("tier removed successfully"))
+}
+
 fn handle_get_location(
     req: http::server::IncomingHttpRequest,
     state: &State,
@@ -308,29 +662,438 @@ fn handle_get_location(
     let uuid = get_uuid_from_path(&req)?;
     let location = state
         .get_location(&uuid)?
-        .ok_or_else(|| anyhow!("location not found"))?;
-    ok_response(&location)
+        .ok_or_else(|| ApiError::NotFound("location not found".into()))?;
+    ok_response(&LocationView::new(location, viewer_timezone(&req)?))
+}
+
+/// The `tz` query parameter, the IANA zone the viewer wants times rendered in.
+fn viewer_timezone(req: &http::server::IncomingHttpRequest) -> Result<Option<Tz>> {
+    req.query_params()
+        .get("tz")
+        .map(|tz| {
+            tz.parse()
+                .map_err(|_| ApiError::BadRequest(format!("invalid tz: {}", tz)).into())
+        })
+        .transpose()
 }
 
 fn handle_update_location(
     req: http::server::IncomingHttpRequest,
     state: &mut State,
+    our: &Address,
 ) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
     let uuid = get_uuid_from_path(&req)?;
-    let body = get_blob().ok_or_else(|| anyhow!("no blob in request"))?;
-    let mut location: Location = serde_json::from_slice(body.bytes())?;
-    location.uuid = uuid;
-    state.update_location(location)?;
-    ok_response(&json!({"message": "location updated successfully"}))
+    let body: serde_json::Value = parse_body()?;
+    let mut location: NewLocation = serde_json::from_value(body.clone())
+        .map_err(|e| ApiError::BadRequest(format!("invalid body: {}", e)))?;
+    // an edit that leaves visibility out keeps it, rather than sharing with everyone
+    if body.get("visibility").is_none() {
+        if let Some(previous) = state.db.get_location(&uuid)? {
+            location.visibility = previous.visibility;
+        }
+    }
+    state.update_location(&our.node().to_string(), uuid, location)?;
+    ok_response(&MessageResponse::new("location updated successfully"))
+}
+
+fn handle_delete_location(
+    req: http::server::IncomingHttpRequest,
+    state: &mut State,
+    our: &Address,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let uuid = get_uuid_from_path(&req)?;
+    state.delete_location(&our.node().to_string(), &uuid)?;
+    ok_response(&MessageResponse::new("location deleted successfully"))
+}
+
+/// `POST /api/photos`, one image as the whole body or any number as the file parts of
+/// a multipart/form-data body.
+/// The response suggests a location for them from their EXIF.
+fn handle_upload_photos(
+    req: http::server::IncomingHttpRequest,
+    state: &mut State,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let body = get_blob().ok_or_else(|| ApiError::BadRequest("missing request body".into()))?;
+    let content_type = req
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let files = if content_type.starts_with("multipart/form-data") {
+        photos::multipart_files(&content_type, body.bytes())?
+    } else {
+        vec![body.bytes()]
+    };
+    if files.is_empty() {
+        return Err(ApiError::BadRequest("no files in request".into()).into());
+    }
+    let viewer = viewer_timezone(&req)?.unwrap_or(Tz::UTC);
+    let saved = state.save_photos(&files, viewer)?;
+    ok_response(&PhotoUploadResponse {
+        photos: saved.iter().map(|photo| photo.id.to_string()).collect(),
+        suggestion: suggest_location(state, &saved),
+    })
 }
 
+fn suggest_location(state: &State, photos: &[Photo]) -> Option<LocationSuggestion> {
+    let position = photos
+        .iter()
+        .find_map(|photo| photo.latitude.zip(photo.longitude));
+    let taken = photos.iter().filter_map(|photo| photo.taken_at);
+    let (start_date, end_date) = (taken.clone().min(), taken.max());
+    if position.is_none() && start_date.is_none() {
+        return None;
+    }
+    Some(LocationSuggestion {
+        start_date,
+        end_date,
+        latitude: position.map(|(latitude, _)| latitude),
+        longitude: position.map(|(_, longitude)| longitude),
+        city: position
+            .and_then(|(latitude, longitude)| state.geo_protocol.closest_city(longitude, latitude))
+            .map(|city| city.name.clone()),
+    })
+}
+
+fn handle_get_photo(
+    req: http::server::IncomingHttpRequest,
+    state: &mut State,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let id = get_uuid_from_path(&req)?;
+    let size = req
+        .query_params()
+        .get("size")
+        .map(|s| PhotoSize::parse(s))
+        .transpose()?
+        .unwrap_or_default();
+    let Some((mime, bytes)) = state.get_photo(&id, size)? else {
+        return fetching_response();
+    };
+    // an id and size always name the same bytes
+    Ok((
+        http::server::HttpResponse::new(http::StatusCode::OK)
+            .header("Cache-Control", "private, max-age=31536000, immutable"),
+        Some(LazyLoadBlob {
+            mime: Some(mime),
+            bytes,
+        }),
+    ))
+}
+
+/// `GET /api/photos/:id/info`, where and when it was taken if its EXIF says and, for a
+/// friend's photo, their tier for us allows.
+fn handle_get_photo_info(
+    req: http::server::IncomingHttpRequest,
+    state: &mut State,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let id = get_uuid_from_path(&req)?;
+    match state.get_photo_info(&id)? {
+        Some(photo) => ok_response(&photo),
+        None => fetching_response(),
+    }
+}
+
+/// A 202 for a friend's photo still on its way from them, to be asked for again.
+fn fetching_response() -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    Ok((
+        http::server::HttpResponse::new(http::StatusCode::ACCEPTED)
+            .header("Retry-After", "1")
+            .header("Cache-Control", "no-store"),
+        Some(LazyLoadBlob {
+            mime: Some("application/json".into()),
+            bytes: serde_json::to_vec(&MessageResponse::new(
+                "fetching the photo from its owner, try again shortly",
+            ))?,
+        }),
+    ))
+}
+
+/// The `:id` of an `/api/{collection}/:id` route, whatever follows it.
 fn get_uuid_from_path(req: &http::server::IncomingHttpRequest) -> Result<Uuid> {
-    let path = req.path().map_err(|e| anyhow!("Invalid path: {}", e))?;
+    let path = req
+        .path()
+        .map_err(|e| ApiError::BadRequest(format!("invalid path: {}", e)))?;
     let id = path
         .split('/')
-        .last()
-        .ok_or_else(|| anyhow!("invalid path format"))?;
-    Uuid::parse_str(id).map_err(|e| anyhow!("invalid UUID: {}", e))
+        .nth(3)
+        .ok_or_else(|| ApiError::BadRequest("invalid path format".into()))?;
+    Ok(Uuid::parse_str(id).map_err(|e| ApiError::BadRequest(format!("invalid UUID: {}", e)))?)
+}
+
+/// Friends' locations overlapping one of ours (`location`), an ad-hoc window
+/// (`lat`, `lon`, `start`, `end`), or by default each of our upcoming locations.
+fn handle_get_overlaps(
+    req: http::server::IncomingHttpRequest,
+    state: &State,
+    our: &Address,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let params = req.query_params();
+    let our_node = our.node().to_string();
+    let radius_km = query_param::<f64>(params, "radius_km")?.unwrap_or(overlap::DEFAULT_RADIUS_KM);
+    if !radius_km.is_finite() || radius_km <= 0.0 {
+        return Err(ApiError::Invalid("invalid radius_km: must be positive".into()).into());
+    }
+    let radius_m = radius_km * 1000.0;
+
+    if let Some(uuid) = query_param::<Uuid>(params, "location")? {
+        let location = state
+            .get_location(&uuid)?
+            .filter(|location| location.owner == our_node)
+            .ok_or_else(|| ApiError::NotFound("location not found".into()))?;
+        return ok_response(&overlap::find_overlaps(
+            state,
+            &our_node,
+            Window::from(&location),
+            radius_m,
+        )?);
+    }
+    match (
+        query_param(params, "lat")?,
+        query_param(params, "lon")?,
+        query_param(params, "start")?,
+        query_param(params, "end")?,
+    ) {
+        (Some(latitude), Some(longitude), Some(start), Some(end)) => {
+            validate_location(latitude, longitude, start, end)?;
+            let window = Window {
+                latitude,
+                longitude,
+                start,
+                end,
+            };
+            ok_response(&overlap::find_overlaps(state, &our_node, window, radius_m)?)
+        }
+        (None, None, None, None) => ok_response(&overlap::upcoming_overlaps(
+            state,
+            &our_node,
+            chrono::Utc::now().timestamp(),
+            radius_m,
+        )?),
+        _ => Err(ApiError::BadRequest(
+            "invalid window: lat, lon, start and end go together".into(),
+        )
+        .into()),
+    }
+}
+
+/// Windows where `friends` (comma-separated, plus us unless `include_self=false`)
+/// are all free between `start` and `end`. A destination given as `lat`, `lon`
+/// and `radius_km` doesn't count as being elsewhere.
+fn handle_get_free_windows(
+    req: http::server::IncomingHttpRequest,
+    state: &State,
+    our: &Address,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let params = req.query_params();
+    let timestamp = |key: &str| -> Result<i64> {
+        query_param(params, key)?
+            .ok_or_else(|| ApiError::BadRequest(format!("invalid range: {key} is required")).into())
+    };
+
+    let mut nodes: Vec<String> = params
+        .get("friends")
+        .map(|s| {
+            s.split(',')
+                .map(str::trim)
+                .filter(|node| !node.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Some(node) = nodes.iter().find(|node| !state.friends.contains_key(*node)) {
+        return Err(ApiError::Invalid(format!("invalid friends: {} is not a friend", node)).into());
+    }
+    if params.get("include_self").map(String::as_str) != Some("false") {
+        nodes.push(our.node().to_string());
+    }
+    if nodes.is_empty() {
+        return Err(ApiError::Invalid("invalid friends: nobody to plan for".into()).into());
+    }
+    let destination = match (
+        query_param::<f64>(params, "lat")?,
+        query_param::<f64>(params, "lon")?,
+        query_param::<f64>(params, "radius_km")?,
+    ) {
+        (Some(lat), Some(lon), Some(radius_km)) => Some(Circle::new(lat, lon, radius_km * 1000.0)?),
+        (None, None, None) => None,
+        _ => {
+            return Err(ApiError::BadRequest(
+                "invalid destination: lat, lon and radius_km go together".into(),
+            )
+            .into())
+        }
+    };
+    let plan = PlanRequest {
+        nodes,
+        start: timestamp("start")?,
+        end: timestamp("end")?,
+        min_length: query_param(params, "min_length")?.unwrap_or(DEFAULT_MIN_WINDOW_SECS),
+        destination,
+    };
+    ok_response(&planner::free_windows(state, &plan)?)
+}
+
+/// A day, the shortest trip worth planning when the caller doesn't say.
+const DEFAULT_MIN_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+/// This API described as OpenAPI 3.1, for scripts and other clients.
+fn handle_openapi(our: &Address) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    ok_response(&openapi::document(our, ROUTES))
+}
+
+fn handle_export(
+    state: &State,
+    our: &Address,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let archive = archive::export(our, state)?;
+    let filename = format!(
+        "callat-{}-{}.json",
+        archive.node,
+        chrono::Utc::now().format("%Y%m%d")
+    );
+    let (response, blob) = ok_response(&archive)?;
+    Ok((
+        response.header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{filename}\""),
+        ),
+        blob,
+    ))
+}
+
+fn handle_import(
+    req: http::server::IncomingHttpRequest,
+    state: &mut State,
+    our: &Address,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let on_conflict = req
+        .query_params()
+        .get("on_conflict")
+        .map(|s| OnConflict::parse(s))
+        .transpose()?
+        .unwrap_or_default();
+    let archive: Archive = parse_body()?;
+
+    let report = archive::import(our, state, archive, on_conflict)?;
+    ok_response(&report)
+}
+
+fn handle_import_ics(
+    state: &mut State,
+    our: &Address,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let body = get_blob().ok_or_else(|| ApiError::BadRequest("missing request body".into()))?;
+    let ics = std::str::from_utf8(body.bytes())
+        .map_err(|_| ApiError::BadRequest("invalid ics: not utf-8".into()))?;
+
+    let report = ics::import_calendar(state, &our.node().to_string(), ics)?;
+    ok_response(&report)
+}
+
+/// `GET /api/notifications[?unread=true][&limit=n]`, newest first.
+fn handle_get_notifications(
+    req: http::server::IncomingHttpRequest,
+    state: &State,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let params = req.query_params();
+    let unread_only = params.get("unread").map(String::as_str) == Some("true");
+    let limit = query_param::<u32>(params, "limit")?
+        .unwrap_or(100)
+        .clamp(1, MAX_PAGE_SIZE);
+    ok_response(&state.db.get_notifications(unread_only, limit)?)
+}
+
+/// Body `{"ids": [1, 2]}` marks those read, `{}` marks everything read.
+fn handle_mark_notifications_read(
+    state: &State,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let MarkReadRequest { ids } = parse_body()?;
+    state.db.mark_notifications_read(ids.as_deref())?;
+    ok_response(&MessageResponse::new("notifications marked read"))
+}
+
+fn handle_get_notification_push(
+    state: &State,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    ok_response(&NotifyPushRequest {
+        process: state.db.get_setting(NOTIFY_PROCESS)?,
+    })
+}
+
+/// Body `{"process": "notify:notify:sys"}` forwards new notifications there,
+/// `{"process": null}` stops forwarding.
+fn handle_set_notification_push(
+    state: &State,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let request: NotifyPushRequest = parse_body()?;
+    match &request.process {
+        Some(process) => {
+            process
+                .parse::<ProcessId>()
+                .map_err(|_| ApiError::Invalid(format!("invalid process: {}", process)))?;
+            state.db.set_setting(NOTIFY_PROCESS, process, None)?;
+        }
+        None => state.db.delete_setting(NOTIFY_PROCESS, None)?,
+    }
+    ok_response(&request)
+}
+
+fn handle_get_calendar_token(
+    state: &State,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    ok_response(&TokenResponse {
+        token: state.calendar_token()?,
+    })
+}
+
+fn handle_rotate_calendar_token(
+    state: &State,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    ok_response(&TokenResponse {
+        token: state.rotate_calendar_token()?,
+    })
+}
+
+/// `GET /api/calendar.ics?token=...[&friends=true]`, our trips and optionally friends'
+/// as they were shared with us.
+fn handle_calendar_feed(
+    req: http::server::IncomingHttpRequest,
+    state: &State,
+    our: &Address,
+) -> Result<(http::server::HttpResponse, Option<LazyLoadBlob>)> {
+    let params = req.query_params();
+    // no feed until the user asks for its URL, which an unauthenticated request can't do
+    let expected = state
+        .existing_calendar_token()?
+        .ok_or_else(|| ApiError::NotFound("calendar feed not found".into()))?;
+    let token = params.get("token").map(String::as_str).unwrap_or_default();
+    if !constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+        return Ok((
+            http::server::HttpResponse::new(http::StatusCode::FORBIDDEN),
+            None,
+        ));
+    }
+
+    let our_node = our.node().to_string();
+    let locations = if params.get("friends").is_some_and(|v| v == "true") {
+        state.get_all_locations()?
+    } else {
+        state.get_locations_by_owner(&our_node)?
+    };
+    let calendar = ics::render_calendar(&locations, &state.geo_protocol, &our_node);
+
+    Ok((
+        http::server::HttpResponse::new(http::StatusCode::OK),
+        Some(LazyLoadBlob {
+            mime: Some("text/calendar; charset=utf-8".into()),
+            bytes: calendar.into_bytes(),
+        }),
+    ))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 fn ok_response<T: serde::Serialize>(
@@ -345,20 +1108,177 @@ fn ok_response<T: serde::Serialize>(
     ))
 }
 
+/// Reads the request body as `T`, a missing or malformed body is a 400.
+fn parse_body<T: DeserializeOwned>() -> Result<T> {
+    let body = get_blob().ok_or_else(|| ApiError::BadRequest("missing request body".into()))?;
+    serde_json::from_slice(body.bytes())
+        .map_err(|e| ApiError::BadRequest(format!("invalid body: {}", e)).into())
+}
+
+/// The query parameter `key` parsed as `T`, `None` when absent and a 400 when it doesn't parse.
+fn query_param<T: FromStr>(params: &HashMap<String, String>, key: &str) -> Result<Option<T>> {
+    params
+        .get(key)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| ApiError::BadRequest(format!("invalid {}: {:?}", key, value)).into())
+        })
+        .transpose()
+}
+
 fn error_response(error: anyhow::Error) -> (http::server::HttpResponse, Option<LazyLoadBlob>) {
-    let status = if error.to_string().contains("not found") {
-        http::StatusCode::NOT_FOUND
-    } else {
-        http::StatusCode::INTERNAL_SERVER_ERROR
+    let (status, code) = match error.downcast_ref::<ApiError>() {
+        Some(api_error) => (api_error.status(), api_error.code()),
+        None => (http::StatusCode::INTERNAL_SERVER_ERROR, "internal"),
     };
 
     println!("HTTPerror: {:?}", error);
 
+    let body = ErrorResponse {
+        error: error.to_string(),
+        code,
+    };
     (
         http::server::HttpResponse::new(status),
         Some(LazyLoadBlob {
             mime: Some("application/json".into()),
-            bytes: json!({"error": error.to_string()}).to_string().into_bytes(),
+            bytes: serde_json::to_vec(&body).unwrap_or_default(),
         }),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A concrete path a route answers on, `:id` segments filled in.
+    fn example_path(pattern: &str) -> String {
+        pattern
+            .split('/')
+            .map(|segment| match segment.starts_with(':') {
+                true => "0b6e6f3c-5d4f-4a43-9a55-6a7c5f0e2f11",
+                false => segment,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[test]
+    fn every_route_is_bound() {
+        let bound = bound_paths();
+        for route in ROUTES {
+            assert!(
+                bound.iter().any(|(path, _)| *path == route.path),
+                "{} {} is not bound",
+                route.method,
+                route.path
+            );
+        }
+    }
+
+    #[test]
+    fn every_route_is_reachable() {
+        for route in ROUTES {
+            let found = find_route(route.method, &example_path(route.path))
+                .unwrap_or_else(|| panic!("{} {} matches nothing", route.method, route.path));
+            assert!(
+                std::ptr::eq(found, route),
+                "{} {} is shadowed by {} {}",
+                route.method,
+                route.path,
+                found.method,
+                found.path
+            );
+        }
+    }
+
+    #[test]
+    fn bindings_agree_on_authentication() {
+        for route in ROUTES {
+            let (_, authenticated) = bound_paths()
+                .into_iter()
+                .find(|(path, _)| *path == route.path)
+                .unwrap();
+            assert_eq!(
+                authenticated, route.authenticated,
+                "{} {} is bound with the wrong authentication",
+                route.method, route.path
+            );
+        }
+    }
+
+    /// Every `$ref` in `value`, anywhere.
+    fn references(value: &serde_json::Value, found: &mut Vec<String>) {
+        match value {
+            serde_json::Value::Object(fields) => {
+                for (name, field) in fields {
+                    match (name.as_str(), field.as_str()) {
+                        ("$ref", Some(reference)) => found.push(reference.to_string()),
+                        _ => references(field, found),
+                    }
+                }
+            }
+            serde_json::Value::Array(items) => {
+                items.iter().for_each(|item| references(item, found))
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn openapi_documents_every_route() {
+        let our = Address::new(
+            "our.os",
+            ProcessId::new(Some("callat"), "callat", "template.os"),
+        );
+        let document = openapi::document(&our, ROUTES);
+
+        let mut operation_ids = Vec::new();
+        for route in ROUTES {
+            let operation =
+                &document["paths"][openapi::path_template(route.path)][route.method.to_lowercase()];
+            assert!(
+                operation.is_object(),
+                "{} {} is not documented",
+                route.method,
+                route.path
+            );
+            assert!(
+                operation["responses"]["200"]["content"].is_object(),
+                "{} {} has no documented response",
+                route.method,
+                route.path
+            );
+            operation_ids.push(operation["operationId"].as_str().unwrap().to_string());
+        }
+        let count = operation_ids.len();
+        operation_ids.sort();
+        operation_ids.dedup();
+        assert_eq!(operation_ids.len(), count, "operationIds must be unique");
+
+        let mut found = Vec::new();
+        references(&document, &mut found);
+        for reference in found {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            assert!(
+                document["components"]["schemas"][name].is_object(),
+                "{} points nowhere",
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn path_matching() {
+        assert!(path_matches("/api/locations", "/api/locations"));
+        assert!(path_matches("/api/locations/:id", "/api/locations/abc"));
+        assert!(!path_matches("/api/locations/:id", "/api/locations/"));
+        assert!(!path_matches("/api/locations/:id", "/api/locations"));
+        assert!(!path_matches(
+            "/api/locations/:id",
+            "/api/locations/abc/def"
+        ));
+        assert!(!path_matches("/api/friends", "/api/friends/pending"));
+    }
+}
@@ -1,8 +1,17 @@
-use kinode_process_lib::{vfs, Address};
+use chrono_tz::Tz;
+use hmac::{Hmac, Mac};
+use kinode_process_lib::{vfs, Address, NodeId};
 use rstar::{PointDistance, RTree, RTreeObject, AABB};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
 
-use crate::state::{FriendType, Location};
+use crate::recurrence::Recurrence;
+use crate::state::{
+    DescriptionPolicy, FuzzKey, Location, LocationGranularity, SharedGranularity, Tier, Visibility,
+};
+
+const METRES_PER_DEGREE: f64 = 111_320.0;
 
 pub fn load_cities_from_file(our: &Address) -> anyhow::Result<Vec<City>> {
     let file_path = format!("{}/pkg/10cities.json", our.package_id());
@@ -12,12 +21,98 @@ pub fn load_cities_from_file(our: &Address) -> anyhow::Result<Vec<City>> {
     Ok(cities)
 }
 
+pub fn load_regions_from_file(our: &Address) -> anyhow::Result<Vec<Region>> {
+    let file_path = format!("{}/pkg/regions.json", our.package_id());
+    let file = vfs::open_file(&file_path, false, None)?;
+    let bytes = file.read()?;
+    let regions: Vec<Region> = serde_json::from_slice(&bytes)?;
+    Ok(regions)
+}
+
+pub fn load_countries_from_file(our: &Address) -> anyhow::Result<Vec<Country>> {
+    let file_path = format!("{}/pkg/countries.json", our.package_id());
+    let file = vfs::open_file(&file_path, false, None)?;
+    let bytes = file.read()?;
+    let countries: Vec<Country> = serde_json::from_slice(&bytes)?;
+    Ok(countries)
+}
+
+pub fn load_timezones_from_file(our: &Address) -> anyhow::Result<Vec<ZoneAnchor>> {
+    let file_path = format!("{}/pkg/timezones.json", our.package_id());
+    let file = vfs::open_file(&file_path, false, None)?;
+    let bytes = file.read()?;
+    let zones: Vec<ZoneAnchor> = serde_json::from_slice(&bytes)?;
+    Ok(zones)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct City {
     pub name: String,
     pub country: String,
     pub latitude: f64,
     pub longitude: f64,
+    #[serde(default)]
+    pub admin1: Option<String>, // region code, e.g. "US-CA", for countries with region data
+}
+
+/// An admin-1 division (state, province, territory) with its approximate centroid.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Region {
+    pub code: String, // ISO 3166-2, e.g. "US-CA" or "IN-GA"
+    pub name: String,
+    pub country: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// An ISO 3166-1 country and the names it goes by, e.g. GB as "Britain (UK)" or "England".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Country {
+    pub code: String,
+    pub names: Vec<String>,
+}
+
+/// A timezone's principal location, from the tz database's zone.tab.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZoneAnchor {
+    pub zone: String, // IANA name, e.g. "America/New_York"
+    pub country: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Result of resolving free-text place names against the city dataset.
+#[derive(Debug)]
+pub enum PlaceMatch<'a> {
+    Found(&'a City),
+    Ambiguous(Vec<&'a City>), // equally good candidates, best first
+    NotFound,
+}
+
+/// A point known to lie inside a region: its centroid, or a city tagged with its code.
+/// Centroids alone put e.g. New York City in New Jersey, the city anchors fix that.
+#[derive(Debug, Clone)]
+struct RegionAnchor {
+    code: String,
+    country: String,
+    longitude: f64,
+    latitude: f64,
+}
+
+impl RTreeObject for RegionAnchor {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point([self.longitude, self.latitude])
+    }
+}
+
+impl PointDistance for RegionAnchor {
+    fn distance_2(&self, point: &[f64; 2]) -> f64 {
+        let dx = self.longitude - point[0];
+        let dy = self.latitude - point[1];
+        dx * dx + dy * dy
+    }
 }
 
 impl RTreeObject for City {
@@ -36,14 +131,100 @@ impl PointDistance for City {
     }
 }
 
+/// Fuzzes locations down to a tier's granularity.
+///
+/// Anything randomized is derived from a keyed hash of (location uuid, friend, tier),
+/// so re-sending a location on every Ping gives a friend the same output each time
+/// and there is nothing to average, while two friends get unrelated outputs.
 pub struct GranularityProtocol {
     city_tree: RTree<City>,
+    country_centroids: HashMap<String, (f64, f64)>, // country code -> (longitude, latitude)
+    regions: HashMap<String, Region>,               // region code -> region
+    region_anchors: RTree<RegionAnchor>,
+    cities_by_name: HashMap<String, Vec<City>>, // lowercased name -> cities
+    countries_by_name: HashMap<String, String>, // lowercased name or code -> country code
+    zones: Vec<(ZoneAnchor, Tz)>,
+    pub fuzz_key: FuzzKey,
 }
 
 impl GranularityProtocol {
-    pub fn new(cities: Vec<City>) -> Self {
+    pub fn new(
+        cities: Vec<City>,
+        regions: Vec<Region>,
+        countries: Vec<Country>,
+        zones: Vec<ZoneAnchor>,
+        fuzz_key: FuzzKey,
+    ) -> Self {
+        // approximate each country by the mean of its cities in the dataset
+        let mut sums: HashMap<String, (f64, f64, f64)> = HashMap::new();
+        for city in &cities {
+            let sum = sums.entry(city.country.clone()).or_default();
+            sum.0 += city.longitude;
+            sum.1 += city.latitude;
+            sum.2 += 1.0;
+        }
+        let country_centroids = sums
+            .into_iter()
+            .map(|(country, (lon, lat, n))| (country, (lon / n, lat / n)))
+            .collect();
+
+        let region_anchors = regions
+            .iter()
+            .map(|region| RegionAnchor {
+                code: region.code.clone(),
+                country: region.country.clone(),
+                longitude: region.longitude,
+                latitude: region.latitude,
+            })
+            .chain(cities.iter().filter_map(|city| {
+                Some(RegionAnchor {
+                    code: city.admin1.clone()?,
+                    country: city.country.clone(),
+                    longitude: city.longitude,
+                    latitude: city.latitude,
+                })
+            }))
+            .collect();
+        let regions = regions
+            .into_iter()
+            .map(|region| (region.code.clone(), region))
+            .collect();
+
+        let mut cities_by_name: HashMap<String, Vec<City>> = HashMap::new();
+        for city in &cities {
+            cities_by_name
+                .entry(city.name.to_lowercase())
+                .or_default()
+                .push(city.clone());
+        }
+        let countries_by_name = countries
+            .into_iter()
+            .flat_map(|country| {
+                let code = country.code.clone();
+                country
+                    .names
+                    .into_iter()
+                    .chain(std::iter::once(country.code))
+                    .map(move |name| (name.to_lowercase(), code.clone()))
+            })
+            .collect();
+
         Self {
             city_tree: RTree::bulk_load(cities),
+            country_centroids,
+            regions,
+            region_anchors: RTree::bulk_load(region_anchors),
+            cities_by_name,
+            countries_by_name,
+            zones: zones
+                .into_iter()
+                .filter_map(|anchor| {
+                    // skip zones newer than our copy of the tz database
+                    let tz = anchor.zone.parse().ok()?;
+                    Some((anchor, tz))
+                })
+                .collect(),
+            fuzz_key,
         }
     }
 
@@ -51,43 +232,340 @@ impl GranularityProtocol {
         self.city_tree.nearest_neighbor(&[longitude, latitude])
     }
 
-    pub fn fuzz_locations(
-        &self,
-        locations: Vec<Location>,
-        friend_type: &FriendType,
-    ) -> Vec<Location> {
-        locations
-            .into_iter()
-            .map(|location| self.fuzz_location(&location, friend_type))
-            .collect()
+    /// The admin-1 region containing a point, None for countries without region data.
+    pub fn closest_region(&self, longitude: f64, latitude: f64) -> Option<&Region> {
+        let point = [longitude, latitude];
+        let city = self.closest_city(longitude, latitude)?;
+        // anchors come nearest first, a closer one across the border would put the point
+        // in a region of another country than the one it's in at country granularity
+        self.region_anchors
+            .nearest_neighbor_iter(&point)
+            .find(|anchor| anchor.country == city.country)
+            .and_then(|anchor| self.regions.get(&anchor.code))
+    }
+
+    pub fn country_centroid(&self, longitude: f64, latitude: f64) -> Option<(f64, f64)> {
+        let city = self.closest_city(longitude, latitude)?;
+        self.country_centroids.get(&city.country).copied()
+    }
+
+    /// The timezone at a point: the zone whose principal city is nearest, among the
+    /// zones of the country the point is in. Good enough for the one-zone countries
+    /// most places are in, approximate along zone borders inside large countries.
+    pub fn timezone_at(&self, longitude: f64, latitude: f64) -> Tz {
+        let distance = |anchor: &ZoneAnchor| {
+            let dx = (anchor.longitude - longitude) * latitude.to_radians().cos();
+            let dy = anchor.latitude - latitude;
+            dx * dx + dy * dy
+        };
+        let nearest = |country: Option<&str>| {
+            self.zones
+                .iter()
+                .filter(|(anchor, _)| country.map_or(true, |country| anchor.country == country))
+                .min_by(|(a, _), (b, _)| distance(a).total_cmp(&distance(b)))
+                .map(|(_, tz)| *tz)
+        };
+        let country = self
+            .closest_city(longitude, latitude)
+            .map(|city| city.country.as_str());
+        country
+            .and_then(|country| nearest(Some(country)))
+            .or_else(|| nearest(None))
+            .unwrap_or(Tz::UTC)
     }
 
-    pub fn fuzz_location(&self, location: &Location, friend_type: &FriendType) -> Location {
-        let (fuzzed_longitude, fuzzed_latitude) = match friend_type {
-            FriendType::Best => (location.longitude, location.latitude), // exact location
-            FriendType::CloseFriend => {
+    /// Resolves an address-like string, e.g. "Hotel X, 1 Main St, Portland, OR, USA",
+    /// to a city in the dataset.
+    ///
+    /// Every comma-separated part naming a city is a candidate. Candidates whose country
+    /// or admin-1 region also appears in the text rank higher, so "Paris, Texas" and
+    /// "Portland, ME" pick the right one, and a tie is reported as ambiguous.
+    pub fn find_place(&self, text: &str) -> PlaceMatch<'_> {
+        let parts: Vec<String> = text
+            .split(',')
+            .map(|part| {
+                // drop postcodes, "75001 Paris" -> "paris", "OR 97201" -> "or"
+                part.split_whitespace()
+                    .filter(|word| !word.chars().any(|c| c.is_ascii_digit()))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+                    .to_lowercase()
+            })
+            .filter(|part| !part.is_empty())
+            .collect();
+
+        let countries: Vec<&String> = parts
+            .iter()
+            .filter_map(|part| self.countries_by_name.get(part))
+            .collect();
+        let mentions_region = |city: &City| {
+            let Some(region) = city.admin1.as_ref().and_then(|code| self.regions.get(code)) else {
+                return false;
+            };
+            let short_code = region
+                .code
+                .rsplit('-')
+                .next()
+                .unwrap_or_default()
+                .to_lowercase();
+            parts
+                .iter()
+                .any(|part| *part == short_code || *part == region.name.to_lowercase())
+        };
+
+        let mut candidates: Vec<(u8, &City)> = parts
+            .iter()
+            .filter_map(|part| self.cities_by_name.get(part))
+            .flatten()
+            .map(|city| {
+                let score =
+                    2 * countries.contains(&&city.country) as u8 + mentions_region(city) as u8;
+                (score, city)
+            })
+            .collect();
+        // best first, and the same city named twice ("New York, New York") side by side
+        candidates.sort_by_key(|(score, city)| (std::cmp::Reverse(*score), *city as *const City));
+        candidates.dedup_by(|a, b| std::ptr::eq(a.1, b.1));
+
+        match candidates.as_slice() {
+            [] => PlaceMatch::NotFound,
+            [(_, city)] => PlaceMatch::Found(city),
+            [(best, city), (second, _), ..] if best > second => PlaceMatch::Found(city),
+            [(best, _), ..] => PlaceMatch::Ambiguous(
+                candidates
+                    .iter()
+                    .filter(|(score, _)| score == best)
+                    .map(|(_, city)| *city)
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Fuzzes a location down to what `friend` may see at `tier`, None if the tier hides it.
+    pub fn fuzz_location(
+        &self,
+        location: &Location,
+        friend: &NodeId,
+        tier: &Tier,
+    ) -> Option<Location> {
+        let exact = (location.longitude, location.latitude);
+        let (fuzzed_longitude, fuzzed_latitude) = match &tier.location {
+            LocationGranularity::Exact => exact,
+            LocationGranularity::Neighbourhood { radius_m } => jitter(
+                location.longitude,
+                location.latitude,
+                *radius_m,
+                self.keyed_samples(location, friend, tier),
+            ),
+            LocationGranularity::City => {
                 // return coordinates of the nearest city
-                if let Some(city) = self.closest_city(location.longitude, location.latitude) {
-                    (city.longitude, city.latitude)
-                } else {
-                    (location.longitude, location.latitude) // fallback to exact location if no city found
-                }
-            }
-            FriendType::Acquaintance => {
-                // return coordinates of the nearest country
-                // todo: add random country centroid
-                if let Some(city) = self.closest_city(location.longitude, location.latitude) {
-                    (city.longitude, city.latitude)
-                } else {
-                    (location.longitude, location.latitude) // fallback to exact location if no city found
-                }
+                self.closest_city(location.longitude, location.latitude)
+                    .map(|city| (city.longitude, city.latitude))
+                    .unwrap_or(exact) // fallback to exact location if no city found
             }
+            LocationGranularity::Region => self
+                .closest_region(location.longitude, location.latitude)
+                .map(|region| (region.longitude, region.latitude))
+                // no region data for this country, share the country instead
+                .or_else(|| self.country_centroid(location.longitude, location.latitude))
+                .unwrap_or(exact), // fallback to exact location if no city found
+            LocationGranularity::Country => self
+                .country_centroid(location.longitude, location.latitude)
+                .unwrap_or(exact), // fallback to exact location if no city found
+            LocationGranularity::Hidden => return None,
+        };
+        // rounded and labelled in the zone at the shared point: the exact one could sit in
+        // another zone, and rounding there would give it away
+        let tz = match tier.location {
+            LocationGranularity::Exact => location.tz(),
+            _ => self.timezone_at(fuzzed_longitude, fuzzed_latitude),
+        };
+        let (start_date, end_date) =
+            tier.time
+                .fuzz_range(location.start_date, location.end_date, tz)?;
+        // a series' end is as precise as any other date, widened to the tier's rounding
+        let recurrence = location.recurrence.clone().map(|recurrence| Recurrence {
+            until: recurrence
+                .until
+                .and_then(|until| tier.time.fuzz_range(until, until, tz))
+                .map(|(_, until)| until),
+            ..recurrence
+        });
+        let description = match tier.description {
+            DescriptionPolicy::Full => location.description.clone(),
+            DescriptionPolicy::PublicSummary => location.public_summary.clone(),
+            DescriptionPolicy::Hidden => String::new(),
         };
 
-        Location {
+        Some(Location {
             longitude: fuzzed_longitude,
             latitude: fuzzed_latitude,
+            start_date,
+            end_date,
+            description,
+            photos: tier.photos.apply(&location.photos),
+            recurrence,
+            visibility: Visibility::default(), // our sharing policy is none of the friend's business
+            public_summary: String::new(),
+            timezone: tz.name().to_string(),
+            // lets the friend tell "in Paris" from "somewhere in France"
+            shared_as: Some(SharedGranularity {
+                location: tier.location.clone(),
+                time: tier.time.clone(),
+            }),
+            ..location.clone()
+        })
+    }
+
+    /// Two uniform samples in [0, 1) from HMAC-SHA256(fuzz_key, uuid | friend | tier).
+    fn keyed_samples(&self, location: &Location, friend: &NodeId, tier: &Tier) -> (f64, f64) {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.fuzz_key)
+            .expect("hmac accepts keys of any length");
+        mac.update(location.uuid.as_bytes());
+        mac.update(friend.as_bytes());
+        mac.update(&[0]); // node ids never contain NUL, keeps (friend, tier) unambiguous
+        mac.update(tier.name.as_bytes());
+        let digest = mac.finalize().into_bytes();
+
+        let sample = |bytes: &[u8]| {
+            // top 53 bits fill an f64 mantissa exactly
+            (u64::from_be_bytes(bytes.try_into().unwrap()) >> 11) as f64 / (1u64 << 53) as f64
+        };
+        (sample(&digest[0..8]), sample(&digest[8..16]))
+    }
+}
+
+/// Moves a point to a spot uniformly distributed within `radius_m` of it.
+fn jitter(longitude: f64, latitude: f64, radius_m: f64, (u, v): (f64, f64)) -> (f64, f64) {
+    let distance = radius_m * v.sqrt(); // sqrt keeps the density uniform over the disc
+    let bearing = u * std::f64::consts::TAU;
+    let lat = latitude + distance * bearing.cos() / METRES_PER_DEGREE;
+    let lon = longitude
+        + distance * bearing.sin()
+            / (METRES_PER_DEGREE * latitude.to_radians().cos().abs().max(0.01));
+    // wrap across the antimeridian rather than piling points up at +-180
+    let lon = (lon + 540.0).rem_euclid(360.0) - 180.0;
+    (lon, lat.clamp(-90.0, 90.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::photos::PhotoSize;
+    use crate::state::{PhotoPolicy, TimeGranularity};
+    use uuid::Uuid;
+
+    const RADIUS_M: f64 = 500.0;
+
+    fn protocol() -> GranularityProtocol {
+        GranularityProtocol::new(vec![], vec![], vec![], vec![], [7; 32])
+    }
+
+    fn tier(name: &str) -> Tier {
+        Tier {
+            name: name.into(),
+            location: LocationGranularity::Neighbourhood { radius_m: RADIUS_M },
+            time: TimeGranularity::Exact,
+            description: DescriptionPolicy::Full,
+            photos: PhotoPolicy::All,
+            photo_size: PhotoSize::Original,
+        }
+    }
+
+    fn location(latitude: f64, longitude: f64) -> Location {
+        Location {
+            uuid: Uuid::new_v4(),
+            start_date: 1_720_000_000,
+            end_date: 1_720_500_000,
+            owner: "alice.os".into(),
+            description: "conference".into(),
+            latitude,
+            longitude,
+            photos: vec![],
+            visibility: Visibility::default(),
+            public_summary: String::new(),
+            timezone: "UTC".into(),
+            shared_as: None,
+            recurrence: None,
+            is_home: false,
+        }
+    }
+
+    fn fuzzed(location: &Location, friend: &str, tier: &Tier) -> (f64, f64) {
+        let fuzzed = protocol()
+            .fuzz_location(location, &friend.to_string(), tier)
+            .unwrap();
+        (fuzzed.longitude, fuzzed.latitude)
+    }
+
+    #[test]
+    fn same_friend_and_tier_get_the_same_point() {
+        let location = location(52.52, 13.405);
+        let point = fuzzed(&location, "bob.os", &tier("CloseFriend"));
+        assert_eq!(point, fuzzed(&location, "bob.os", &tier("CloseFriend")));
+        assert_ne!(point, (location.longitude, location.latitude));
+    }
+
+    #[test]
+    fn different_friends_or_tiers_get_different_points() {
+        let location = location(52.52, 13.405);
+        let point = fuzzed(&location, "bob.os", &tier("CloseFriend"));
+        assert_ne!(point, fuzzed(&location, "carol.os", &tier("CloseFriend")));
+        assert_ne!(point, fuzzed(&location, "bob.os", &tier("Neighbour")));
+        // nor does another location of ours land at the same offset
+        let other = Location {
+            uuid: Uuid::new_v4(),
             ..location.clone()
+        };
+        let offset = |(lon, lat): (f64, f64), location: &Location| {
+            (lon - location.longitude, lat - location.latitude)
+        };
+        assert_ne!(
+            offset(point, &location),
+            offset(fuzzed(&other, "bob.os", &tier("CloseFriend")), &other)
+        );
+    }
+
+    #[test]
+    fn neighbourhood_stays_within_its_radius() {
+        for latitude in [0.0, 52.52, -33.87, 70.0] {
+            for i in 0..200 {
+                let location = location(latitude, 13.405);
+                let (lon, lat) = fuzzed(&location, &format!("friend{}.os", i), &tier("Near"));
+                let dx = (lon - location.longitude)
+                    * METRES_PER_DEGREE
+                    * location.latitude.to_radians().cos();
+                let dy = (lat - location.latitude) * METRES_PER_DEGREE;
+                let distance = (dx * dx + dy * dy).sqrt();
+                assert!(
+                    distance <= RADIUS_M + 1e-6,
+                    "{} m from ({}, {})",
+                    distance,
+                    location.latitude,
+                    location.longitude
+                );
+            }
         }
     }
+
+    #[test]
+    fn regions_stay_in_the_country_of_the_nearest_city() {
+        let cities: Vec<City> =
+            serde_json::from_str(include_str!("../../pkg/10cities.json")).unwrap();
+        let regions: Vec<Region> =
+            serde_json::from_str(include_str!("../../pkg/regions.json")).unwrap();
+        let protocol = GranularityProtocol::new(cities, regions, vec![], vec![], [7; 32]);
+        let region = |latitude: f64, longitude: f64| {
+            let city = protocol.closest_city(longitude, latitude).unwrap();
+            let region = protocol.closest_region(longitude, latitude).unwrap();
+            (city.country.as_str(), region.code.as_str())
+        };
+        // just north of the border, closer to US anchors than to any Canadian city
+        assert_eq!(region(49.03, -119.47), ("CA", "CA-BC")); // Osoyoos, not Washington
+        assert_eq!(region(42.30, -83.03), ("CA", "CA-ON")); // Windsor, not Ohio
+        assert_eq!(region(46.52, -84.33), ("CA", "CA-ON")); // Sault Ste. Marie, not Michigan
+        assert_eq!(region(40.71, -74.01), ("US", "US-NY"));
+        // no region data
+        assert!(protocol.closest_region(-9.14, 38.72).is_none());
+    }
 }
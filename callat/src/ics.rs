@@ -0,0 +1,753 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use kinode_process_lib::NodeId;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    geocity::{GranularityProtocol, PlaceMatch},
+    recurrence::Recurrence,
+    state::{round_to_day, Location, NewLocation, State},
+};
+
+const DAY: i64 = 86_400;
+const MAX_LINE_OCTETS: usize = 75; // RFC 5545 3.1, excluding the CRLF
+
+/// Renders locations as an iCalendar feed, one VEVENT per location.
+///
+/// Entries starting and ending on midnight in their own zone, which is what
+/// day-granularity tiers send, become all-day events so calendars don't show them
+/// as midnight-to-midnight. Recurring locations carry their RRULE; homes are left
+/// out, they'd cover the calendar in one endless event.
+pub fn render_calendar(
+    locations: &[Location],
+    geo_protocol: &GranularityProtocol,
+    our: &NodeId,
+) -> String {
+    let now = format_datetime(Utc::now().timestamp());
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//callat//callat//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("X-WR-CALNAME:{}", escape_text(&format!("cal.lat: {our}"))),
+    ];
+
+    for location in locations.iter().filter(|location| !location.is_home) {
+        let place = geo_protocol
+            .closest_city(location.longitude, location.latitude)
+            .map(|city| format!("{}, {}", city.name, city.country));
+        let summary = match (&location.owner == our, &place) {
+            (true, Some(place)) => place.clone(),
+            (true, None) => location.description.clone(),
+            (false, Some(place)) => format!("{} in {}", location.owner, place),
+            (false, None) => location.owner.clone(),
+        };
+
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}@callat", location.uuid));
+        lines.push(format!("DTSTAMP:{now}"));
+        let tz = location.tz();
+        if is_all_day(location.start_date, location.end_date, tz) {
+            // DTEND of an all-day event is exclusive, which a rounded-up end already is
+            lines.push(format!(
+                "DTSTART;VALUE=DATE:{}",
+                format_date(location.start_date, tz)
+            ));
+            lines.push(format!(
+                "DTEND;VALUE=DATE:{}",
+                format_date(location.end_date, tz)
+            ));
+        } else if location.recurrence.is_some() {
+            // repeats step on the wall clock where the trip is, as they do when we expand them
+            lines.push(format!(
+                "DTSTART;TZID={}:{}",
+                tz.name(),
+                format_local_datetime(location.start_date, tz)
+            ));
+            lines.push(format!(
+                "DTEND;TZID={}:{}",
+                tz.name(),
+                format_local_datetime(location.end_date.max(location.start_date), tz)
+            ));
+        } else {
+            lines.push(format!("DTSTART:{}", format_datetime(location.start_date)));
+            lines.push(format!(
+                "DTEND:{}",
+                format_datetime(location.end_date.max(location.start_date))
+            ));
+        }
+        if let Some(recurrence) = &location.recurrence {
+            lines.push(format!("RRULE:{recurrence}"));
+        }
+        lines.push(format!("SUMMARY:{}", escape_text(&summary)));
+        if !location.description.is_empty() {
+            lines.push(format!(
+                "DESCRIPTION:{}",
+                escape_text(&location.description)
+            ));
+        }
+        if let Some(place) = &place {
+            lines.push(format!("LOCATION:{}", escape_text(place)));
+        }
+        lines.push(format!(
+            "GEO:{:.6};{:.6}",
+            location.latitude, location.longitude
+        ));
+        lines.push("TRANSP:TRANSPARENT".to_string()); // a trip doesn't make us busy
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold_line(line)).collect()
+}
+
+/// The parts of a VEVENT we can turn into a location.
+#[derive(Debug, Default)]
+pub struct IcsEvent {
+    pub uid: Option<String>,
+    pub summary: String,
+    pub description: String,
+    pub location: Option<String>,
+    pub geo: Option<(f64, f64)>, // (latitude, longitude)
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    duration: Option<i64>,
+    recurrence: Option<Recurrence>,
+    all_day: bool,
+    floating: bool, // DTSTART had no zone, it's wall-clock time wherever the event is
+    error: Option<String>, // first property we couldn't read, the event is skipped
+}
+
+/// Parses the VEVENTs out of an iCalendar file. Nested components such as VALARM
+/// are skipped, unknown properties ignored, and one malformed event doesn't stop the rest.
+pub fn parse_events(ics: &str) -> Vec<IcsEvent> {
+    // unfold continuation lines (RFC 5545 3.1)
+    let unfolded = ics
+        .replace("\r\n", "\n")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+
+    let mut events = Vec::new();
+    let mut components: Vec<String> = Vec::new();
+    let mut event = IcsEvent::default();
+    for line in unfolded.lines().filter(|line| !line.is_empty()) {
+        let Some((name, params, value)) = parse_property(line) else {
+            continue;
+        };
+        match name.as_str() {
+            "BEGIN" => {
+                if value.eq_ignore_ascii_case("VEVENT") {
+                    event = IcsEvent::default();
+                }
+                components.push(value.to_uppercase());
+                continue;
+            }
+            "END" => {
+                if components.pop().as_deref() == Some("VEVENT") {
+                    if let (None, Some(start), Some(duration)) =
+                        (event.end, event.start, event.duration)
+                    {
+                        match start.checked_add(duration) {
+                            Some(end) => event.end = Some(end),
+                            None => {
+                                event
+                                    .error
+                                    .get_or_insert("DURATION ends out of range".to_string());
+                            }
+                        }
+                    }
+                    events.push(std::mem::take(&mut event));
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if components.last().map(String::as_str) != Some("VEVENT") {
+            continue;
+        }
+        if let Err(e) = read_property(&mut event, &name, &params, value) {
+            event.error.get_or_insert(e.to_string());
+        }
+    }
+    events
+}
+
+fn read_property(
+    event: &mut IcsEvent,
+    name: &str,
+    params: &[(String, String)],
+    value: &str,
+) -> Result<()> {
+    match name {
+        "UID" => event.uid = Some(value.to_string()),
+        "SUMMARY" => event.summary = unescape_text(value),
+        "DESCRIPTION" => event.description = unescape_text(value),
+        "LOCATION" => event.location = Some(unescape_text(value)),
+        "GEO" => {
+            let (lat, lon) = value
+                .split_once(';')
+                .ok_or_else(|| anyhow!("invalid GEO: {}", value))?;
+            event.geo = Some((lat.trim().parse()?, lon.trim().parse()?));
+        }
+        "DTSTART" => {
+            let (ts, all_day, floating) = parse_date_time(value, params)?;
+            event.start = Some(ts);
+            event.all_day = all_day;
+            event.floating = floating;
+        }
+        "DTEND" => event.end = Some(parse_date_time(value, params)?.0),
+        "DURATION" => event.duration = Some(parse_duration(value)?),
+        // rules beyond our subset (BYDAY and friends) fail here and skip the event
+        "RRULE" => event.recurrence = Some(value.parse()?),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Splits `NAME;PARAM=a;PARAM="b:c":value` into its name, parameters and value.
+fn parse_property(line: &str) -> Option<(String, Vec<(String, String)>, &str)> {
+    // the value starts at the first colon outside a quoted parameter value
+    let mut quoted = false;
+    let colon = line
+        .char_indices()
+        .find(|(_, c)| {
+            if *c == '"' {
+                quoted = !quoted;
+            }
+            *c == ':' && !quoted
+        })
+        .map(|(i, _)| i)?;
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+
+    let mut parts = head.split(';');
+    let name = parts.next().unwrap_or_default().to_uppercase();
+    let params = parts
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (key.to_uppercase(), value.trim_matches('"').to_string()))
+        .collect();
+    Some((name, params, value))
+}
+
+/// A DATE or DATE-TIME value as a unix timestamp, whether it was a whole DATE, and
+/// whether it was floating (no zone). Floating times are read as UTC for now, see
+/// `in_zone` for placing them once we know where the event is.
+fn parse_date_time(value: &str, params: &[(String, String)]) -> Result<(i64, bool, bool)> {
+    let param = |key: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    };
+    if param("VALUE") == Some("DATE") || value.len() == 8 {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d")?;
+        return Ok((
+            date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp(),
+            true,
+            true,
+        ));
+    }
+
+    let naive = NaiveDateTime::parse_from_str(value.trim_end_matches('Z'), "%Y%m%dT%H%M%S")?;
+    let floating = !value.ends_with('Z') && param("TZID").is_none();
+    let ts = match param("TZID") {
+        _ if value.ends_with('Z') => naive.and_utc().timestamp(),
+        Some(tzid) => {
+            let tz: Tz = tzid
+                .parse()
+                .map_err(|_| anyhow!("unknown TZID: {}", tzid))?;
+            tz.from_local_datetime(&naive)
+                .earliest() // the first of a repeated hour, whatever's next for a skipped one
+                .or_else(|| {
+                    tz.from_local_datetime(&(naive + chrono::Duration::hours(1)))
+                        .earliest()
+                })
+                .ok_or_else(|| anyhow!("invalid local time {} in {}", value, tzid))?
+                .timestamp()
+        }
+        None => naive.and_utc().timestamp(),
+    };
+    Ok((ts, false, floating))
+}
+
+/// Reads a floating time, parsed as if it were UTC, as wall-clock time in `tz`.
+fn in_zone(ts: i64, tz: Tz) -> i64 {
+    let naive = DateTime::from_timestamp(ts, 0)
+        .unwrap_or_default()
+        .naive_utc();
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(naive + chrono::Duration::hours(1)))
+                .earliest()
+        })
+        .map(|datetime| datetime.timestamp())
+        .unwrap_or(ts)
+}
+
+/// An RFC 5545 DURATION such as `P1D`, `PT2H30M` or `P2W`, in seconds.
+fn parse_duration(value: &str) -> Result<i64> {
+    let invalid = || anyhow!("invalid DURATION: {}", value);
+    let (sign, rest) = match value.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, value.trim_start_matches('+')),
+    };
+    let rest = rest.strip_prefix('P').ok_or_else(invalid)?;
+
+    let mut seconds = 0;
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in rest.chars() {
+        let unit = match c {
+            '0'..='9' => {
+                number.push(c);
+                continue;
+            }
+            'T' => {
+                in_time = true;
+                continue;
+            }
+            'W' => 7 * DAY,
+            'D' => DAY,
+            'H' if in_time => 3600,
+            'M' if in_time => 60,
+            'S' if in_time => 1,
+            _ => return Err(invalid()),
+        };
+        seconds = number
+            .parse::<i64>()
+            .ok()
+            .and_then(|number| number.checked_mul(unit))
+            .and_then(|part| part.checked_add(seconds))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    Ok(sign * seconds)
+}
+
+fn unescape_text(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportedEvent {
+    pub uid: Option<String>,
+    pub summary: String,
+    pub uuid: Uuid,
+    pub place: Option<String>, // the city LOCATION resolved to, None when GEO was given
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkippedEvent {
+    pub uid: Option<String>,
+    pub summary: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AmbiguousEvent {
+    pub uid: Option<String>,
+    pub summary: String,
+    pub location: String,
+    pub candidates: Vec<String>, // "City, region, country", add a GEO or a country to pick one
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct IcsImportReport {
+    pub created: Vec<ImportedEvent>,
+    pub skipped: Vec<SkippedEvent>,
+    pub ambiguous: Vec<AmbiguousEvent>,
+}
+
+// derives location uuids from event UIDs, so importing the same file twice is a no-op
+const ICS_NAMESPACE: Uuid = Uuid::from_u128(0x5c1e_7a0b_6f0e_4d2a_9a57_c4a1_0e3b_8d21);
+
+/// Creates a location for every event with a time and a place we can pin down.
+pub fn import_calendar(state: &mut State, our: &NodeId, ics: &str) -> Result<IcsImportReport> {
+    let mut report = IcsImportReport::default();
+    for event in parse_events(ics) {
+        let skip = |reason: &str| SkippedEvent {
+            uid: event.uid.clone(),
+            summary: event.summary.clone(),
+            reason: reason.to_string(),
+        };
+        if let Some(error) = &event.error {
+            report.skipped.push(skip(error));
+            continue;
+        }
+        let Some(start_date) = event.start else {
+            report.skipped.push(skip("no DTSTART"));
+            continue;
+        };
+        let end_date = match event.end {
+            Some(end) => end.max(start_date),
+            None if event.all_day => start_date + DAY,
+            None => start_date,
+        };
+        let uuid = match &event.uid {
+            Some(uid) => Uuid::new_v5(&ICS_NAMESPACE, format!("{our}/{uid}").as_bytes()),
+            None => Uuid::new_v4(),
+        };
+        if state.get_location(&uuid)?.is_some() {
+            report.skipped.push(skip("already imported"));
+            continue;
+        }
+
+        let (latitude, longitude, place) = match (event.geo, &event.location) {
+            (Some((latitude, longitude)), _) => (latitude, longitude, None),
+            (None, Some(location)) => match state.geo_protocol.find_place(location) {
+                PlaceMatch::Found(city) => (
+                    city.latitude,
+                    city.longitude,
+                    Some(format!("{}, {}", city.name, city.country)),
+                ),
+                PlaceMatch::Ambiguous(cities) => {
+                    report.ambiguous.push(AmbiguousEvent {
+                        uid: event.uid.clone(),
+                        summary: event.summary.clone(),
+                        location: location.clone(),
+                        candidates: cities
+                            .iter()
+                            .map(|city| match &city.admin1 {
+                                Some(region) => {
+                                    format!("{}, {}, {}", city.name, region, city.country)
+                                }
+                                None => format!("{}, {}", city.name, city.country),
+                            })
+                            .collect(),
+                    });
+                    continue;
+                }
+                PlaceMatch::NotFound => {
+                    report.skipped.push(skip("LOCATION matches no known city"));
+                    continue;
+                }
+            },
+            (None, None) => {
+                report.skipped.push(skip("no GEO or LOCATION"));
+                continue;
+            }
+        };
+
+        let tz = state.geo_protocol.timezone_at(longitude, latitude);
+        let (start_date, end_date) = if event.floating {
+            // "20240704" or "20240704T090000" without a zone mean July 4 where the trip is
+            (in_zone(start_date, tz), in_zone(end_date, tz))
+        } else {
+            (start_date, end_date)
+        };
+
+        let description = match (event.summary.is_empty(), event.description.is_empty()) {
+            (_, true) => event.summary.clone(),
+            (true, false) => event.description.clone(),
+            (false, false) => format!("{}\n\n{}", event.summary, event.description),
+        };
+        let new_location = NewLocation {
+            start_date,
+            end_date,
+            description,
+            latitude,
+            longitude,
+            photos: vec![],
+            visibility: Default::default(),
+            public_summary: String::new(),
+            timezone: Some(tz.name().to_string()),
+            recurrence: event.recurrence.clone(),
+            is_home: false,
+        };
+        match state.create_location(our, uuid, new_location) {
+            Ok(_) => {}
+            // a GEO off the globe or an end before the start, the rest of the calendar is fine
+            Err(e) if matches!(e.downcast_ref(), Some(ApiError::Invalid(_))) => {
+                report.skipped.push(skip(&e.to_string()));
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        report.created.push(ImportedEvent {
+            uid: event.uid.clone(),
+            summary: event.summary.clone(),
+            uuid,
+            place,
+        });
+    }
+    Ok(report)
+}
+
+/// Whether both ends fall on midnight in the location's zone.
+fn is_all_day(start: i64, end: i64, tz: Tz) -> bool {
+    let at_midnight = |ts: i64| round_to_day(ts, tz) == Some(ts);
+    end > start && at_midnight(start) && at_midnight(end)
+}
+
+fn format_datetime(ts: i64) -> String {
+    DateTime::from_timestamp(ts, 0)
+        .unwrap_or_default()
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string()
+}
+
+/// Wall-clock time in `tz`, for DTSTART;TZID=...
+fn format_local_datetime(ts: i64, tz: Tz) -> String {
+    tz.timestamp_opt(ts, 0)
+        .single()
+        .map(|datetime| datetime.format("%Y%m%dT%H%M%S").to_string())
+        .unwrap_or_default()
+}
+
+fn format_date(ts: i64, tz: Tz) -> String {
+    tz.timestamp_opt(ts, 0)
+        .single()
+        .map(|datetime| datetime.format("%Y%m%d").to_string())
+        .unwrap_or_default()
+}
+
+/// Escapes a TEXT value (RFC 5545 3.3.11).
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Splits a content line into CRLF-terminated lines of at most 75 octets, continuation
+/// lines starting with a space, without cutting a UTF-8 character in half.
+fn fold_line(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + line.len() / MAX_LINE_OCTETS * 3 + 2);
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            folded.push_str("\r\n ");
+            octets = 1; // the leading space counts
+        }
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> i64 {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0)
+            .unwrap()
+            .timestamp()
+    }
+
+    #[test]
+    fn reads_zoned_floating_and_all_day_times() {
+        let zoned = parse_date_time("20240704T090000", &params(&[("TZID", "America/New_York")]));
+        assert_eq!(zoned.unwrap(), (utc(2024, 7, 4, 13, 0), false, false));
+        let utc_time = parse_date_time("20240704T090000Z", &[]).unwrap();
+        assert_eq!(utc_time, (utc(2024, 7, 4, 9, 0), false, false));
+        let floating = parse_date_time("20240704T090000", &[]).unwrap();
+        assert_eq!(floating, (utc(2024, 7, 4, 9, 0), false, true));
+        let all_day = parse_date_time("20240704", &params(&[("VALUE", "DATE")])).unwrap();
+        assert_eq!(all_day, (utc(2024, 7, 4, 0, 0), true, true));
+
+        assert!(parse_date_time("20240704T090000", &params(&[("TZID", "Mars/Olympus")])).is_err());
+        assert!(parse_date_time("July 4th", &[]).is_err());
+    }
+
+    #[test]
+    fn reads_durations() {
+        assert_eq!(parse_duration("P1D").unwrap(), DAY);
+        assert_eq!(parse_duration("P2W").unwrap(), 14 * DAY);
+        assert_eq!(parse_duration("PT2H30M").unwrap(), 9000);
+        assert_eq!(parse_duration("P1DT1S").unwrap(), DAY + 1);
+        assert_eq!(parse_duration("-PT15M").unwrap(), -900);
+        assert!(parse_duration("1D").is_err());
+        assert!(parse_duration("P1H").is_err()); // hours only after the T
+        assert!(parse_duration("P99999999999999W").is_err());
+        assert!(parse_duration("P9223372036854775807DT1S").is_err());
+    }
+
+    #[test]
+    fn unescapes_text() {
+        assert_eq!(
+            unescape_text(r"Lisbon\, Porto\; Faro"),
+            "Lisbon, Porto; Faro"
+        );
+        assert_eq!(unescape_text(r"one\ntwo\Nthree"), "one\ntwo\nthree");
+        assert_eq!(unescape_text(r"back\\slash"), r"back\slash");
+        assert_eq!(unescape_text("trailing\\"), "trailing");
+    }
+
+    #[test]
+    fn parses_good_events_past_bad_ones() {
+        let ics = [
+            "BEGIN:VCALENDAR",
+            "BEGIN:VEVENT",
+            "UID:lisbon",
+            r"SUMMARY:Lisbon\, again",
+            "DESCRIPTION:a long description that some calendar folded onto",
+            "  a second line",
+            "DTSTART;TZID=Europe/Lisbon:20240704T090000",
+            "DURATION:P2D",
+            "GEO:38.72;-9.14",
+            "BEGIN:VALARM",
+            "DESCRIPTION:not the event's",
+            "END:VALARM",
+            "END:VEVENT",
+            "BEGIN:VEVENT",
+            "UID:bad-start",
+            "DTSTART:tomorrow",
+            "END:VEVENT",
+            "BEGIN:VEVENT",
+            "UID:forever",
+            "DTSTART:20240704T090000Z",
+            "DURATION:P99999999999999W",
+            "END:VEVENT",
+            "BEGIN:VEVENT",
+            "UID:past-the-end",
+            "DTSTART:20240704T090000Z",
+            "DURATION:PT9223372036854775807S",
+            "END:VEVENT",
+            "BEGIN:VEVENT",
+            "UID:fringe",
+            "DTSTART;VALUE=DATE:20240801",
+            "DTEND;VALUE=DATE:20240803",
+            "LOCATION:Edinburgh",
+            "RRULE:FREQ=YEARLY;COUNT=3",
+            "END:VEVENT",
+            "END:VCALENDAR",
+        ]
+        .join("\r\n");
+
+        let events = parse_events(&ics);
+        assert_eq!(events.len(), 5);
+
+        let lisbon = &events[0];
+        assert_eq!(lisbon.uid.as_deref(), Some("lisbon"));
+        assert_eq!(lisbon.summary, "Lisbon, again");
+        assert_eq!(
+            lisbon.description,
+            "a long description that some calendar folded onto a second line"
+        );
+        assert_eq!(lisbon.geo, Some((38.72, -9.14)));
+        assert_eq!(lisbon.start, Some(utc(2024, 7, 4, 8, 0)));
+        assert_eq!(lisbon.end, Some(utc(2024, 7, 6, 8, 0)));
+        assert!(lisbon.error.is_none());
+
+        assert!(events[1].error.is_some());
+        assert!(events[2].error.as_deref().unwrap().contains("DURATION"));
+        assert!(events[3].error.as_deref().unwrap().contains("DURATION"));
+
+        let fringe = &events[4];
+        assert!(fringe.error.is_none() && fringe.all_day && fringe.floating);
+        assert_eq!(fringe.location.as_deref(), Some("Edinburgh"));
+        assert_eq!(fringe.end, Some(utc(2024, 8, 3, 0, 0)));
+        assert_eq!(fringe.recurrence.as_ref().unwrap().count, Some(3));
+    }
+
+    fn location(start_date: i64, end_date: i64, timezone: &str) -> Location {
+        Location {
+            uuid: Uuid::nil(),
+            start_date,
+            end_date,
+            owner: "alice.os".into(),
+            description: "Fringe, then the Highlands; maybe".into(),
+            latitude: 55.95,
+            longitude: -3.19,
+            photos: vec![],
+            visibility: Default::default(),
+            public_summary: String::new(),
+            timezone: timezone.into(),
+            shared_as: None,
+            recurrence: None,
+            is_home: false,
+        }
+    }
+
+    fn feed(locations: &[Location]) -> String {
+        let protocol = GranularityProtocol::new(vec![], vec![], vec![], vec![], [7; 32]);
+        render_calendar(locations, &protocol, &"alice.os".to_string())
+    }
+
+    #[test]
+    fn folds_at_75_octets_between_characters() {
+        let line = format!("DESCRIPTION:{}", "é".repeat(100));
+        let folded = fold_line(&line);
+        assert!(folded.ends_with("\r\n"));
+        for part in folded.trim_end_matches("\r\n").split("\r\n") {
+            assert!(part.len() <= MAX_LINE_OCTETS, "{} octets", part.len());
+        }
+        // a 2-octet character never straddles a fold, so unfolding gives the line back
+        assert_eq!(folded.replace("\r\n ", "").trim_end(), line);
+        assert_eq!(fold_line("SUMMARY:short"), "SUMMARY:short\r\n");
+    }
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(
+            escape_text("Lisbon, Porto; a\\b\r\nnext"),
+            r"Lisbon\, Porto\; a\\b\nnext"
+        );
+    }
+
+    #[test]
+    fn whole_days_in_the_locations_zone_are_all_day() {
+        // midnight to midnight in New York, 04:00 UTC
+        let start = utc(2024, 7, 4, 4, 0);
+        let end = utc(2024, 7, 6, 4, 0);
+        let new_york = feed(&[location(start, end, "America/New_York")]);
+        assert!(new_york.contains("DTSTART;VALUE=DATE:20240704\r\n"));
+        assert!(new_york.contains("DTEND;VALUE=DATE:20240706\r\n"));
+        assert!(new_york.contains(r"DESCRIPTION:Fringe\, then the Highlands\; maybe"));
+
+        let utc_feed = feed(&[location(start, end, "UTC")]);
+        assert!(utc_feed.contains("DTSTART:20240704T040000Z\r\n"));
+        assert!(utc_feed.contains("DTEND:20240706T040000Z\r\n"));
+    }
+
+    #[test]
+    fn series_carry_their_rule_and_zone() {
+        let mut series = location(
+            utc(2024, 7, 4, 8, 0),
+            utc(2024, 7, 4, 17, 0),
+            "Europe/London",
+        );
+        series.recurrence = Some("FREQ=WEEKLY;COUNT=3".parse().unwrap());
+        let mut home = location(0, 0, "Europe/London");
+        home.is_home = true;
+
+        let calendar = feed(&[series, home]);
+        assert!(calendar.contains("DTSTART;TZID=Europe/London:20240704T090000\r\n"));
+        assert!(calendar.contains("DTEND;TZID=Europe/London:20240704T180000\r\n"));
+        assert!(calendar.contains("RRULE:FREQ=WEEKLY;COUNT=3\r\n"));
+        assert_eq!(calendar.matches("BEGIN:VEVENT").count(), 1); // homes are left out
+    }
+}
@@ -2,12 +2,31 @@ use std::str::FromStr;
 
 use anyhow::Result;
 use kinode::process::standard::NodeId;
-use kinode_process_lib::{await_message, http, println, Address, Message, ProcessId, Request};
+use kinode_process_lib::{
+    await_message, http, println, Address, Message, ProcessId, Request, Response,
+};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
+mod api;
+mod archive;
+mod error;
+mod events;
 mod frontend;
 mod geocity;
+mod ics;
+mod migrations;
+#[cfg(test)]
+mod native_db;
+mod notifications;
+mod openapi;
+mod overlap;
+mod photos;
+mod planner;
+mod recurrence;
 mod state;
+use events::Event;
+use photos::PhotoSize;
 use state::{Location, State};
 
 wit_bindgen::generate!({
@@ -26,8 +45,11 @@ const TIMEOUT: u64 = 30;
 pub enum RemoteRequest {
     Ping,                              // request remote node to update us
     Sync { locations: Vec<Location> }, // message, spinning sync until received?
+    Delete { uuid: Uuid },             // the sender deleted one of their locations
     FriendRequest,
     FriendResponse,
+    Photo { id: Uuid, size: PhotoSize }, // answered with the image as blob
+    PhotoInfo { id: Uuid },              // answered with a Result<Photo, LocationError>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -54,6 +76,7 @@ fn init(our: Address) {
         handle_message(&our, &mut state, &mut server)
             .map_err(|e| println!("error: {:?}", e))
             .ok();
+        frontend::push_events(&server, &mut state);
     }
 }
 
@@ -79,6 +102,9 @@ fn handle_message(
             } else if message.is_request() {
                 handle_remote_message(our, message, state)?;
                 Ok(())
+            } else if let Some(context) = message.context() {
+                // the only requests we await an answer to are for friends' photos
+                state.photo_answered(context, &message)
             } else {
                 Ok(())
             }
@@ -86,8 +112,7 @@ fn handle_message(
         Err(send_error) => {
             if send_error.message.is_request() {
                 if let Some(context) = send_error.context() {
-                    let _target: Address = std::str::from_utf8(context)?.parse()?;
-                    // Implement retry logic if needed
+                    state.photo_unanswered(context)?;
                 }
             }
             Ok(())
@@ -106,11 +131,9 @@ fn handle_remote_message(our: &Address, message: Message, state: &mut State) ->
             if let Some(friend) = state.get_friend(&sender) {
                 let locations = state.get_locations_by_owner(&our.node().into())?;
 
-                // for each location, fuzz the date and location and send to friend,
+                // for each location, apply its visibility policy, fuzz the date and location and send to friend,
                 // only our own locations for now, can gossip around others as well..
-                let fuzzed_locations = state
-                    .geo_protocol
-                    .fuzz_locations(locations, &friend.friend_type);
+                let fuzzed_locations = state.locations_for_friend(locations, friend);
 
                 let req = RemoteRequest::Sync {
                     locations: fuzzed_locations,
@@ -123,12 +146,27 @@ fn handle_remote_message(our: &Address, message: Message, state: &mut State) ->
                     .send()?;
             }
         }
+        // only friends' locations are kept, or any node could fill our notifications
+        RemoteRequest::Sync { .. } | RemoteRequest::Delete { .. }
+            if state.get_friend(&sender).is_none() =>
+        {
+            println!("Ignoring locations from {}, not a friend", sender);
+        }
         RemoteRequest::Sync { locations } => {
             println!("Received sync data for {} locations", locations.len());
+            let count = locations.len();
+            state.friend_photos.forget(&sender);
 
             for location in locations {
                 if location.owner == sender {
-                    state.add_location(&location)?;
+                    if !state.store_friend_location(location.clone())? {
+                        continue;
+                    }
+                    if let Err(e) =
+                        notifications::on_friend_location(state, &our.node().to_string(), &location)
+                    {
+                        println!("failed to check for overlaps: {:?}", e);
+                    }
                 } else {
                     println!(
                         "Received location from unknown sender: {} with owner {}",
@@ -136,14 +174,62 @@ fn handle_remote_message(our: &Address, message: Message, state: &mut State) ->
                     );
                 }
             }
+            state.events.push(Event::SyncCompleted {
+                node_id: sender,
+                locations: count,
+            });
+        }
+        RemoteRequest::Delete { uuid } => {
+            println!("Received delete of {} from {}", uuid, sender);
+            state.friend_photos.forget(&sender);
+            state.delete_friend_location(&sender, &uuid)?;
         }
         RemoteRequest::FriendRequest => {
             println!("Received friend request from {}", sender);
-            state.add_pending_friend_request(sender);
+            state.add_pending_friend_request(sender)?;
         }
         RemoteRequest::FriendResponse => {
             println!("Received friend response from {}", sender);
-            state.handle_friend_response(sender);
+            state.handle_friend_response(sender)?;
+        }
+        RemoteRequest::Photo { id, size } => {
+            let allowed = match state.get_friend(&sender) {
+                Some(friend) => state.photo_size_for(&our.node().into(), friend, &id)?,
+                None => None,
+            };
+            // whatever they asked for, but no more detailed than their tier, and never
+            // with the EXIF that says where it was taken
+            let photo = allowed
+                .map(|allowed| {
+                    let (_, bytes) = state.photos.load(&state.db, &id, size.max(allowed))?;
+                    photos::strip_metadata(&bytes)
+                })
+                .transpose();
+            let response = match photo {
+                Ok(Some(bytes)) => Response::new()
+                    .body(serde_json::to_vec(&LocationResponse::Ok(()))?)
+                    .blob_bytes(bytes),
+                Ok(None) => Response::new().body(serde_json::to_vec(&LocationResponse::Err(
+                    LocationError::UnauthorizedMember,
+                ))?),
+                Err(e) => {
+                    println!("failed to load photo {} for {}: {:?}", id, sender, e);
+                    Response::new().body(serde_json::to_vec(&LocationResponse::Err(
+                        LocationError::UnknownLocation,
+                    ))?)
+                }
+            };
+            response.send()?;
+        }
+        RemoteRequest::PhotoInfo { id } => {
+            let photo = match state.get_friend(&sender) {
+                Some(friend) => state.photo_info_for(&our.node().into(), friend, &id)?,
+                None => None,
+            };
+            let response: Result<_, LocationError> = photo.ok_or(LocationError::UnauthorizedMember);
+            Response::new()
+                .body(serde_json::to_vec(&response)?)
+                .send()?;
         }
     }
     Ok(())
@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::Utc;
+use kinode_process_lib::{println, sqlite::Sqlite};
+use serde_json::Value;
+
+/// One schema change, applied once and recorded in `schema_version`.
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub steps: &'static [Step],
+}
+
+pub enum Step {
+    Sql(&'static str),
+    /// Skipped if the column is already there, e.g. on nodes whose table was created
+    /// by a CREATE TABLE that already had it.
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        definition: &'static str,
+    },
+}
+
+/// Every migration in order. Never edit or reorder a released entry, append a new one.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create locations table",
+        steps: &[Step::Sql(CREATE_LOCATIONS_TABLE)],
+    },
+    Migration {
+        version: 2,
+        description: "per-location visibility",
+        steps: &[Step::AddColumn {
+            table: "locations",
+            column: "visibility",
+            definition: "TEXT",
+        }],
+    },
+    Migration {
+        version: 3,
+        description: "public summary",
+        steps: &[Step::AddColumn {
+            table: "locations",
+            column: "public_summary",
+            definition: "TEXT",
+        }],
+    },
+    Migration {
+        version: 4,
+        description: "move friends, pending requests, lists and tiers out of process state",
+        steps: &[
+            Step::Sql(CREATE_FRIENDS_TABLE),
+            Step::Sql(CREATE_PENDING_FRIEND_REQUESTS_TABLE),
+            Step::Sql(CREATE_CUSTOM_LISTS_TABLE),
+            Step::Sql(CREATE_TIERS_TABLE),
+            Step::Sql(CREATE_SETTINGS_TABLE),
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "index locations by position",
+        steps: &[Step::Sql(
+            "CREATE INDEX IF NOT EXISTS idx_locations_lat_lon ON locations (latitude, longitude)",
+        )],
+    },
+    Migration {
+        version: 6,
+        description: "full-text search over descriptions",
+        steps: &[
+            Step::Sql(CREATE_LOCATIONS_FTS_TABLE),
+            Step::Sql(
+                "INSERT INTO locations_fts (uuid, description, public_summary)
+                 SELECT uuid, COALESCE(description, ''), COALESCE(public_summary, '') FROM locations",
+            ),
+            Step::Sql(CREATE_LOCATIONS_FTS_INSERT_TRIGGER),
+            Step::Sql(CREATE_LOCATIONS_FTS_UPDATE_TRIGGER),
+            Step::Sql(CREATE_LOCATIONS_FTS_DELETE_TRIGGER),
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "index locations by owner and time",
+        steps: &[
+            Step::Sql("CREATE INDEX IF NOT EXISTS idx_locations_owner ON locations (owner)"),
+            // end_date rides along so the bounded range scan filters without touching the table
+            Step::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_locations_start_end ON locations (start_date, end_date)",
+            ),
+            Step::Sql("CREATE INDEX IF NOT EXISTS idx_locations_end_date ON locations (end_date)"),
+            // makes MAX(end_date - start_date) a single lookup, see DB::get_locations
+            Step::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_locations_duration ON locations ((end_date - start_date))",
+            ),
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "per-location timezone",
+        steps: &[Step::AddColumn {
+            table: "locations",
+            column: "timezone",
+            definition: "TEXT",
+        }],
+    },
+    Migration {
+        version: 9,
+        description: "granularity friends' locations were shared at",
+        steps: &[Step::AddColumn {
+            table: "locations",
+            column: "shared_as",
+            definition: "TEXT",
+        }],
+    },
+    Migration {
+        version: 10,
+        description: "recurring locations and home bases",
+        steps: &[
+            Step::AddColumn {
+                table: "locations",
+                column: "recurrence",
+                definition: "TEXT",
+            },
+            Step::AddColumn {
+                table: "locations",
+                column: "is_home",
+                definition: "INTEGER NOT NULL DEFAULT 0",
+            },
+            // a home spanning decades would unbound the range scan, see DB::max_duration
+            Step::Sql("DROP INDEX IF EXISTS idx_locations_duration"),
+            Step::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_locations_duration_one_off ON locations ((end_date - start_date)) WHERE recurrence IS NULL AND is_home = 0",
+            ),
+        ],
+    },
+    Migration {
+        version: 11,
+        description: "overlap notifications",
+        steps: &[Step::Sql(CREATE_NOTIFICATIONS_TABLE)],
+    },
+    Migration {
+        version: 12,
+        description: "uploaded photos",
+        steps: &[Step::Sql(CREATE_PHOTOS_TABLE)],
+    },
+    Migration {
+        version: 13,
+        description: "when and where photos were taken",
+        steps: &[
+            Step::AddColumn {
+                table: "photos",
+                column: "taken_at",
+                definition: "INTEGER",
+            },
+            Step::AddColumn {
+                table: "photos",
+                column: "latitude",
+                definition: "REAL",
+            },
+            Step::AddColumn {
+                table: "photos",
+                column: "longitude",
+                definition: "REAL",
+            },
+        ],
+    },
+];
+
+/// The calls migrations make, so they can be tested against a native sqlite.
+pub trait Database {
+    fn read(&self, query: String, params: Vec<Value>) -> Result<Vec<HashMap<String, Value>>>;
+    fn write(&self, statement: String, params: Vec<Value>, tx_id: Option<u64>) -> Result<()>;
+    fn begin_tx(&self) -> Result<u64>;
+    fn commit_tx(&self, tx_id: u64) -> Result<()>;
+}
+
+impl Database for Sqlite {
+    fn read(&self, query: String, params: Vec<Value>) -> Result<Vec<HashMap<String, Value>>> {
+        Sqlite::read(self, query, params)
+    }
+
+    fn write(&self, statement: String, params: Vec<Value>, tx_id: Option<u64>) -> Result<()> {
+        Sqlite::write(self, statement, params, tx_id)
+    }
+
+    fn begin_tx(&self) -> Result<u64> {
+        Sqlite::begin_tx(self)
+    }
+
+    fn commit_tx(&self, tx_id: u64) -> Result<()> {
+        Sqlite::commit_tx(self, tx_id)
+    }
+}
+
+/// Brings the database up to the latest version, one transaction per migration.
+pub fn run(db: &dyn Database) -> Result<()> {
+    apply(db, MIGRATIONS)
+}
+
+/// Applies those of `migrations` newer than the database.
+///
+/// The sqlite runtime queues a transaction's writes and runs them at commit in one sqlite
+/// transaction, rolled back whole if a statement fails. A failed migration so leaves the
+/// database at the version before it, and the next start tries it again. Everything a
+/// migration reads is read before its transaction: reads don't see queued writes, and
+/// process_lib has no rollback to abandon a transaction with.
+fn apply(db: &dyn Database, migrations: &[Migration]) -> Result<()> {
+    db.write(CREATE_SCHEMA_VERSION_TABLE.to_string(), vec![], None)?;
+    let current = current_version(db)?;
+
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        println!(
+            "applying migration {}: {}",
+            migration.version, migration.description
+        );
+        let mut statements = Vec::new();
+        for step in migration.steps {
+            match step {
+                Step::Sql(sql) => statements.push(sql.to_string()),
+                Step::AddColumn {
+                    table,
+                    column,
+                    definition,
+                } => {
+                    if !column_exists(db, table, column)? {
+                        statements.push(format!(
+                            "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+                        ));
+                    }
+                }
+            }
+        }
+        let tx_id = db.begin_tx()?;
+        for statement in statements {
+            db.write(statement, vec![], Some(tx_id))?;
+        }
+        db.write(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?)"
+                .to_string(),
+            vec![
+                migration.version.into(),
+                migration.description.into(),
+                Utc::now().timestamp().into(),
+            ],
+            Some(tx_id),
+        )?;
+        db.commit_tx(tx_id)?;
+    }
+    Ok(())
+}
+
+pub fn current_version(db: &dyn Database) -> Result<i64> {
+    let rows = db.read(
+        "SELECT MAX(version) AS version FROM schema_version".to_string(),
+        vec![],
+    )?;
+    Ok(rows
+        .first()
+        .and_then(|row| row.get("version"))
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0))
+}
+
+fn column_exists(db: &dyn Database, table: &str, column: &str) -> Result<bool> {
+    let rows = db.read(
+        "SELECT name FROM pragma_table_info(?) WHERE name = ?".to_string(),
+        vec![table.into(), column.into()],
+    )?;
+    Ok(!rows.is_empty())
+}
+
+const CREATE_SCHEMA_VERSION_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS schema_version (
+    version INTEGER PRIMARY KEY,
+    description TEXT NOT NULL,
+    applied_at INTEGER NOT NULL
+);";
+
+// the original schema, as shipped before migrations existed.
+const CREATE_LOCATIONS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS locations (
+    uuid TEXT PRIMARY KEY,
+    start_date INTEGER NOT NULL,
+    end_date INTEGER,
+    owner TEXT NOT NULL,
+    description TEXT,
+    latitude REAL NOT NULL,
+    longitude REAL NOT NULL,
+    photos TEXT
+);";
+
+const CREATE_FRIENDS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS friends (
+    node_id TEXT PRIMARY KEY,
+    friend_type TEXT NOT NULL,
+    last_pinged INTEGER NOT NULL
+);";
+
+// is_local: 1 for requests we sent, 0 for ones we received
+const CREATE_PENDING_FRIEND_REQUESTS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS pending_friend_requests (
+    node_id TEXT NOT NULL,
+    is_local INTEGER NOT NULL,
+    friend_type TEXT NOT NULL,
+    last_pinged INTEGER NOT NULL,
+    PRIMARY KEY (node_id, is_local)
+);";
+
+const CREATE_CUSTOM_LISTS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS custom_lists (
+    list_name TEXT NOT NULL,
+    node_id TEXT NOT NULL,
+    PRIMARY KEY (list_name, node_id)
+);";
+
+// definition is the Tier as JSON, tiers are small and always read whole
+const CREATE_TIERS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS tiers (
+    name TEXT PRIMARY KEY,
+    definition TEXT NOT NULL
+);";
+
+const CREATE_SETTINGS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);";
+
+// one row per overlap, the unique key keeps re-syncs from notifying twice
+const CREATE_NOTIFICATIONS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS notifications (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at INTEGER NOT NULL,
+    friend TEXT NOT NULL,
+    our_location TEXT NOT NULL,
+    friend_location TEXT NOT NULL,
+    match_kind TEXT NOT NULL,
+    overlap_start INTEGER NOT NULL,
+    overlap_end INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    read INTEGER NOT NULL DEFAULT 0,
+    UNIQUE (our_location, friend_location, overlap_start)
+);";
+
+// the bytes live in the VFS photos drive, named by id
+const CREATE_PHOTOS_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS photos (
+    id TEXT PRIMARY KEY,
+    mime TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);";
+
+// a standalone index keyed by uuid: external content tables key on rowid, which
+// VACUUM may renumber for tables like locations without an INTEGER PRIMARY KEY.
+const CREATE_LOCATIONS_FTS_TABLE: &str = "
+CREATE VIRTUAL TABLE IF NOT EXISTS locations_fts USING fts5(
+    uuid UNINDEXED,
+    description,
+    public_summary
+);";
+
+const CREATE_LOCATIONS_FTS_INSERT_TRIGGER: &str = "
+CREATE TRIGGER IF NOT EXISTS locations_fts_insert AFTER INSERT ON locations BEGIN
+    INSERT INTO locations_fts (uuid, description, public_summary)
+    VALUES (new.uuid, COALESCE(new.description, ''), COALESCE(new.public_summary, ''));
+END;";
+
+const CREATE_LOCATIONS_FTS_UPDATE_TRIGGER: &str = "
+CREATE TRIGGER IF NOT EXISTS locations_fts_update AFTER UPDATE ON locations BEGIN
+    DELETE FROM locations_fts WHERE uuid = old.uuid;
+    INSERT INTO locations_fts (uuid, description, public_summary)
+    VALUES (new.uuid, COALESCE(new.description, ''), COALESCE(new.public_summary, ''));
+END;";
+
+const CREATE_LOCATIONS_FTS_DELETE_TRIGGER: &str = "
+CREATE TRIGGER IF NOT EXISTS locations_fts_delete AFTER DELETE ON locations BEGIN
+    DELETE FROM locations_fts WHERE uuid = old.uuid;
+END;";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::native_db::NativeDb;
+
+    /// The columns of every table, to compare schemas by.
+    fn schema(db: &NativeDb) -> Vec<(String, Vec<String>)> {
+        let tables = db
+            .read(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'locations_fts%' ORDER BY name".into(),
+                vec![],
+            )
+            .unwrap();
+        tables
+            .into_iter()
+            .map(|table| {
+                let name = table["name"].as_str().unwrap().to_string();
+                let mut columns: Vec<String> = db
+                    .read(
+                        "SELECT name FROM pragma_table_info(?)".into(),
+                        vec![name.clone().into()],
+                    )
+                    .unwrap()
+                    .into_iter()
+                    .map(|column| column["name"].as_str().unwrap().to_string())
+                    .collect();
+                columns.sort();
+                (name, columns)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn upgrades_every_earlier_version_to_the_fresh_schema() {
+        let fresh = NativeDb::new();
+        run(&fresh).unwrap();
+        let latest = MIGRATIONS.last().unwrap().version;
+        assert_eq!(current_version(&fresh).unwrap(), latest);
+
+        for released in 0..MIGRATIONS.len() {
+            let db = NativeDb::new();
+            apply(&db, &MIGRATIONS[..released]).unwrap();
+            run(&db).unwrap();
+            assert_eq!(current_version(&db).unwrap(), latest);
+            assert_eq!(schema(&db), schema(&fresh), "upgraded from {}", released);
+            // and running again is a no-op
+            run(&db).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_failed_migration_leaves_the_version_before_it() {
+        const GOOD: Migration = Migration {
+            version: 1,
+            description: "good",
+            steps: &[Step::Sql("CREATE TABLE a (x INTEGER)")],
+        };
+        let broken = [
+            GOOD,
+            Migration {
+                version: 2,
+                description: "broken",
+                steps: &[
+                    Step::Sql("CREATE TABLE b (x INTEGER)"),
+                    Step::Sql("INSERT INTO missing VALUES (1)"),
+                ],
+            },
+        ];
+        let db = NativeDb::new();
+        assert!(apply(&db, &broken).is_err());
+        assert_eq!(current_version(&db).unwrap(), 1);
+        let tables = |name: &str| {
+            db.read(
+                "SELECT name FROM sqlite_master WHERE name = ?".into(),
+                vec![name.into()],
+            )
+            .unwrap()
+            .len()
+        };
+        assert_eq!((tables("a"), tables("b")), (1, 0));
+
+        // fixed in the next release, it's applied on the next start
+        let fixed = [
+            GOOD,
+            Migration {
+                version: 2,
+                description: "fixed",
+                steps: &[Step::Sql("CREATE TABLE b (x INTEGER)")],
+            },
+        ];
+        apply(&db, &fixed).unwrap();
+        assert_eq!(current_version(&db).unwrap(), 2);
+        assert_eq!(tables("b"), 1);
+    }
+}
@@ -0,0 +1,124 @@
+//! A bundled sqlite behind the same `Database` trait the runtime's sqlite implements,
+//! so tests can run migrations and queries natively.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
+
+use anyhow::{anyhow, Result};
+use rusqlite::{
+    types::{Type, ValueRef},
+    Connection,
+};
+use serde_json::Value;
+
+use crate::migrations::Database;
+
+type Queued = Vec<(String, Vec<Value>)>;
+
+/// An in-memory database with the runtime's semantics: a transaction's writes are
+/// queued and run at commit, all or none.
+pub struct NativeDb {
+    conn: RefCell<Connection>,
+    txs: RefCell<HashMap<u64, Queued>>,
+    last_tx: Cell<u64>,
+}
+
+impl NativeDb {
+    pub fn new() -> Self {
+        Self {
+            conn: RefCell::new(Connection::open_in_memory().unwrap()),
+            txs: RefCell::default(),
+            last_tx: Cell::new(0),
+        }
+    }
+}
+
+fn to_sql(params: &[Value]) -> Vec<rusqlite::types::Value> {
+    params
+        .iter()
+        .map(|param| match param {
+            Value::Null => rusqlite::types::Value::Null,
+            Value::Number(n) if n.is_i64() => n.as_i64().unwrap().into(),
+            Value::Number(n) => n.as_f64().unwrap().into(),
+            Value::String(s) => s.clone().into(),
+            other => other.to_string().into(),
+        })
+        .collect()
+}
+
+impl Database for NativeDb {
+    fn read(&self, query: String, params: Vec<Value>) -> Result<Vec<HashMap<String, Value>>> {
+        let conn = self.conn.borrow();
+        let mut statement = conn.prepare(&query)?;
+        let names: Vec<String> = statement
+            .column_names()
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let rows = statement.query_map(rusqlite::params_from_iter(to_sql(&params)), |row| {
+            names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| {
+                    let value = match row.get_ref(i)? {
+                        ValueRef::Null => Value::Null,
+                        ValueRef::Integer(n) => n.into(),
+                        ValueRef::Real(n) => n.into(),
+                        ValueRef::Text(text) => String::from_utf8_lossy(text).into(),
+                        // nothing we store is a blob, so there's no JSON shape to match
+                        ValueRef::Blob(_) => {
+                            return Err(rusqlite::Error::InvalidColumnType(
+                                i,
+                                name.clone(),
+                                Type::Blob,
+                            ))
+                        }
+                    };
+                    Ok((name.clone(), value))
+                })
+                .collect()
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn write(&self, statement: String, params: Vec<Value>, tx_id: Option<u64>) -> Result<()> {
+        match tx_id {
+            Some(tx_id) => self
+                .txs
+                .borrow_mut()
+                .get_mut(&tx_id)
+                .ok_or_else(|| anyhow!("no transaction {}", tx_id))?
+                .push((statement, params)),
+            None => {
+                self.conn
+                    .borrow()
+                    .execute(&statement, rusqlite::params_from_iter(to_sql(&params)))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn begin_tx(&self) -> Result<u64> {
+        let tx_id = self.last_tx.get() + 1;
+        self.last_tx.set(tx_id);
+        self.txs.borrow_mut().insert(tx_id, Vec::new());
+        Ok(tx_id)
+    }
+
+    fn commit_tx(&self, tx_id: u64) -> Result<()> {
+        let statements = self
+            .txs
+            .borrow_mut()
+            .remove(&tx_id)
+            .ok_or_else(|| anyhow!("no transaction {}", tx_id))?;
+        let mut conn = self.conn.borrow_mut();
+        let tx = conn.transaction()?;
+        for (statement, params) in statements {
+            tx.execute(&statement, rusqlite::params_from_iter(to_sql(&params)))?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
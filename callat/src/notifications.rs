@@ -0,0 +1,347 @@
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use kinode_process_lib::{println, Address, NodeId, ProcessId, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    events::Event,
+    overlap::{self, MatchKind, Window},
+    state::{Location, LocationFilter, State, EXPANSION_HORIZON_SECS, NOTIFY_PROCESS},
+};
+
+/// A friend's plans crossing one of ours, told once per overlap.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Notification {
+    pub id: i64, // assigned by the database
+    pub created_at: i64,
+    pub friend: NodeId,
+    pub our_location: Uuid,
+    pub friend_location: Uuid,
+    pub match_kind: MatchKind,
+    pub overlap_start: i64,
+    pub overlap_end: i64,
+    pub message: String,
+    pub read: bool,
+}
+
+/// Notifies about our plans overlapping `location`, a friend's, just synced.
+pub fn on_friend_location(state: &mut State, our: &NodeId, location: &Location) -> Result<()> {
+    // a friend's home isn't news, they're there whenever they aren't travelling
+    if location.is_home {
+        return Ok(());
+    }
+    let now = Utc::now().timestamp();
+    let radius_m = overlap::DEFAULT_RADIUS_KM * 1000.0;
+    for theirs in upcoming(location, now) {
+        let filter = LocationFilter {
+            start: Some(theirs.start_date),
+            end: Some(theirs.end_date),
+            owner: Some(our.clone()),
+            ..Default::default()
+        };
+        for ours in state.get_locations(&filter)?.locations {
+            let match_kind = overlap::classify(state, &Window::from(&ours), &theirs, radius_m);
+            if let Some(match_kind) = match_kind.filter(is_notable) {
+                notify(state, our, &ours, &theirs, match_kind, now)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Notifies about friends' plans overlapping `location`, one of ours just created.
+pub fn on_own_location(state: &mut State, our: &NodeId, location: &Location) -> Result<()> {
+    // friends passing through are found when their trips sync, not against all of our home
+    if location.is_home {
+        return Ok(());
+    }
+    let now = Utc::now().timestamp();
+    let radius_m = overlap::DEFAULT_RADIUS_KM * 1000.0;
+    for ours in upcoming(location, now) {
+        let found = overlap::find_overlaps(state, our, Window::from(&ours), radius_m)?;
+        for overlap in found {
+            if !overlap.location.is_home && is_notable(&overlap.match_kind) {
+                notify(
+                    state,
+                    our,
+                    &ours,
+                    &overlap.location,
+                    overlap.match_kind,
+                    now,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sharing a region or a country with a friend isn't worth interrupting anyone for.
+fn is_notable(match_kind: &MatchKind) -> bool {
+    matches!(match_kind, MatchKind::SameCity | MatchKind::Nearby)
+}
+
+/// `location` itself, or its occurrences up to the expansion horizon if it repeats.
+fn upcoming(location: &Location, now: i64) -> Vec<Location> {
+    match &location.recurrence {
+        Some(recurrence) => recurrence
+            .occurrences(
+                location.start_date,
+                location.end_date,
+                location.tz(),
+                now,
+                now.saturating_add(EXPANSION_HORIZON_SECS),
+            )
+            .into_iter()
+            .map(|(start_date, end_date)| Location {
+                start_date,
+                end_date,
+                ..location.clone()
+            })
+            .collect(),
+        None => vec![location.clone()],
+    }
+}
+
+fn notify(
+    state: &mut State,
+    our: &NodeId,
+    ours: &Location,
+    theirs: &Location,
+    match_kind: MatchKind,
+    now: i64,
+) -> Result<()> {
+    let overlap_start = ours.start_date.max(theirs.start_date);
+    let overlap_end = ours.end_date.min(theirs.end_date);
+    if overlap_end < now {
+        return Ok(()); // old news
+    }
+    // re-syncs send the same locations again, a changed trip is a new overlap
+    if state
+        .db
+        .notification_exists(&ours.uuid, &theirs.uuid, overlap_start)?
+    {
+        return Ok(());
+    }
+
+    let place = state
+        .geo_protocol
+        .closest_city(theirs.longitude, theirs.latitude)
+        .map(|city| format!("{}, {}", city.name, city.country))
+        .unwrap_or_else(|| "your area".to_string());
+    // dates as we'd read them where we'll be
+    let tz = ours.tz();
+    let date = |ts: i64| {
+        tz.timestamp_opt(ts, 0)
+            .single()
+            .map(|datetime| datetime.format("%b %-d").to_string())
+            .unwrap_or_default()
+    };
+    let notification = Notification {
+        id: 0,
+        created_at: now,
+        friend: theirs.owner.clone(),
+        our_location: ours.uuid,
+        friend_location: theirs.uuid,
+        match_kind,
+        overlap_start,
+        overlap_end,
+        message: format!(
+            "{} will be in {} while you are, {} to {}",
+            theirs.owner,
+            place,
+            date(overlap_start),
+            date(overlap_end)
+        ),
+        read: false,
+    };
+    println!("new overlap: {}", notification.message);
+    state.db.insert_notification(&notification)?;
+    push(state, our, &notification);
+    state.events.push(Event::Notification { notification });
+    Ok(())
+}
+
+/// Forwards a notification to the process in the NOTIFY_PROCESS setting, e.g. a
+/// notifications app on this node. Off unless set, and never fails the caller.
+fn push(state: &State, our: &NodeId, notification: &Notification) {
+    let process = match state.db.get_setting(NOTIFY_PROCESS) {
+        Ok(Some(process)) => process,
+        _ => return,
+    };
+    let Ok(process) = process.parse::<ProcessId>() else {
+        println!("not pushing notification, invalid process: {}", process);
+        return;
+    };
+    let body = json!({
+        "title": "cal.lat",
+        "body": notification.message,
+        "notification": notification,
+    });
+    let sent = serde_json::to_vec(&body)
+        .map_err(anyhow::Error::from)
+        .and_then(|body| {
+            Request::to(Address::new(our.clone(), process))
+                .body(body)
+                .send()
+        });
+    if let Err(e) = sent {
+        println!("failed to push notification: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geocity::{City, GranularityProtocol};
+
+    const DAY: i64 = 86_400;
+    const OUR: &str = "alice.os";
+
+    fn state() -> State {
+        let city = |name: &str, latitude: f64, longitude: f64| City {
+            name: name.into(),
+            country: "PT".into(),
+            latitude,
+            longitude,
+            admin1: None,
+        };
+        let cities = vec![city("Lisbon", 38.72, -9.14), city("Porto", 41.15, -8.61)];
+        State::in_memory(GranularityProtocol::new(
+            cities,
+            vec![],
+            vec![],
+            vec![],
+            [7; 32],
+        ))
+    }
+
+    fn location(owner: &str, start_date: i64, end_date: i64) -> Location {
+        Location {
+            uuid: Uuid::new_v4(),
+            start_date,
+            end_date,
+            owner: owner.into(),
+            description: String::new(),
+            latitude: 38.72,
+            longitude: -9.14,
+            photos: vec![],
+            visibility: Default::default(),
+            public_summary: String::new(),
+            timezone: "Europe/Lisbon".into(),
+            shared_as: None,
+            recurrence: None,
+            is_home: false,
+        }
+    }
+
+    fn notifications(state: &State) -> Vec<Notification> {
+        state.db.get_notifications(false, 100).unwrap()
+    }
+
+    #[test]
+    fn notifies_once_per_overlap() {
+        let mut state = state();
+        let now = Utc::now().timestamp();
+        let ours = location(OUR, now + DAY, now + 5 * DAY);
+        let theirs = location("bob.os", now + 2 * DAY, now + 9 * DAY);
+        let our = OUR.to_string();
+        for _ in 0..2 {
+            notify(&mut state, &our, &ours, &theirs, MatchKind::SameCity, now).unwrap();
+        }
+        let sent = notifications(&state);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            (sent[0].overlap_start, sent[0].overlap_end),
+            (now + 2 * DAY, now + 5 * DAY)
+        );
+        assert_eq!(state.events.len(), 1);
+
+        // the same trip moved is a new overlap
+        let moved = Location {
+            start_date: now + 3 * DAY,
+            ..theirs
+        };
+        notify(&mut state, &our, &ours, &moved, MatchKind::SameCity, now).unwrap();
+        assert_eq!(notifications(&state).len(), 2);
+    }
+
+    #[test]
+    fn overlaps_already_over_are_old_news() {
+        let mut state = state();
+        let now = Utc::now().timestamp();
+        let ours = location(OUR, now - 9 * DAY, now + DAY);
+        let theirs = location("bob.os", now - 9 * DAY, now - DAY);
+        notify(
+            &mut state,
+            &OUR.into(),
+            &ours,
+            &theirs,
+            MatchKind::SameCity,
+            now,
+        )
+        .unwrap();
+        assert!(notifications(&state).is_empty());
+    }
+
+    #[test]
+    fn homes_are_not_news() {
+        let mut state = state();
+        let now = Utc::now().timestamp();
+        let ours = location(OUR, now + DAY, now + 5 * DAY);
+        state.add_location(&ours).unwrap();
+
+        let home = Location {
+            is_home: true,
+            ..location("bob.os", now - 365 * DAY, now + 365 * DAY)
+        };
+        on_friend_location(&mut state, &OUR.into(), &home).unwrap();
+        assert!(notifications(&state).is_empty());
+
+        let trip = location("bob.os", now + 2 * DAY, now + 3 * DAY);
+        on_friend_location(&mut state, &OUR.into(), &trip).unwrap();
+        assert_eq!(notifications(&state).len(), 1);
+        assert_eq!(notifications(&state)[0].friend_location, trip.uuid);
+
+        // nor is our own home a reason to tell us about everyone passing through
+        let mut state = self::state();
+        state.db.upsert_location(&trip, None).unwrap();
+        let our_home = Location {
+            is_home: true,
+            ..location(OUR, now - 365 * DAY, now + 365 * DAY)
+        };
+        on_own_location(&mut state, &OUR.into(), &our_home).unwrap();
+        assert!(notifications(&state).is_empty());
+        on_own_location(&mut state, &OUR.into(), &ours).unwrap();
+        assert_eq!(notifications(&state).len(), 1);
+    }
+
+    #[test]
+    fn only_the_same_city_or_nearby_is_notable() {
+        assert!(is_notable(&MatchKind::SameCity));
+        assert!(is_notable(&MatchKind::Nearby));
+        assert!(!is_notable(&MatchKind::SameRegion));
+        assert!(!is_notable(&MatchKind::SameCountry));
+    }
+
+    #[test]
+    fn series_are_checked_from_now_on() {
+        let now = Utc::now().timestamp();
+        let trip = location(OUR, now + DAY, now + 2 * DAY);
+        assert_eq!(upcoming(&trip, now), vec![trip.clone()]);
+
+        let series = Location {
+            recurrence: Some("FREQ=WEEKLY".parse().unwrap()),
+            ..location(OUR, now - 52 * 7 * DAY, now - 52 * 7 * DAY + DAY)
+        };
+        let occurrences = upcoming(&series, now);
+        assert!(!occurrences.is_empty());
+        assert!(occurrences
+            .iter()
+            .all(|occurrence| occurrence.end_date >= now
+                && occurrence.start_date <= now + EXPANSION_HORIZON_SECS
+                && occurrence.uuid == series.uuid));
+        assert!(occurrences[0].start_date < now + 7 * DAY);
+    }
+}
@@ -0,0 +1,1331 @@
+use kinode_process_lib::Address;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+use crate::{
+    api::{
+        CustomListRequest, ErrorResponse, FriendTypeRequest, LocationSuggestion, MarkReadRequest,
+        MessageResponse, NodeIdRequest, NotifyPushRequest, PendingFriendsResponse,
+        PhotoUploadResponse, TierNameRequest, TokenResponse,
+    },
+    archive::{Archive, ImportCounts, ImportReport},
+    frontend::Route,
+    ics::{AmbiguousEvent, IcsImportReport, ImportedEvent, SkippedEvent},
+    notifications::Notification,
+    overlap::{MatchKind, Overlap, PlanOverlaps},
+    photos::{Photo, PhotoSize},
+    planner::FreeWindow,
+    recurrence::Recurrence,
+    state::{
+        DescriptionPolicy, Friend, LocalTimes, Location, LocationGranularity, LocationView,
+        NewLocation, PhotoPolicy, SharedGranularity, Tier, TimeGranularity, Visibility,
+    },
+};
+
+/// A type the API sends or receives, described as the JSON its serde impls produce.
+///
+/// Written by hand next to each other here rather than derived, so a field added to a
+/// type must be added below too. The tests check the two agree by validating real values.
+pub trait Schema {
+    /// Name under `#/components/schemas`.
+    const NAME: &'static str;
+    /// Nested API types go through `components.reference`.
+    fn schema(components: &mut Components) -> Value;
+}
+
+/// `#/components/schemas`, filled in as operations reference types.
+#[derive(Default)]
+pub struct Components(BTreeMap<&'static str, Value>);
+
+impl Components {
+    /// A `$ref` to `T`, registering its schema on first use.
+    pub fn reference<T: Schema>(&mut self) -> Value {
+        if !self.0.contains_key(T::NAME) {
+            self.0.insert(T::NAME, Value::Null); // reserved first, in case T refers back to itself
+            let schema = T::schema(self);
+            self.0.insert(T::NAME, schema);
+        }
+        json!({ "$ref": format!("#/components/schemas/{}", T::NAME) })
+    }
+}
+
+/// A request or response body: its media types and their schemas.
+pub type Content = fn(&mut Components) -> Value;
+
+pub fn json<T: Schema>(components: &mut Components) -> Value {
+    content(components.reference::<T>())
+}
+
+pub fn json_array<T: Schema>(components: &mut Components) -> Value {
+    content(array(components.reference::<T>()))
+}
+
+pub fn calendar(_: &mut Components) -> Value {
+    json!({ "text/calendar": { "schema": string() } })
+}
+
+pub fn image(_: &mut Components) -> Value {
+    json!({ "image/*": {} })
+}
+
+/// One image as the body, or several as the file parts of a form.
+pub fn image_upload(_: &mut Components) -> Value {
+    json!({
+        "image/*": {},
+        "multipart/form-data": {
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "file": { "type": "array", "items": { "contentMediaType": "application/octet-stream" } },
+                },
+            },
+        },
+    })
+}
+
+pub fn content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+/// A query parameter of a route, `kind` being its JSON schema type.
+#[derive(Debug, Clone, Copy)]
+pub struct Param {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+pub const fn param(name: &'static str, kind: &'static str, description: &'static str) -> Param {
+    Param {
+        name,
+        kind,
+        required: false,
+        description,
+    }
+}
+
+pub const fn required(param: Param) -> Param {
+    Param {
+        required: true,
+        ..param
+    }
+}
+
+/// The OpenAPI 3.1 document for `routes`, as served at `/api/openapi.json`.
+pub fn document(our: &Address, routes: &[Route]) -> Value {
+    let mut components = Components::default();
+    let error = json!({
+        "description": "error, `code` is bad_request (400), not_found (404), conflict (409), invalid (422) or internal (500)",
+        "content": json::<ErrorResponse>(&mut components),
+    });
+
+    let mut paths = Map::new();
+    for route in routes {
+        let mut parameters: Vec<Value> = route
+            .path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix(':'))
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string", "format": "uuid" },
+                })
+            })
+            .collect();
+        parameters.extend(route.query.iter().map(|param| {
+            json!({
+                "name": param.name,
+                "in": "query",
+                "required": param.required,
+                "description": param.description,
+                "schema": { "type": param.kind },
+            })
+        }));
+
+        let mut operation = Map::new();
+        operation.insert("operationId".into(), operation_id(route).into());
+        if !parameters.is_empty() {
+            operation.insert("parameters".into(), parameters.into());
+        }
+        if let Some(request) = route.request {
+            operation.insert(
+                "requestBody".into(),
+                json!({ "required": true, "content": request(&mut components) }),
+            );
+        }
+        let mut ok = json!({ "description": "success" });
+        if let Some(response) = route.response {
+            ok["content"] = response(&mut components);
+        }
+        let mut responses = json!({ "200": ok, "default": error.clone() });
+        if route.deferred {
+            responses["202"] = json!({
+                "description": "still being fetched from a friend, try again after Retry-After seconds",
+                "content": json::<MessageResponse>(&mut components),
+            });
+        }
+        operation.insert("responses".into(), responses);
+        if !route.authenticated {
+            operation.insert("security".into(), json!([]));
+        }
+
+        let path = path_template(route.path);
+        let item = paths
+            .entry(path)
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[route.method.to_lowercase()] = operation.into();
+    }
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "cal.lat",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Locations, friends and sharing tiers of this node. Times are unix seconds.",
+        },
+        "servers": [{ "url": format!("/{}", our.process) }],
+        "paths": paths,
+        "components": {
+            "schemas": components.0,
+            "securitySchemes": {
+                "node": {
+                    "type": "apiKey",
+                    "in": "cookie",
+                    "name": format!("kinode-auth_{}", our.node()),
+                    "description": "set by logging in to the node",
+                },
+            },
+        },
+        "security": [{ "node": [] }],
+    })
+}
+
+/// "/api/locations/:id" as OpenAPI writes it, "/api/locations/{id}".
+pub fn path_template(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(name) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// e.g. "post_api_friends_accept", unique as long as method and path are.
+fn operation_id(route: &Route) -> String {
+    let path: String = route
+        .path
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!(
+        "{}{}",
+        route.method.to_lowercase(),
+        path.trim_end_matches('_')
+    )
+}
+
+// schema building blocks
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn integer() -> Value {
+    json!({ "type": "integer" })
+}
+
+fn number() -> Value {
+    json!({ "type": "number" })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn timestamp() -> Value {
+    json!({ "type": "integer", "description": "unix seconds" })
+}
+
+fn uuid() -> Value {
+    json!({ "type": "string", "format": "uuid" })
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+/// An object keyed by arbitrary strings.
+fn map(values: Value) -> Value {
+    json!({ "type": "object", "additionalProperties": values })
+}
+
+fn nullable(schema: Value) -> Value {
+    json!({ "anyOf": [schema, { "type": "null" }] })
+}
+
+fn one_of_strings(values: &[&str]) -> Value {
+    json!({ "type": "string", "enum": values })
+}
+
+fn describe(mut schema: Value, description: &str) -> Value {
+    schema["description"] = description.into();
+    schema
+}
+
+/// An object with `properties`, all of them required but those in `optional`.
+fn object(properties: Vec<(&str, Value)>, optional: &[&str]) -> Value {
+    let required: Vec<&str> = properties
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| !optional.contains(name))
+        .collect();
+    let properties: Map<String, Value> = properties
+        .into_iter()
+        .map(|(name, schema)| (name.to_string(), schema))
+        .collect();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+/// A serde enum with `{"Variant": {...}}` for variants carrying data, a bare string otherwise.
+fn tagged_enum(units: &[&str], variants: Vec<(&str, Value)>) -> Value {
+    let mut one_of = vec![one_of_strings(units)];
+    one_of.extend(
+        variants
+            .into_iter()
+            .map(|(name, fields)| object(vec![(name, fields)], &[])),
+    );
+    json!({ "oneOf": one_of })
+}
+
+// request and response bodies, api.rs
+
+impl Schema for NodeIdRequest {
+    const NAME: &'static str = "NodeIdRequest";
+    fn schema(_: &mut Components) -> Value {
+        object(vec![("node_id", string())], &[])
+    }
+}
+
+impl Schema for FriendTypeRequest {
+    const NAME: &'static str = "FriendTypeRequest";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![
+                ("node_id", string()),
+                ("friend_type", describe(string(), "name of a tier")),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for CustomListRequest {
+    const NAME: &'static str = "CustomListRequest";
+    fn schema(_: &mut Components) -> Value {
+        object(vec![("list_name", string()), ("node_id", string())], &[])
+    }
+}
+
+impl Schema for TierNameRequest {
+    const NAME: &'static str = "TierNameRequest";
+    fn schema(_: &mut Components) -> Value {
+        object(vec![("name", string())], &[])
+    }
+}
+
+impl Schema for MarkReadRequest {
+    const NAME: &'static str = "MarkReadRequest";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![(
+                "ids",
+                describe(
+                    nullable(array(integer())),
+                    "left out, everything is marked read",
+                ),
+            )],
+            &["ids"],
+        )
+    }
+}
+
+impl Schema for NotifyPushRequest {
+    const NAME: &'static str = "NotifyPushRequest";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![(
+                "process",
+                describe(
+                    nullable(string()),
+                    "process id new notifications are sent to, null for none",
+                ),
+            )],
+            &[],
+        )
+    }
+}
+
+impl Schema for MessageResponse {
+    const NAME: &'static str = "MessageResponse";
+    fn schema(_: &mut Components) -> Value {
+        object(vec![("message", string())], &[])
+    }
+}
+
+impl Schema for PendingFriendsResponse<'_> {
+    const NAME: &'static str = "PendingFriendsResponse";
+    fn schema(components: &mut Components) -> Value {
+        let friend = components.reference::<Friend>();
+        object(
+            vec![
+                ("incoming", array(friend.clone())),
+                ("outgoing", array(friend)),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for PhotoUploadResponse {
+    const NAME: &'static str = "PhotoUploadResponse";
+    fn schema(components: &mut Components) -> Value {
+        object(
+            vec![
+                (
+                    "photos",
+                    describe(array(string()), "photo ids, in upload order"),
+                ),
+                (
+                    "suggestion",
+                    nullable(components.reference::<LocationSuggestion>()),
+                ),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for LocationSuggestion {
+    const NAME: &'static str = "LocationSuggestion";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![
+                ("start_date", nullable(timestamp())),
+                ("end_date", nullable(timestamp())),
+                ("latitude", nullable(number())),
+                ("longitude", nullable(number())),
+                (
+                    "city",
+                    describe(nullable(string()), "nearest to latitude and longitude"),
+                ),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for Photo {
+    const NAME: &'static str = "Photo";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![
+                ("id", uuid()),
+                ("mime", string()),
+                ("size", describe(integer(), "of the original, in bytes")),
+                ("created_at", timestamp()),
+                ("taken_at", describe(nullable(timestamp()), "from the EXIF")),
+                ("latitude", describe(nullable(number()), "from the EXIF")),
+                ("longitude", describe(nullable(number()), "from the EXIF")),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for TokenResponse {
+    const NAME: &'static str = "TokenResponse";
+    fn schema(_: &mut Components) -> Value {
+        object(vec![("token", string())], &[])
+    }
+}
+
+impl Schema for ErrorResponse {
+    const NAME: &'static str = "ErrorResponse";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![
+                ("error", string()),
+                (
+                    "code",
+                    one_of_strings(&[
+                        "bad_request",
+                        "not_found",
+                        "conflict",
+                        "invalid",
+                        "internal",
+                    ]),
+                ),
+            ],
+            &[],
+        )
+    }
+}
+
+// locations, friends and tiers, state.rs
+
+impl Schema for Location {
+    const NAME: &'static str = "Location";
+    fn schema(components: &mut Components) -> Value {
+        object(
+            vec![
+                ("uuid", uuid()),
+                ("start_date", timestamp()),
+                ("end_date", timestamp()),
+                ("owner", string()),
+                ("description", string()),
+                ("latitude", number()),
+                ("longitude", number()),
+                ("photos", array(string())),
+                ("visibility", components.reference::<Visibility>()),
+                ("public_summary", string()),
+                (
+                    "timezone",
+                    describe(string(), "IANA zone, e.g. America/New_York"),
+                ),
+                (
+                    "shared_as",
+                    nullable(components.reference::<SharedGranularity>()),
+                ),
+                ("recurrence", nullable(components.reference::<Recurrence>())),
+                ("is_home", boolean()),
+            ],
+            &[
+                "visibility",
+                "public_summary",
+                "timezone",
+                "shared_as",
+                "recurrence",
+                "is_home",
+            ],
+        )
+    }
+}
+
+impl Schema for LocationView {
+    const NAME: &'static str = "LocationView";
+    fn schema(components: &mut Components) -> Value {
+        // the location's own fields are flattened in beside the rendered times
+        let mut schema = Location::schema(components);
+        let times = components.reference::<LocalTimes>();
+        schema["properties"]["local"] = times.clone();
+        schema["properties"]["viewer"] = describe(times, "only when the request gave a tz");
+        schema["required"]
+            .as_array_mut()
+            .expect("objects list their required properties")
+            .push("local".into());
+        schema
+    }
+}
+
+impl Schema for NewLocation {
+    const NAME: &'static str = "NewLocation";
+    fn schema(components: &mut Components) -> Value {
+        object(
+            vec![
+                ("start_date", timestamp()),
+                ("end_date", timestamp()),
+                ("description", string()),
+                ("latitude", number()),
+                ("longitude", number()),
+                ("photos", array(string())),
+                ("visibility", components.reference::<Visibility>()),
+                ("public_summary", string()),
+                (
+                    "timezone",
+                    describe(
+                        nullable(string()),
+                        "resolved from the coordinates when left out",
+                    ),
+                ),
+                ("recurrence", nullable(components.reference::<Recurrence>())),
+                ("is_home", boolean()),
+            ],
+            &[
+                "visibility",
+                "public_summary",
+                "timezone",
+                "recurrence",
+                "is_home",
+            ],
+        )
+    }
+}
+
+impl Schema for Visibility {
+    const NAME: &'static str = "Visibility";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![
+                ("private", boolean()),
+                ("max_tier", nullable(string())),
+                ("allow", array(string())),
+                ("deny", array(string())),
+            ],
+            &["private", "max_tier", "allow", "deny"],
+        )
+    }
+}
+
+impl Schema for SharedGranularity {
+    const NAME: &'static str = "SharedGranularity";
+    fn schema(components: &mut Components) -> Value {
+        object(
+            vec![
+                ("location", components.reference::<LocationGranularity>()),
+                ("time", components.reference::<TimeGranularity>()),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for LocalTimes {
+    const NAME: &'static str = "LocalTimes";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![
+                ("timezone", string()),
+                (
+                    "start",
+                    describe(string(), "RFC 3339 with the zone's offset"),
+                ),
+                ("end", describe(string(), "RFC 3339 with the zone's offset")),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for Recurrence {
+    const NAME: &'static str = "Recurrence";
+    fn schema(_: &mut Components) -> Value {
+        describe(
+            string(),
+            "RRULE subset: FREQ=DAILY|WEEKLY|MONTHLY|YEARLY with INTERVAL and COUNT or UNTIL",
+        )
+    }
+}
+
+impl Schema for LocationGranularity {
+    const NAME: &'static str = "LocationGranularity";
+    fn schema(_: &mut Components) -> Value {
+        tagged_enum(
+            &["Exact", "City", "Region", "Country", "Hidden"],
+            vec![("Neighbourhood", object(vec![("radius_m", number())], &[]))],
+        )
+    }
+}
+
+impl Schema for TimeGranularity {
+    const NAME: &'static str = "TimeGranularity";
+    fn schema(_: &mut Components) -> Value {
+        one_of_strings(&["Exact", "Day", "Week", "Month", "Hidden"])
+    }
+}
+
+impl Schema for DescriptionPolicy {
+    const NAME: &'static str = "DescriptionPolicy";
+    fn schema(_: &mut Components) -> Value {
+        one_of_strings(&["Full", "PublicSummary", "Hidden"])
+    }
+}
+
+impl Schema for PhotoPolicy {
+    const NAME: &'static str = "PhotoPolicy";
+    fn schema(_: &mut Components) -> Value {
+        tagged_enum(
+            &["All", "Hidden"],
+            vec![("Limit", object(vec![("max", integer())], &[]))],
+        )
+    }
+}
+
+impl Schema for PhotoSize {
+    const NAME: &'static str = "PhotoSize";
+    fn schema(_: &mut Components) -> Value {
+        one_of_strings(&["Original", "Web", "Thumb"])
+    }
+}
+
+impl Schema for Tier {
+    const NAME: &'static str = "Tier";
+    fn schema(components: &mut Components) -> Value {
+        object(
+            vec![
+                ("name", string()),
+                ("location", components.reference::<LocationGranularity>()),
+                ("time", components.reference::<TimeGranularity>()),
+                ("description", components.reference::<DescriptionPolicy>()),
+                ("photos", components.reference::<PhotoPolicy>()),
+                ("photo_size", components.reference::<PhotoSize>()),
+            ],
+            &["description", "photos", "photo_size"],
+        )
+    }
+}
+
+impl Schema for Friend {
+    const NAME: &'static str = "Friend";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![
+                ("node_id", string()),
+                ("friend_type", describe(string(), "name of a tier")),
+                ("last_pinged", timestamp()),
+            ],
+            &[],
+        )
+    }
+}
+
+// archives and imports, archive.rs and ics.rs
+
+impl Schema for Archive {
+    const NAME: &'static str = "Archive";
+    fn schema(components: &mut Components) -> Value {
+        object(
+            vec![
+                ("version", integer()),
+                ("exported_at", timestamp()),
+                ("node", string()),
+                ("locations", array(components.reference::<Location>())),
+                ("friends", array(components.reference::<Friend>())),
+                ("custom_lists", map(array(string()))),
+                ("tiers", array(components.reference::<Tier>())),
+                ("settings", map(string())),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for ImportCounts {
+    const NAME: &'static str = "ImportCounts";
+    fn schema(_: &mut Components) -> Value {
+        object(vec![("imported", integer()), ("skipped", integer())], &[])
+    }
+}
+
+impl Schema for ImportReport {
+    const NAME: &'static str = "ImportReport";
+    fn schema(components: &mut Components) -> Value {
+        let counts = components.reference::<ImportCounts>();
+        object(
+            vec![
+                ("locations", counts.clone()),
+                ("friends", counts.clone()),
+                ("tiers", counts.clone()),
+                ("settings", counts),
+                ("custom_list_members", integer()),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for IcsImportReport {
+    const NAME: &'static str = "IcsImportReport";
+    fn schema(components: &mut Components) -> Value {
+        object(
+            vec![
+                ("created", array(components.reference::<ImportedEvent>())),
+                ("skipped", array(components.reference::<SkippedEvent>())),
+                ("ambiguous", array(components.reference::<AmbiguousEvent>())),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for ImportedEvent {
+    const NAME: &'static str = "ImportedEvent";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![
+                ("uid", nullable(string())),
+                ("summary", string()),
+                ("uuid", uuid()),
+                ("place", nullable(string())),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for SkippedEvent {
+    const NAME: &'static str = "SkippedEvent";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![
+                ("uid", nullable(string())),
+                ("summary", string()),
+                ("reason", string()),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for AmbiguousEvent {
+    const NAME: &'static str = "AmbiguousEvent";
+    fn schema(_: &mut Components) -> Value {
+        object(
+            vec![
+                ("uid", nullable(string())),
+                ("summary", string()),
+                ("location", string()),
+                ("candidates", array(string())),
+            ],
+            &[],
+        )
+    }
+}
+
+// overlaps, free windows and notifications
+
+impl Schema for MatchKind {
+    const NAME: &'static str = "MatchKind";
+    fn schema(_: &mut Components) -> Value {
+        one_of_strings(&["SameCity", "Nearby", "SameRegion", "SameCountry"])
+    }
+}
+
+impl Schema for Overlap {
+    const NAME: &'static str = "Overlap";
+    fn schema(components: &mut Components) -> Value {
+        object(
+            vec![
+                ("location", components.reference::<Location>()),
+                ("match_kind", components.reference::<MatchKind>()),
+                ("distance_km", number()),
+                ("overlap_start", timestamp()),
+                ("overlap_end", timestamp()),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for PlanOverlaps {
+    const NAME: &'static str = "PlanOverlaps";
+    fn schema(components: &mut Components) -> Value {
+        object(
+            vec![
+                ("location", components.reference::<Location>()),
+                ("overlaps", array(components.reference::<Overlap>())),
+            ],
+            &[],
+        )
+    }
+}
+
+impl Schema for FreeWindow {
+    const NAME: &'static str = "FreeWindow";
+    fn schema(_: &mut Components) -> Value {
+        object(vec![("start", timestamp()), ("end", timestamp())], &[])
+    }
+}
+
+impl Schema for Notification {
+    const NAME: &'static str = "Notification";
+    fn schema(components: &mut Components) -> Value {
+        object(
+            vec![
+                ("id", integer()),
+                ("created_at", timestamp()),
+                ("friend", string()),
+                ("our_location", uuid()),
+                ("friend_location", uuid()),
+                ("match_kind", components.reference::<MatchKind>()),
+                ("overlap_start", timestamp()),
+                ("overlap_end", timestamp()),
+                ("message", string()),
+                ("read", boolean()),
+            ],
+            &[],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{de::DeserializeOwned, Serialize};
+    use uuid::Uuid;
+
+    use crate::state::default_tiers;
+
+    /// Checks `value` against `schema` for the parts of JSON schema used above. Objects
+    /// are closed: a serialized field the schema doesn't list is an error.
+    fn validate(
+        schema: &Value,
+        value: &Value,
+        components: &Components,
+        at: &str,
+    ) -> Result<(), String> {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            let schema = components
+                .0
+                .get(name)
+                .ok_or_else(|| format!("{at}: unknown {reference}"))?;
+            return validate(schema, value, components, at);
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(options) = schema[key].as_array() {
+                let errors: Vec<String> = options
+                    .iter()
+                    .filter_map(|option| validate(option, value, components, at).err())
+                    .collect();
+                return match errors.len() < options.len() {
+                    true => Ok(()),
+                    false => Err(errors.join("; ")),
+                };
+            }
+        }
+        if let Some(allowed) = schema["enum"].as_array() {
+            if !allowed.contains(value) {
+                return Err(format!("{at}: {value} is not one of {allowed:?}"));
+            }
+        }
+        let matches = match schema["type"].as_str() {
+            Some("null") => value.is_null(),
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_i64() || value.is_u64(),
+            Some("number") => value.is_number(),
+            Some("boolean") => value.is_boolean(),
+            Some("array") => value.is_array(),
+            Some("object") => value.is_object(),
+            Some(other) => return Err(format!("{at}: unknown type {other}")),
+            None => true,
+        };
+        if !matches {
+            return Err(format!("{at}: {value} is not {}", schema["type"]));
+        }
+
+        if let Some(items) = value.as_array() {
+            for (i, item) in items.iter().enumerate() {
+                validate(&schema["items"], item, components, &format!("{at}[{i}]"))?;
+            }
+        }
+        if let Some(fields) = value.as_object() {
+            for required in schema["required"].as_array().into_iter().flatten() {
+                let required = required.as_str().unwrap_or_default();
+                if !fields.contains_key(required) {
+                    return Err(format!("{at}: missing {required}"));
+                }
+            }
+            for (name, field) in fields {
+                let at = format!("{at}.{name}");
+                match (
+                    schema["properties"].get(name),
+                    schema.get("additionalProperties"),
+                ) {
+                    (Some(property), _) | (None, Some(property)) => {
+                        validate(property, field, components, &at)?
+                    }
+                    (None, None) => return Err(format!("{at}: not in the schema")),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check<T: Schema + Serialize>(value: &T) {
+        let mut components = Components::default();
+        let reference = components.reference::<T>();
+        let json = serde_json::to_value(value).unwrap();
+        if let Err(e) = validate(&reference, &json, &components, T::NAME) {
+            panic!("{} disagrees with its schema: {}", T::NAME, e);
+        }
+    }
+
+    /// Every variant of `$enum`, built from the listed fields. The match is exhaustive, so
+    /// a variant added later doesn't compile until it's listed, and then checked.
+    macro_rules! every_variant {
+        ($enum:ident { $($variant:ident $({ $($field:ident: $value:expr),* })?),* $(,)? }) => {{
+            let _ = |value: &$enum| match value {
+                $($enum::$variant { .. } => {})*
+            };
+            vec![$($enum::$variant $({ $($field: $value),* })?),*]
+        }};
+    }
+
+    /// Checks each variant against the enum's schema, and that the schema names no others.
+    fn check_variants<T: Schema + Serialize>(variants: &[T]) {
+        let mut components = Components::default();
+        components.reference::<T>();
+        let schema = &components.0[T::NAME];
+        let options = match schema["oneOf"].as_array() {
+            Some(options) => options.clone(),
+            None => vec![schema.clone()],
+        };
+        // bare strings in an enum, variants with data as an object's single property
+        let mut listed: Vec<String> = options
+            .iter()
+            .flat_map(|option| match option["enum"].as_array() {
+                Some(names) => names.iter().map(|name| name.to_string()).collect(),
+                None => option["properties"]
+                    .as_object()
+                    .unwrap()
+                    .keys()
+                    .map(|name| Value::from(name.as_str()).to_string())
+                    .collect::<Vec<_>>(),
+            })
+            .collect();
+        let mut names: Vec<String> = variants
+            .iter()
+            .map(|variant| {
+                check(variant);
+                match serde_json::to_value(variant).unwrap() {
+                    Value::Object(fields) => Value::from(fields.keys().next().unwrap().as_str()),
+                    name => name,
+                }
+                .to_string()
+            })
+            .collect();
+        listed.sort();
+        names.sort();
+        assert_eq!(
+            names,
+            listed,
+            "{} has other variants than its schema",
+            T::NAME
+        );
+    }
+
+    /// Only the fields the schema calls required, which must be enough to deserialize.
+    fn check_minimal<T: Schema + Serialize + DeserializeOwned>(value: &T) {
+        let mut components = Components::default();
+        components.reference::<T>();
+        let required = components.0[T::NAME]["required"]
+            .as_array()
+            .unwrap()
+            .clone();
+        let mut json = serde_json::to_value(value).unwrap();
+        json.as_object_mut()
+            .unwrap()
+            .retain(|name, _| required.contains(&Value::from(name.as_str())));
+        if let Err(e) = serde_json::from_value::<T>(json) {
+            panic!("{} needs more than its required fields: {}", T::NAME, e);
+        }
+    }
+
+    fn location() -> Location {
+        Location {
+            uuid: Uuid::new_v4(),
+            start_date: 1_720_000_000,
+            end_date: 1_720_500_000,
+            owner: "alice.os".into(),
+            description: "conference".into(),
+            latitude: 52.52,
+            longitude: 13.405,
+            photos: vec!["photo.jpg".into()],
+            visibility: Visibility {
+                private: false,
+                max_tier: Some("CloseFriend".into()),
+                allow: vec!["bob.os".into()],
+                deny: vec![],
+            },
+            public_summary: "in Berlin".into(),
+            timezone: "Europe/Berlin".into(),
+            shared_as: Some(SharedGranularity {
+                location: LocationGranularity::Neighbourhood { radius_m: 500.0 },
+                time: TimeGranularity::Day,
+            }),
+            recurrence: Some("FREQ=YEARLY;COUNT=3".parse().unwrap()),
+            is_home: false,
+        }
+    }
+
+    fn bare_location() -> Location {
+        Location {
+            visibility: Visibility::default(),
+            shared_as: None,
+            recurrence: None,
+            ..location()
+        }
+    }
+
+    fn friend() -> Friend {
+        Friend {
+            node_id: "bob.os".into(),
+            friend_type: "Best".into(),
+            last_pinged: 1_720_000_000,
+        }
+    }
+
+    fn overlap() -> Overlap {
+        Overlap {
+            location: location(),
+            match_kind: MatchKind::Nearby,
+            distance_km: 3.5,
+            overlap_start: 1_720_000_000,
+            overlap_end: 1_720_100_000,
+        }
+    }
+
+    #[test]
+    fn locations_match_their_schemas() {
+        for location in [location(), bare_location()] {
+            check(&location);
+            check_minimal(&location);
+            check(&LocationView::new(location.clone(), None));
+            check(&LocationView::new(
+                location,
+                Some(chrono_tz::Tz::Asia__Tokyo),
+            ));
+        }
+        let new_location = NewLocation {
+            start_date: 1_720_000_000,
+            end_date: 1_720_500_000,
+            description: "conference".into(),
+            latitude: 52.52,
+            longitude: 13.405,
+            photos: vec![],
+            visibility: Visibility::default(),
+            public_summary: String::new(),
+            timezone: Some("Europe/Berlin".into()),
+            recurrence: Some("FREQ=WEEKLY;INTERVAL=2".parse().unwrap()),
+            is_home: true,
+        };
+        check(&new_location);
+        check_minimal(&new_location);
+    }
+
+    #[test]
+    fn friends_and_tiers_match_their_schemas() {
+        check(&friend());
+        check_minimal(&friend());
+        let mut tiers: Vec<Tier> = default_tiers().into_values().collect();
+        tiers.push(Tier {
+            name: "Neighbours".into(),
+            location: LocationGranularity::Neighbourhood { radius_m: 1000.0 },
+            time: TimeGranularity::Month,
+            description: DescriptionPolicy::Hidden,
+            photos: PhotoPolicy::Limit { max: 2 },
+            photo_size: PhotoSize::Thumb,
+        });
+        for tier in &tiers {
+            check(tier);
+            check_minimal(tier);
+        }
+        let friends = [friend()];
+        check(&PendingFriendsResponse {
+            incoming: friends.iter().collect(),
+            outgoing: vec![],
+        });
+    }
+
+    #[test]
+    fn imports_and_exports_match_their_schemas() {
+        let archive = Archive {
+            version: 1,
+            exported_at: 1_720_000_000,
+            node: "alice.os".into(),
+            locations: vec![location(), bare_location()],
+            friends: vec![friend()],
+            custom_lists: [("hiking".to_string(), vec!["bob.os".to_string()])].into(),
+            tiers: default_tiers().into_values().collect(),
+            settings: [("fuzz_key".to_string(), "00ff".to_string())].into(),
+        };
+        check(&archive);
+        check_minimal(&archive);
+        check(&ImportReport::default());
+        check(&IcsImportReport {
+            created: vec![ImportedEvent {
+                uid: Some("event-1".into()),
+                summary: "conference".into(),
+                uuid: Uuid::new_v4(),
+                place: None,
+            }],
+            skipped: vec![SkippedEvent {
+                uid: None,
+                summary: "lunch".into(),
+                reason: "no GEO or LOCATION".into(),
+            }],
+            ambiguous: vec![AmbiguousEvent {
+                uid: None,
+                summary: "trip".into(),
+                location: "Springfield".into(),
+                candidates: vec!["Springfield, Illinois, US".into()],
+            }],
+        });
+    }
+
+    #[test]
+    fn plans_match_their_schemas() {
+        check(&overlap());
+        check(&PlanOverlaps {
+            location: bare_location(),
+            overlaps: vec![overlap()],
+        });
+        check(&FreeWindow {
+            start: 1_720_000_000,
+            end: 1_720_100_000,
+        });
+        check(&Notification {
+            id: 1,
+            created_at: 1_719_000_000,
+            friend: "bob.os".into(),
+            our_location: Uuid::new_v4(),
+            friend_location: Uuid::new_v4(),
+            match_kind: MatchKind::SameCity,
+            overlap_start: 1_720_000_000,
+            overlap_end: 1_720_100_000,
+            message: "bob.os will be in Berlin, DE while you are, Jul 3 to Jul 4".into(),
+            read: false,
+        });
+    }
+
+    #[test]
+    fn api_bodies_match_their_schemas() {
+        check(&MessageResponse::new("done"));
+        check(&PhotoUploadResponse {
+            photos: vec![Uuid::new_v4().to_string()],
+            suggestion: None,
+        });
+        check(&PhotoUploadResponse {
+            photos: vec![Uuid::new_v4().to_string()],
+            suggestion: Some(LocationSuggestion {
+                start_date: Some(1_720_000_000),
+                end_date: Some(1_720_003_600),
+                latitude: Some(38.72),
+                longitude: Some(-9.14),
+                city: Some("Lisbon".into()),
+            }),
+        });
+        let photo = Photo {
+            id: Uuid::new_v4(),
+            mime: "image/jpeg".into(),
+            size: 2_400_000,
+            created_at: 1_720_010_000,
+            taken_at: Some(1_720_000_000),
+            latitude: Some(38.72),
+            longitude: Some(-9.14),
+        };
+        check(&photo);
+        check(&Photo {
+            taken_at: None,
+            latitude: None,
+            longitude: None,
+            ..photo
+        });
+        check(&TokenResponse {
+            token: "abc".into(),
+        });
+        check(&ErrorResponse {
+            error: "location not found".into(),
+            code: "not_found",
+        });
+        for process in [Some("notify:notify:sys".to_string()), None] {
+            check(&NotifyPushRequest { process });
+        }
+        // request bodies are only deserialized, check what a client would send parses
+        let parses = |name: &str, schema: Value, body: Value| {
+            let mut components = Components::default();
+            components.0.insert("body", schema);
+            let reference = json!({ "$ref": "#/components/schemas/body" });
+            if let Err(e) = validate(&reference, &body, &components, name) {
+                panic!("{name} disagrees with its schema: {e}");
+            }
+        };
+        let mut components = Components::default();
+        let body = json!({ "node_id": "bob.os" });
+        parses(
+            "NodeIdRequest",
+            NodeIdRequest::schema(&mut components),
+            body.clone(),
+        );
+        serde_json::from_value::<NodeIdRequest>(body).unwrap();
+        let body = json!({ "node_id": "bob.os", "friend_type": "Best" });
+        parses(
+            "FriendTypeRequest",
+            FriendTypeRequest::schema(&mut components),
+            body.clone(),
+        );
+        serde_json::from_value::<FriendTypeRequest>(body).unwrap();
+        let body = json!({ "list_name": "hiking", "node_id": "bob.os" });
+        parses(
+            "CustomListRequest",
+            CustomListRequest::schema(&mut components),
+            body.clone(),
+        );
+        serde_json::from_value::<CustomListRequest>(body).unwrap();
+        let body = json!({ "name": "Best" });
+        parses(
+            "TierNameRequest",
+            TierNameRequest::schema(&mut components),
+            body.clone(),
+        );
+        serde_json::from_value::<TierNameRequest>(body).unwrap();
+        for body in [json!({}), json!({ "ids": [1, 2] })] {
+            parses(
+                "MarkReadRequest",
+                MarkReadRequest::schema(&mut components),
+                body.clone(),
+            );
+            serde_json::from_value::<MarkReadRequest>(body).unwrap();
+        }
+    }
+
+    #[test]
+    fn enums_match_their_schemas() {
+        check_variants(&every_variant!(LocationGranularity {
+            Exact,
+            Neighbourhood { radius_m: 500.0 },
+            City,
+            Region,
+            Country,
+            Hidden,
+        }));
+        check_variants(&every_variant!(TimeGranularity {
+            Exact,
+            Day,
+            Week,
+            Month,
+            Hidden,
+        }));
+        check_variants(&every_variant!(DescriptionPolicy {
+            Full,
+            PublicSummary,
+            Hidden,
+        }));
+        check_variants(&every_variant!(PhotoPolicy {
+            All,
+            Limit { max: 2 },
+            Hidden,
+        }));
+        check_variants(&every_variant!(PhotoSize {
+            Original,
+            Web,
+            Thumb,
+        }));
+        check_variants(&every_variant!(MatchKind {
+            SameCity,
+            Nearby,
+            SameRegion,
+            SameCountry,
+        }));
+    }
+
+    #[test]
+    fn path_templates() {
+        assert_eq!(path_template("/api/locations/:id"), "/api/locations/{id}");
+        assert_eq!(path_template("/api/locations"), "/api/locations");
+    }
+}
@@ -0,0 +1,381 @@
+use anyhow::Result;
+use kinode_process_lib::NodeId;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{
+    haversine_m, Location, LocationFilter, LocationGranularity, SharedGranularity, State,
+};
+
+/// How far "nearby" reaches when the caller doesn't say.
+pub const DEFAULT_RADIUS_KM: f64 = 25.0;
+
+/// A place and time to look for friends around.
+#[derive(Debug, Clone, Copy)]
+pub struct Window {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub start: i64,
+    pub end: i64,
+}
+
+impl From<&Location> for Window {
+    fn from(location: &Location) -> Self {
+        Self {
+            latitude: location.latitude,
+            longitude: location.longitude,
+            start: location.start_date,
+            end: location.end_date,
+        }
+    }
+}
+
+/// How sure we can be a friend is where we are, strongest first. A friend who
+/// shared a location at country granularity can only ever be a `SameCountry` match.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MatchKind {
+    SameCity,
+    Nearby, // within the radius, allowing for how far the shared point may be off
+    SameRegion,
+    SameCountry,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Overlap {
+    pub location: Location, // the friend's, as shared with us
+    pub match_kind: MatchKind,
+    pub distance_km: f64, // between the shared points, rough when they were fuzzed
+    pub overlap_start: i64,
+    pub overlap_end: i64,
+}
+
+/// Friends' locations overlapping `window` in time and close to it in space.
+pub fn find_overlaps(
+    state: &State,
+    our: &NodeId,
+    window: Window,
+    radius_m: f64,
+) -> Result<Vec<Overlap>> {
+    // time can be filtered in SQL; space can't, a country-level location sits at the
+    // country's centroid however far that is from the point that matched
+    let filter = LocationFilter {
+        start: Some(window.start),
+        end: Some(window.end),
+        ..Default::default()
+    };
+    let candidates = state.get_locations(&filter)?.locations;
+
+    let mut overlaps: Vec<Overlap> = candidates
+        .into_iter()
+        .filter(|location| &location.owner != our)
+        .filter_map(|location| {
+            let match_kind = classify(state, &window, &location, radius_m)?;
+            Some(Overlap {
+                match_kind,
+                distance_km: haversine_m(
+                    window.latitude,
+                    window.longitude,
+                    location.latitude,
+                    location.longitude,
+                ) / 1000.0,
+                overlap_start: window.start.max(location.start_date),
+                overlap_end: window.end.min(location.end_date),
+                location,
+            })
+        })
+        .collect();
+    overlaps.sort_by(|a, b| {
+        (a.match_kind, a.overlap_start)
+            .cmp(&(b.match_kind, b.overlap_start))
+            .then(a.distance_km.total_cmp(&b.distance_km))
+    });
+    Ok(overlaps)
+}
+
+/// Whether `location` is near `window`, at the precision it was shared with us.
+pub fn classify(
+    state: &State,
+    window: &Window,
+    location: &Location,
+    radius_m: f64,
+) -> Option<MatchKind> {
+    let geo = &state.geo_protocol;
+    let our_city = geo.closest_city(window.longitude, window.latitude)?;
+    let their_city = geo.closest_city(location.longitude, location.latitude)?;
+    let same_city = our_city.name == their_city.name && our_city.country == their_city.country;
+    let distance = haversine_m(
+        window.latitude,
+        window.longitude,
+        location.latitude,
+        location.longitude,
+    );
+
+    // locations from before shared_as existed were sent as-is or city-rounded, treat as exact
+    let granularity = location
+        .shared_as
+        .as_ref()
+        .map(|shared: &SharedGranularity| &shared.location)
+        .unwrap_or(&LocationGranularity::Exact);
+    match granularity {
+        LocationGranularity::Exact | LocationGranularity::City if same_city => {
+            Some(MatchKind::SameCity)
+        }
+        LocationGranularity::Exact | LocationGranularity::City => {
+            (distance <= radius_m).then_some(MatchKind::Nearby)
+        }
+        // the shared point is up to radius_m off, neither same city nor in range is certain
+        LocationGranularity::Neighbourhood { radius_m: fuzz_m } => {
+            (distance <= radius_m + fuzz_m).then_some(MatchKind::Nearby)
+        }
+        LocationGranularity::Region => {
+            let ours = geo.closest_region(window.longitude, window.latitude);
+            let theirs = geo.closest_region(location.longitude, location.latitude);
+            match (ours, theirs) {
+                (Some(ours), Some(theirs)) => {
+                    (ours.code == theirs.code).then_some(MatchKind::SameRegion)
+                }
+                // a region-level share from a country without region data fell back to its country
+                _ => (our_city.country == their_city.country).then_some(MatchKind::SameCountry),
+            }
+        }
+        LocationGranularity::Country => {
+            (our_city.country == their_city.country).then_some(MatchKind::SameCountry)
+        }
+        LocationGranularity::Hidden => None,
+    }
+}
+
+/// One of our planned locations and who will be around for it.
+#[derive(Debug, Serialize)]
+pub struct PlanOverlaps {
+    pub location: Location,
+    pub overlaps: Vec<Overlap>,
+}
+
+/// Overlaps for each of our locations that hasn't ended yet, soonest first.
+pub fn upcoming_overlaps(
+    state: &State,
+    our: &NodeId,
+    now: i64,
+    radius_m: f64,
+) -> Result<Vec<PlanOverlaps>> {
+    let mut plans: Vec<Location> = state
+        .get_locations_by_owner(our)?
+        .into_iter()
+        .filter(|location| location.end_date >= now)
+        .collect();
+    plans.sort_by_key(|location| location.start_date);
+    plans
+        .into_iter()
+        .map(|location| {
+            let overlaps = find_overlaps(state, our, Window::from(&location), radius_m)?;
+            Ok(PlanOverlaps { location, overlaps })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geocity::{City, GranularityProtocol, Region};
+    use crate::state::{TimeGranularity, Visibility};
+    use uuid::Uuid;
+
+    const DAY: i64 = 86_400;
+    const RADIUS_M: f64 = 25_000.0;
+
+    fn state() -> State {
+        let city = |name: &str, country: &str, admin1: Option<&str>, latitude, longitude| City {
+            name: name.into(),
+            country: country.into(),
+            latitude,
+            longitude,
+            admin1: admin1.map(String::from),
+        };
+        let region = |code: &str, latitude, longitude| Region {
+            code: code.into(),
+            name: code.into(),
+            country: "US".into(),
+            latitude,
+            longitude,
+        };
+        let cities = vec![
+            city("Lisbon", "PT", None, 38.72, -9.14),
+            city("Sintra", "PT", None, 38.80, -9.38),
+            city("Porto", "PT", None, 41.15, -8.61),
+            city("Madrid", "ES", None, 40.42, -3.70),
+            city("Boston", "US", Some("US-MA"), 42.36, -71.06),
+            city("Worcester", "US", Some("US-MA"), 42.26, -71.80),
+            city("Providence", "US", Some("US-RI"), 41.82, -71.41),
+        ];
+        let regions = vec![region("US-MA", 42.3, -71.8), region("US-RI", 41.7, -71.5)];
+        State::in_memory(GranularityProtocol::new(
+            cities,
+            regions,
+            vec![],
+            vec![],
+            [7; 32],
+        ))
+    }
+
+    /// bob's location at a point, shared with us at `granularity`.
+    fn shared(latitude: f64, longitude: f64, granularity: LocationGranularity) -> Location {
+        Location {
+            uuid: Uuid::new_v4(),
+            start_date: 0,
+            end_date: DAY,
+            owner: "bob.os".into(),
+            description: String::new(),
+            latitude,
+            longitude,
+            photos: vec![],
+            visibility: Visibility::default(),
+            public_summary: String::new(),
+            timezone: "UTC".into(),
+            shared_as: Some(SharedGranularity {
+                location: granularity,
+                time: TimeGranularity::Exact,
+            }),
+            recurrence: None,
+            is_home: false,
+        }
+    }
+
+    fn window(latitude: f64, longitude: f64) -> Window {
+        Window {
+            latitude,
+            longitude,
+            start: 0,
+            end: DAY,
+        }
+    }
+
+    #[test]
+    fn exact_and_city_shares_in_our_city_are_the_same_city() {
+        let state = state();
+        let lisbon = window(38.72, -9.14);
+        for granularity in [LocationGranularity::Exact, LocationGranularity::City] {
+            let location = shared(38.73, -9.15, granularity);
+            let kind = classify(&state, &lisbon, &location, RADIUS_M);
+            assert_eq!(kind, Some(MatchKind::SameCity));
+        }
+        // from before shared_as, taken as exact
+        let unmarked = Location {
+            shared_as: None,
+            ..shared(38.73, -9.15, LocationGranularity::Exact)
+        };
+        let kind = classify(&state, &lisbon, &unmarked, RADIUS_M);
+        assert_eq!(kind, Some(MatchKind::SameCity));
+
+        // Sintra is another city, 22 km off
+        let sintra = shared(38.80, -9.38, LocationGranularity::Exact);
+        let kind = classify(&state, &lisbon, &sintra, RADIUS_M);
+        assert_eq!(kind, Some(MatchKind::Nearby));
+        assert_eq!(classify(&state, &lisbon, &sintra, 20_000.0), None);
+    }
+
+    #[test]
+    fn a_neighbourhood_share_widens_the_radius_by_its_fuzz() {
+        let state = state();
+        let lisbon = window(38.72, -9.14);
+        let sintra = shared(
+            38.80,
+            -9.38,
+            LocationGranularity::Neighbourhood { radius_m: 5_000.0 },
+        );
+        // 22 km is outside 20 km, but the shared point may be 5 km from where they are
+        let kind = classify(&state, &lisbon, &sintra, 20_000.0);
+        assert_eq!(kind, Some(MatchKind::Nearby));
+        assert_eq!(classify(&state, &lisbon, &sintra, 15_000.0), None);
+        // and never counts as the same city, the point may be in the next one
+        let in_lisbon = shared(
+            38.72,
+            -9.14,
+            LocationGranularity::Neighbourhood { radius_m: 5_000.0 },
+        );
+        let kind = classify(&state, &lisbon, &in_lisbon, RADIUS_M);
+        assert_eq!(kind, Some(MatchKind::Nearby));
+    }
+
+    #[test]
+    fn region_shares_fall_back_to_the_country_without_region_data() {
+        let state = state();
+        let boston = window(42.36, -71.06);
+        let worcester = shared(42.26, -71.80, LocationGranularity::Region);
+        let providence = shared(41.82, -71.41, LocationGranularity::Region);
+        let kind = classify(&state, &boston, &worcester, RADIUS_M);
+        assert_eq!(kind, Some(MatchKind::SameRegion));
+        assert_eq!(classify(&state, &boston, &providence, RADIUS_M), None);
+
+        let lisbon = window(38.72, -9.14);
+        let porto = shared(41.15, -8.61, LocationGranularity::Region);
+        let madrid = shared(40.42, -3.70, LocationGranularity::Region);
+        let kind = classify(&state, &lisbon, &porto, RADIUS_M);
+        assert_eq!(kind, Some(MatchKind::SameCountry));
+        assert_eq!(classify(&state, &lisbon, &madrid, RADIUS_M), None);
+
+        let country = shared(41.15, -8.61, LocationGranularity::Country);
+        let kind = classify(&state, &lisbon, &country, RADIUS_M);
+        assert_eq!(kind, Some(MatchKind::SameCountry));
+    }
+
+    #[test]
+    fn hidden_never_matches() {
+        let state = state();
+        let hidden = shared(38.72, -9.14, LocationGranularity::Hidden);
+        assert_eq!(
+            classify(&state, &window(38.72, -9.14), &hidden, RADIUS_M),
+            None
+        );
+    }
+
+    #[test]
+    fn sorts_by_match_kind_then_start_then_distance() {
+        let state = state();
+        let at = |latitude, longitude, start_date| Location {
+            start_date,
+            end_date: start_date + DAY,
+            ..shared(latitude, longitude, LocationGranularity::Exact)
+        };
+        let nearby_first = at(38.80, -9.38, 0);
+        let same_city_later = at(38.72, -9.14, 2 * DAY);
+        let same_city_far = at(38.75, -9.10, DAY);
+        let same_city_close = at(38.72, -9.14, DAY);
+        let ours = Location {
+            owner: "alice.os".into(),
+            ..at(38.72, -9.14, DAY)
+        };
+        let after = at(38.72, -9.14, 10 * DAY);
+        for location in [
+            &nearby_first,
+            &same_city_later,
+            &same_city_far,
+            &same_city_close,
+            &ours,
+            &after,
+        ] {
+            state.db.insert_location(location).unwrap();
+        }
+
+        let window = Window {
+            start: 0,
+            end: 4 * DAY,
+            ..window(38.72, -9.14)
+        };
+        let found = find_overlaps(&state, &"alice.os".into(), window, RADIUS_M).unwrap();
+        let order: Vec<Uuid> = found.iter().map(|overlap| overlap.location.uuid).collect();
+        assert_eq!(
+            order,
+            vec![
+                same_city_close.uuid,
+                same_city_far.uuid,
+                same_city_later.uuid,
+                nearby_first.uuid
+            ]
+        );
+        assert_eq!(found[3].match_kind, MatchKind::Nearby);
+        assert_eq!(
+            (found[2].overlap_start, found[2].overlap_end),
+            (2 * DAY, 3 * DAY)
+        );
+    }
+}
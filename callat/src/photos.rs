@@ -0,0 +1,885 @@
+use std::{collections::HashMap, io::Cursor};
+
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use exif::{In, Tag, Value};
+use image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageDecoder, ImageError,
+    ImageReader, Limits,
+};
+use kinode_process_lib::{println, vfs, Address, NodeId};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{error::ApiError, state::DB, TIMEOUT};
+
+/// Largest photo we take, a phone shoots 5-15 MB.
+pub const MAX_PHOTO_BYTES: usize = 25 * 1024 * 1024;
+
+/// Longest edge of the web and thumbnail renditions, in pixels.
+const WEB_EDGE: u32 = 1600;
+const THUMB_EDGE: u32 = 320;
+const JPEG_QUALITY: u8 = 80;
+
+/// What decoding may take, a few KB of PNG can claim to be gigapixels. A 50 MP phone
+/// shot is 8160x6144, 150 MB as RGB.
+const MAX_DECODED_EDGE: u32 = 12_000;
+const MAX_DECODED_BYTES: u64 = 256 * 1024 * 1024;
+
+/// An uploaded image, its bytes at `{drive}/{id}` and its id in `Location.photos`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Photo {
+    pub id: Uuid,
+    pub mime: String,
+    pub size: i64,
+    pub created_at: i64,
+    // from the EXIF, the bytes themselves never leave the node with it
+    pub taken_at: Option<i64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+/// The renditions kept of each photo, from most to least detailed. Web and Thumb are
+/// upright JPEGs scaled down to fit WEB_EDGE and THUMB_EDGE, never up.
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum PhotoSize {
+    #[default]
+    Original,
+    Web,
+    Thumb,
+}
+
+impl PhotoSize {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "original" => Ok(Self::Original),
+            "web" => Ok(Self::Web),
+            "thumb" => Ok(Self::Thumb),
+            _ => Err(
+                ApiError::BadRequest("invalid size: expected original, web or thumb".into()).into(),
+            ),
+        }
+    }
+
+    /// Where the rendition is stored, next to the original at `{drive}/{id}`.
+    fn suffix(&self) -> &'static str {
+        match self {
+            Self::Original => "",
+            Self::Web => ".web",
+            Self::Thumb => ".thumb",
+        }
+    }
+}
+
+/// Photos kept in the package's "photos" VFS drive, indexed in the photos table.
+pub struct PhotoStore {
+    drive: String,
+}
+
+impl PhotoStore {
+    pub fn open(our: &Address) -> Result<Self> {
+        let drive = vfs::create_drive(our.package_id(), "photos", None)?;
+        Ok(Self { drive })
+    }
+
+    /// A store whose drive was never created, for tests that don't touch files.
+    #[cfg(test)]
+    pub fn unopened() -> Self {
+        Self {
+            drive: String::new(),
+        }
+    }
+
+    fn path(&self, id: &Uuid, size: PhotoSize) -> String {
+        format!("{}/{}{}", self.drive, id, size.suffix())
+    }
+
+    /// Stores uploaded images with their smaller renditions, all or none: every file is
+    /// checked and scaled before any is written. When and where each was taken comes from
+    /// its EXIF, the camera's clock read in the `zone` of where it was, None if unknown.
+    pub fn save_all(
+        &self,
+        db: &DB,
+        files: &[&[u8]],
+        zone: impl Fn(Option<(f64, f64)>) -> Tz,
+    ) -> Result<Vec<Photo>> {
+        let mimes = files
+            .iter()
+            .map(|bytes| check_image(bytes))
+            .collect::<Result<Vec<_>>>()?;
+        let renditions = files
+            .iter()
+            .map(|bytes| scale(bytes))
+            .collect::<Result<Vec<_>>>()?;
+        let now = Utc::now().timestamp();
+        let mut photos = Vec::with_capacity(files.len());
+        for ((bytes, mime), renditions) in files.iter().zip(mimes).zip(renditions) {
+            let exif = read_exif(bytes);
+            let photo = Photo {
+                id: Uuid::new_v4(),
+                mime: mime.to_string(),
+                size: bytes.len() as i64,
+                created_at: now,
+                taken_at: exif.timestamp(zone(exif.position)),
+                latitude: exif.position.map(|(latitude, _)| latitude),
+                longitude: exif.position.map(|(_, longitude)| longitude),
+            };
+            vfs::create_file(&self.path(&photo.id, PhotoSize::Original), None)?.write(bytes)?;
+            for (size, scaled) in &renditions {
+                vfs::create_file(&self.path(&photo.id, *size), None)?.write(scaled)?;
+            }
+            db.insert_photo(&photo)?;
+            photos.push(photo);
+        }
+        Ok(photos)
+    }
+
+    /// The photo as `size`, with the mime type of that rendition.
+    pub fn load(&self, db: &DB, id: &Uuid, size: PhotoSize) -> Result<(String, Vec<u8>)> {
+        let photo = db
+            .get_photo(id)?
+            .ok_or_else(|| ApiError::NotFound("photo not found".into()))?;
+        let original = || vfs::open_file(&self.path(id, PhotoSize::Original), false, None)?.read();
+        if size == PhotoSize::Original {
+            return Ok((photo.mime, original()?));
+        }
+        if let Ok(bytes) =
+            vfs::open_file(&self.path(id, size), false, None).and_then(|file| file.read())
+        {
+            return Ok(("image/jpeg".into(), bytes));
+        }
+        // uploaded before there were renditions, make them now
+        let mut found = None;
+        for (scaled_size, scaled) in scale(&original()?)? {
+            if let Err(e) = vfs::create_file(&self.path(id, scaled_size), None)
+                .and_then(|file| file.write(&scaled))
+            {
+                println!("failed to store {:?} of photo {}: {:?}", scaled_size, id, e);
+            }
+            if scaled_size == size {
+                found = Some(scaled);
+            }
+        }
+        let bytes = found.ok_or_else(|| anyhow!("no {:?} rendition", size))?;
+        Ok(("image/jpeg".into(), bytes))
+    }
+
+    /// Deletes those of `ids` that no location refers to anymore. Entries that aren't
+    /// our photos, like links kept from before uploads, are left alone.
+    pub fn delete_orphans(&self, db: &DB, ids: &[String]) -> Result<()> {
+        for id in ids.iter().filter_map(|id| id.parse::<Uuid>().ok()) {
+            if db.get_photo(&id)?.is_none() || db.photo_in_use(&id)? {
+                continue;
+            }
+            db.delete_photo(&id)?;
+            // the row is what makes a photo exist, a leftover file is only wasted space
+            for size in [PhotoSize::Original, PhotoSize::Web, PhotoSize::Thumb] {
+                if let Err(e) = vfs::remove_file(&self.path(&id, size), None) {
+                    println!("failed to remove {:?} of photo {}: {:?}", size, id, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How long a friend's answer about one of their photos is kept, and how long to wait
+/// before asking again after they didn't answer.
+const FRIEND_PHOTO_TTL: i64 = 60 * 60;
+const FRIEND_PHOTO_RETRY: i64 = 60;
+/// How many bytes of friends' photos are kept, the oldest are dropped first.
+const FRIEND_PHOTO_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// A photo as a rendition, or None for its details.
+pub type FriendPhotoKey = (Uuid, Option<PhotoSize>);
+
+/// Where asking a friend about one of their photos is at.
+#[derive(Debug, Clone)]
+pub enum Fetch {
+    Pending,
+    /// their response body, and the image for a rendition
+    Answered {
+        body: Vec<u8>,
+        bytes: Vec<u8>,
+    },
+    Unanswered, // offline or timed out
+}
+
+struct FetchEntry {
+    owner: NodeId,
+    at: i64,
+    fetch: Fetch,
+}
+
+/// Friends' answers about their photos. They're asked in the background so an HTTP request
+/// never waits on another node, and the UI asks again until the answer is here.
+#[derive(Default)]
+pub struct FriendPhotos {
+    entries: HashMap<FriendPhotoKey, FetchEntry>,
+}
+
+impl FriendPhotos {
+    /// Where asking for `key` is at, None if it's time to ask (again).
+    pub fn get(&self, key: &FriendPhotoKey, now: i64) -> Option<&Fetch> {
+        let entry = self.entries.get(key)?;
+        let ttl = match entry.fetch {
+            // the response or a timeout comes back before this
+            Fetch::Pending => TIMEOUT as i64 * 2,
+            Fetch::Answered { .. } => FRIEND_PHOTO_TTL,
+            Fetch::Unanswered => FRIEND_PHOTO_RETRY,
+        };
+        (now - entry.at < ttl).then_some(&entry.fetch)
+    }
+
+    pub fn insert(&mut self, key: FriendPhotoKey, owner: NodeId, fetch: Fetch, now: i64) {
+        self.entries.insert(
+            key,
+            FetchEntry {
+                owner,
+                at: now,
+                fetch,
+            },
+        );
+        self.evict();
+    }
+
+    /// Records the answer to a pending fetch, one nobody is waiting for is dropped.
+    pub fn answer(&mut self, key: &FriendPhotoKey, fetch: Fetch, now: i64) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.at = now;
+            entry.fetch = fetch;
+            self.evict();
+        }
+    }
+
+    /// Drops everything `owner` told, their tiers for us may have changed.
+    pub fn forget(&mut self, owner: &str) {
+        self.entries.retain(|_, entry| entry.owner != owner);
+    }
+
+    fn bytes(&self) -> usize {
+        self.entries
+            .values()
+            .map(|entry| match &entry.fetch {
+                Fetch::Answered { body, bytes } => body.len() + bytes.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    fn evict(&mut self) {
+        while self.bytes() > FRIEND_PHOTO_CACHE_BYTES {
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| matches!(entry.fetch, Fetch::Answered { .. }))
+                .min_by_key(|(_, entry)| entry.at)
+                .map(|(key, _)| *key);
+            match oldest {
+                Some(key) => self.entries.remove(&key),
+                None => break,
+            };
+        }
+    }
+}
+
+/// The Web and Thumb renditions of an image, each scaled from the one before.
+fn scale(bytes: &[u8]) -> Result<Vec<(PhotoSize, Vec<u8>)>> {
+    let mut image = decode(bytes)?;
+    let mut renditions = Vec::new();
+    for (size, edge) in [(PhotoSize::Web, WEB_EDGE), (PhotoSize::Thumb, THUMB_EDGE)] {
+        if image.width() > edge || image.height() > edge {
+            image = image.resize(edge, edge, FilterType::Triangle);
+        }
+        let mut jpeg = Vec::new();
+        // JPEG has no alpha channel, transparent areas come out black
+        JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode_image(&image.to_rgb8())?;
+        renditions.push((size, jpeg));
+    }
+    Ok(renditions)
+}
+
+/// Decodes the image upright. Phones store pictures as shot and record which way is up
+/// in EXIF, which re-encoding drops.
+fn decode(bytes: &[u8]) -> Result<DynamicImage> {
+    let invalid = |e: ImageError| ApiError::Invalid(format!("invalid photo: {}", e));
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DECODED_EDGE);
+    limits.max_image_height = Some(MAX_DECODED_EDGE);
+    limits.max_alloc = Some(MAX_DECODED_BYTES);
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().map_err(invalid)?;
+    let orientation = decoder.orientation().map_err(invalid)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(invalid)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// When and where a photo was taken, as far as its EXIF tells.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Exif {
+    /// the camera's clock, in whatever zone it was set to
+    pub taken_at: Option<NaiveDateTime>,
+    /// of that clock from UTC in minutes, only newer cameras record it
+    pub offset: Option<i16>,
+    pub position: Option<(f64, f64)>, // latitude, longitude
+    /// how to turn the stored pixels upright, 1 being as stored
+    pub orientation: Option<u32>,
+}
+
+impl Exif {
+    /// The capture time in unix seconds, the camera's clock read in `zone` unless it
+    /// recorded its offset.
+    pub fn timestamp(&self, zone: Tz) -> Option<i64> {
+        let taken_at = self.taken_at?;
+        match self.offset {
+            Some(minutes) => Some(taken_at.and_utc().timestamp() - i64::from(minutes) * 60),
+            None => zone
+                .from_local_datetime(&taken_at)
+                .earliest()
+                .map(|time| time.timestamp()),
+        }
+    }
+}
+
+/// The EXIF of a JPEG, PNG or WebP, empty if there's none or it doesn't parse.
+pub fn read_exif(bytes: &[u8]) -> Exif {
+    let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(bytes)) else {
+        return Exif::default();
+    };
+    let field = |tag| exif.get_field(tag, In::PRIMARY).map(|field| &field.value);
+    let ascii = |tag| match field(tag) {
+        Some(Value::Ascii(values)) => values.first().map(|value| value.as_slice()),
+        _ => None,
+    };
+
+    let mut taken_at =
+        ascii(Tag::DateTimeOriginal).and_then(|value| exif::DateTime::from_ascii(value).ok());
+    if let (Some(taken_at), Some(offset)) = (taken_at.as_mut(), ascii(Tag::OffsetTimeOriginal)) {
+        taken_at.parse_offset(offset).ok();
+    }
+
+    // degrees, minutes and seconds, negative to the south and west
+    let coordinate = |tag, reference, negative: &[u8]| {
+        let Some(Value::Rational(dms)) = field(tag) else {
+            return None;
+        };
+        let [degrees, minutes, seconds] = dms.as_slice() else {
+            return None;
+        };
+        let value = degrees.to_f64() + minutes.to_f64() / 60.0 + seconds.to_f64() / 3600.0;
+        let sign = if ascii(reference) == Some(negative) {
+            -1.0
+        } else {
+            1.0
+        };
+        Some(sign * value).filter(|value| value.is_finite())
+    };
+    let position = coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, b"S")
+        .zip(coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, b"W"))
+        // cameras without a fix write zeros
+        .filter(|&(latitude, longitude)| {
+            latitude.abs() <= 90.0
+                && longitude.abs() <= 180.0
+                && (latitude, longitude) != (0.0, 0.0)
+        });
+
+    Exif {
+        taken_at: taken_at.as_ref().and_then(|time| {
+            NaiveDate::from_ymd_opt(time.year.into(), time.month.into(), time.day.into())?
+                .and_hms_opt(time.hour.into(), time.minute.into(), time.second.into())
+        }),
+        offset: taken_at.and_then(|time| time.offset),
+        position,
+        orientation: field(Tag::Orientation).and_then(|value| value.get_uint(0)),
+    }
+}
+
+/// `bytes` without the EXIF, XMP and text metadata that can tell where and when the
+/// photo was taken, for every photo that leaves the node. A JPEG keeps its orientation,
+/// in an EXIF block holding nothing else. GIFs have no EXIF and go as they are.
+pub fn strip_metadata(bytes: &[u8]) -> Result<Vec<u8>> {
+    match check_image(bytes)? {
+        "image/jpeg" => strip_jpeg(bytes, read_exif(bytes).orientation),
+        "image/png" => strip_png(bytes),
+        "image/webp" => strip_webp(bytes),
+        _ => Ok(bytes.to_vec()),
+    }
+}
+
+fn strip_jpeg(bytes: &[u8], orientation: Option<u32>) -> Result<Vec<u8>> {
+    let malformed = || anyhow!("malformed JPEG");
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..2]);
+    // JFIF wants its APP0 right after the start of image, so this goes after any APP0
+    let mut orientation = orientation
+        .filter(|&orientation| orientation != 1)
+        .map(orientation_segment);
+    let mut rest = &bytes[2..];
+    loop {
+        let marker = match rest {
+            [0xFF, 0xFF, ..] => {
+                rest = &rest[1..]; // fill byte
+                continue;
+            }
+            [0xFF, marker, ..] => *marker,
+            _ => return Err(malformed()),
+        };
+        if marker != 0xE0 {
+            if let Some(segment) = orientation.take() {
+                out.extend_from_slice(&segment);
+            }
+        }
+        match marker {
+            // start of scan or end of image: the rest is image data, copied as it is
+            0xDA | 0xD9 => {
+                out.extend_from_slice(rest);
+                return Ok(out);
+            }
+            // markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&rest[..2]);
+                rest = &rest[2..];
+                continue;
+            }
+            _ => {}
+        }
+        let [_, _, high, low, ..] = *rest else {
+            return Err(malformed());
+        };
+        let end = 2 + usize::from(u16::from_be_bytes([high, low]));
+        let segment = rest.get(..end).ok_or_else(malformed)?;
+        // APP1 holds EXIF and XMP, APP13 Photoshop's IPTC
+        if marker != 0xE1 && marker != 0xED {
+            out.extend_from_slice(segment);
+        }
+        rest = &rest[end..];
+    }
+}
+
+/// An APP1 segment whose EXIF holds only the orientation.
+fn orientation_segment(orientation: u32) -> Vec<u8> {
+    let mut segment = vec![0xFF, 0xE1, 0, 34];
+    segment.extend_from_slice(b"Exif\0\0");
+    // big-endian TIFF header, IFD0 at offset 8 with a single SHORT entry
+    segment.extend_from_slice(b"MM\0\x2A\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01");
+    segment.extend_from_slice(&(orientation as u16).to_be_bytes());
+    // value padding, then no next IFD
+    segment.extend_from_slice(&[0; 6]);
+    segment
+}
+
+fn strip_png(bytes: &[u8]) -> Result<Vec<u8>> {
+    let malformed = || anyhow!("malformed PNG");
+    let mut out = bytes[..8].to_vec();
+    let mut rest = &bytes[8..];
+    while !rest.is_empty() {
+        let [a, b, c, d, ..] = *rest else {
+            return Err(malformed());
+        };
+        // length, type, data and CRC
+        let end = 12 + u32::from_be_bytes([a, b, c, d]) as usize;
+        let chunk = rest.get(..end).ok_or_else(malformed)?;
+        if !matches!(&chunk[4..8], b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt") {
+            out.extend_from_slice(chunk);
+        }
+        rest = &rest[end..];
+    }
+    Ok(out)
+}
+
+fn strip_webp(bytes: &[u8]) -> Result<Vec<u8>> {
+    let malformed = || anyhow!("malformed WebP");
+    let mut out = bytes[..12].to_vec();
+    let mut rest = &bytes[12..];
+    while !rest.is_empty() {
+        let [_, _, _, _, a, b, c, d, ..] = *rest else {
+            return Err(malformed());
+        };
+        let size = u32::from_le_bytes([a, b, c, d]) as usize;
+        if rest.len() < 8 + size {
+            return Err(malformed());
+        }
+        // chunks are padded to an even size, though a last one may not be
+        let end = (8 + size + size % 2).min(rest.len());
+        let chunk = &rest[..end];
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" if size > 0 => {
+                let mut chunk = chunk.to_vec();
+                chunk[8] &= !(0x08 | 0x04); // the EXIF and XMP flags
+                out.extend_from_slice(&chunk);
+            }
+            _ => out.extend_from_slice(chunk),
+        }
+        rest = &rest[end..];
+    }
+    let size = u32::try_from(out.len() - 8)?;
+    out[4..8].copy_from_slice(&size.to_le_bytes());
+    Ok(out)
+}
+
+/// The image's type from its first bytes, whatever the upload claimed it was.
+pub fn check_image(bytes: &[u8]) -> Result<&'static str> {
+    if bytes.len() > MAX_PHOTO_BYTES {
+        return Err(ApiError::Invalid(format!(
+            "invalid photo: larger than {} MB",
+            MAX_PHOTO_BYTES / 1024 / 1024
+        ))
+        .into());
+    }
+    let mime = match bytes {
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => "image/png",
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        _ => {
+            return Err(ApiError::Invalid(
+                "invalid photo: expected a JPEG, PNG, GIF or WebP image".into(),
+            )
+            .into())
+        }
+    };
+    Ok(mime)
+}
+
+/// The contents of the file parts of a multipart/form-data body, other fields skipped.
+pub fn multipart_files<'a>(content_type: &str, body: &'a [u8]) -> Result<Vec<&'a [u8]>> {
+    let invalid =
+        |reason: &str| ApiError::BadRequest(format!("invalid multipart body: {}", reason));
+    let boundary = content_type
+        .split(';')
+        .skip(1)
+        .find_map(|param| param.trim().strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty())
+        .ok_or_else(|| invalid("no boundary"))?;
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let next_delimiter = [b"\r\n".as_slice(), &delimiter].concat();
+
+    let start = find(body, &delimiter).ok_or_else(|| invalid("no parts"))?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut files = Vec::new();
+    // each part is "\r\n" headers "\r\n\r\n" content, up to "\r\n--boundary"; "--" after the last
+    while !rest.starts_with(b"--") {
+        rest = rest
+            .strip_prefix(b"\r\n")
+            .ok_or_else(|| invalid("malformed delimiter"))?;
+        let end = find(rest, &next_delimiter).ok_or_else(|| invalid("unterminated part"))?;
+        let part = &rest[..end];
+        rest = &rest[end + next_delimiter.len()..];
+
+        let header_end = find(part, b"\r\n\r\n").ok_or_else(|| invalid("part without headers"))?;
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        let is_file = headers.lines().any(|line| {
+            line.to_ascii_lowercase()
+                .starts_with("content-disposition:")
+                && line.contains("filename=")
+        });
+        if is_file {
+            files.push(&part[header_end + 4..]);
+        }
+    }
+    Ok(files)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10];
+
+    #[test]
+    fn sniffs_image_types() {
+        assert_eq!(check_image(JPEG).unwrap(), "image/jpeg");
+        assert_eq!(check_image(b"GIF89a...").unwrap(), "image/gif");
+        assert_eq!(
+            check_image(b"RIFF\x10\x00\x00\x00WEBPVP8 ").unwrap(),
+            "image/webp"
+        );
+        assert!(check_image(b"<svg xmlns=").is_err());
+        assert!(check_image(b"").is_err());
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    fn dimensions(jpeg: &[u8]) -> (u32, u32) {
+        let image = image::load_from_memory(jpeg).unwrap();
+        (image.width(), image.height())
+    }
+
+    #[test]
+    fn scales_down_to_jpeg_renditions() {
+        let renditions = scale(&png(4000, 2000)).unwrap();
+        let sizes: Vec<_> = renditions.iter().map(|(size, _)| *size).collect();
+        assert_eq!(sizes, vec![PhotoSize::Web, PhotoSize::Thumb]);
+        assert_eq!(check_image(&renditions[0].1).unwrap(), "image/jpeg");
+        assert_eq!(dimensions(&renditions[0].1), (1600, 800));
+        assert_eq!(dimensions(&renditions[1].1), (320, 160));
+
+        // never scaled up
+        let renditions = scale(&png(500, 1000)).unwrap();
+        assert_eq!(dimensions(&renditions[0].1), (500, 1000));
+        assert_eq!(dimensions(&renditions[1].1), (160, 320));
+
+        assert!(scale(JPEG).is_err());
+    }
+
+    #[test]
+    fn keeps_friend_photos_until_stale_or_forgotten() {
+        let mut cache = FriendPhotos::default();
+        let key = (Uuid::new_v4(), Some(PhotoSize::Web));
+        assert!(cache.get(&key, 0).is_none());
+
+        cache.insert(key, "bob.os".into(), Fetch::Pending, 0);
+        assert!(matches!(cache.get(&key, 1), Some(Fetch::Pending)));
+        // no answer ever came, ask again
+        assert!(cache.get(&key, TIMEOUT as i64 * 2).is_none());
+
+        let answered = Fetch::Answered {
+            body: vec![],
+            bytes: vec![0; 10],
+        };
+        cache.answer(&key, answered, 100);
+        assert!(matches!(cache.get(&key, 101), Some(Fetch::Answered { .. })));
+        assert!(cache.get(&key, 100 + FRIEND_PHOTO_TTL).is_none());
+
+        // an answer for something nobody asked about isn't kept
+        let other = (Uuid::new_v4(), None);
+        cache.answer(&other, Fetch::Unanswered, 100);
+        assert!(cache.get(&other, 100).is_none());
+
+        cache.forget("alice.os");
+        assert!(cache.get(&key, 101).is_some());
+        cache.forget("bob.os");
+        assert!(cache.get(&key, 101).is_none());
+    }
+
+    #[test]
+    fn drops_the_oldest_friend_photos_past_the_cap() {
+        let mut cache = FriendPhotos::default();
+        let keys: Vec<FriendPhotoKey> = (0..3).map(|_| (Uuid::new_v4(), None)).collect();
+        for (at, key) in keys.iter().enumerate() {
+            let fetch = Fetch::Answered {
+                body: vec![],
+                bytes: vec![0; FRIEND_PHOTO_CACHE_BYTES / 2],
+            };
+            cache.insert(*key, "bob.os".into(), fetch, at as i64);
+        }
+        assert!(cache.get(&keys[0], 3).is_none());
+        assert!(cache.get(&keys[1], 3).is_some());
+        assert!(cache.get(&keys[2], 3).is_some());
+    }
+
+    #[test]
+    fn refuses_to_decode_huge_images() {
+        assert!(decode(&png(MAX_DECODED_EDGE, 1)).is_ok());
+        assert!(decode(&png(MAX_DECODED_EDGE + 1, 1)).is_err());
+        assert!(decode(&png(1, MAX_DECODED_EDGE + 1)).is_err());
+    }
+
+    #[test]
+    fn sizes_order_from_most_detailed() {
+        assert_eq!(PhotoSize::parse("web").unwrap(), PhotoSize::Web);
+        assert!(PhotoSize::parse("huge").is_err());
+        // a tier capped at Web gets Web when asking for the original, Thumb when asking for Thumb
+        assert_eq!(PhotoSize::Original.max(PhotoSize::Web), PhotoSize::Web);
+        assert_eq!(PhotoSize::Thumb.max(PhotoSize::Web), PhotoSize::Thumb);
+    }
+
+    fn field(tag: Tag, value: Value) -> exif::Field {
+        exif::Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value,
+        }
+    }
+
+    fn ascii(value: &str) -> Value {
+        Value::Ascii(vec![value.as_bytes().to_vec()])
+    }
+
+    fn degrees(degrees: u32, minutes: u32, seconds: u32) -> Value {
+        let rational = |num| exif::Rational { num, denom: 1 };
+        Value::Rational(vec![
+            rational(degrees),
+            rational(minutes),
+            rational(seconds),
+        ])
+    }
+
+    /// Taken in Lisbon, 38°43'12"N 9°8'24"W, on July 3rd 2024 at 18:30 local time.
+    fn lisbon() -> Vec<exif::Field> {
+        vec![
+            field(Tag::DateTimeOriginal, ascii("2024:07:03 18:30:00")),
+            field(Tag::GPSLatitudeRef, ascii("N")),
+            field(Tag::GPSLatitude, degrees(38, 43, 12)),
+            field(Tag::GPSLongitudeRef, ascii("W")),
+            field(Tag::GPSLongitude, degrees(9, 8, 24)),
+            field(Tag::Orientation, Value::Short(vec![6])),
+        ]
+    }
+
+    fn tiff(fields: &[exif::Field]) -> Vec<u8> {
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        tiff.into_inner()
+    }
+
+    fn encode(format: image::ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(8, 4)
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    /// `jpeg` with `fields` as EXIF, in an APP1 right after the start of image.
+    fn with_exif(jpeg: &[u8], fields: &[exif::Field]) -> Vec<u8> {
+        let app1 = [b"Exif\0\0".as_slice(), &tiff(fields)].concat();
+        let length = (app1.len() as u16 + 2).to_be_bytes();
+        [&jpeg[..2], &[0xFF, 0xE1], &length, &app1, &jpeg[2..]].concat()
+    }
+
+    #[test]
+    fn reads_when_and_where_from_exif() {
+        let exif = read_exif(&with_exif(&encode(image::ImageFormat::Jpeg), &lisbon()));
+        let (latitude, longitude) = exif.position.unwrap();
+        assert!((latitude - 38.72).abs() < 1e-9);
+        assert!((longitude + 9.14).abs() < 1e-9);
+        assert_eq!(exif.orientation, Some(6));
+        // no offset recorded, the clock is read in the zone it was taken in
+        assert_eq!(
+            exif.timestamp(chrono_tz::Europe::Lisbon),
+            Some(1_720_027_800)
+        );
+
+        let mut fields = lisbon();
+        fields.push(field(Tag::OffsetTimeOriginal, ascii("+02:00")));
+        let exif = read_exif(&with_exif(&encode(image::ImageFormat::Jpeg), &fields));
+        assert_eq!(
+            exif.timestamp(chrono_tz::Europe::Lisbon),
+            Some(1_720_024_200)
+        );
+
+        let fields = [
+            field(Tag::GPSLatitude, degrees(0, 0, 0)),
+            field(Tag::GPSLongitude, degrees(0, 0, 0)),
+        ];
+        let exif = read_exif(&with_exif(&encode(image::ImageFormat::Jpeg), &fields));
+        assert_eq!(exif.position, None);
+        assert_eq!(
+            read_exif(&encode(image::ImageFormat::Jpeg)),
+            Exif::default()
+        );
+    }
+
+    #[test]
+    fn strips_jpeg_metadata_but_not_orientation() {
+        let jpeg = encode(image::ImageFormat::Jpeg);
+        let stripped = strip_metadata(&with_exif(&jpeg, &lisbon())).unwrap();
+        let expected = Exif {
+            orientation: Some(6),
+            ..Exif::default()
+        };
+        assert_eq!(read_exif(&stripped), expected);
+        assert!(image::load_from_memory(&stripped).is_ok());
+
+        let upright = [field(Tag::Orientation, Value::Short(vec![1]))];
+        assert_eq!(strip_metadata(&with_exif(&jpeg, &upright)).unwrap(), jpeg);
+        assert!(strip_metadata(&jpeg[..12]).is_err()); // cut in a header
+    }
+
+    #[test]
+    fn strips_png_and_webp_metadata() {
+        let png = encode(image::ImageFormat::Png);
+        let tiff = tiff(&lisbon());
+        // after the 8 byte signature and the 25 byte IHDR, the CRC isn't checked
+        let exif_chunk = [
+            (tiff.len() as u32).to_be_bytes().as_slice(),
+            b"eXIf",
+            &tiff,
+            &[0; 4],
+        ]
+        .concat();
+        let tagged = [&png[..33], &exif_chunk, &png[33..]].concat();
+        assert!(read_exif(&tagged).position.is_some());
+        assert_eq!(strip_metadata(&tagged).unwrap(), png);
+
+        // a lossless WebP, extended to say it has EXIF
+        let webp = encode(image::ImageFormat::WebP);
+        let vp8x = [
+            b"VP8X".as_slice(),
+            &10u32.to_le_bytes(),
+            &[0x08, 0, 0, 0, 7, 0, 0, 3, 0, 0],
+        ]
+        .concat();
+        let exif_chunk = [
+            b"EXIF".as_slice(),
+            &(tiff.len() as u32).to_le_bytes(),
+            &tiff,
+        ]
+        .concat();
+        let mut tagged = [&webp[..12], &vp8x, &webp[12..], &exif_chunk].concat();
+        if tiff.len() % 2 == 1 {
+            tagged.push(0);
+        }
+        let riff_size = (tagged.len() as u32 - 8).to_le_bytes();
+        tagged[4..8].copy_from_slice(&riff_size);
+        assert!(read_exif(&tagged).position.is_some());
+
+        let stripped = strip_metadata(&tagged).unwrap();
+        assert_eq!(read_exif(&stripped), Exif::default());
+        assert_eq!(stripped[20], 0); // no EXIF flag left
+        assert_eq!(
+            stripped.len() - 8,
+            u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize
+        );
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn parses_multipart_files() {
+        let body = [
+            b"preamble\r\n--XyZ\r\n".as_slice(),
+            b"Content-Disposition: form-data; name=\"caption\"\r\n\r\nbeach\r\n--XyZ\r\n",
+            b"Content-Disposition: form-data; name=\"file\"; filename=\"a.jpg\"\r\n",
+            b"Content-Type: image/jpeg\r\n\r\n",
+            JPEG,
+            b"\r\n--XyZ\r\n",
+            b"Content-Disposition: form-data; name=\"file\"; filename=\"b.gif\"\r\n\r\nGIF89a\r\n\r\n",
+            b"\r\n--XyZ--\r\n",
+        ]
+        .concat();
+        let files = multipart_files("multipart/form-data; boundary=\"XyZ\"", &body).unwrap();
+        assert_eq!(files, vec![JPEG, b"GIF89a\r\n\r\n".as_slice()]);
+    }
+
+    #[test]
+    fn rejects_broken_multipart() {
+        let body =
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a\"\r\n\r\nabc";
+        assert!(multipart_files("multipart/form-data; boundary=XyZ", body).is_err());
+        assert!(multipart_files("multipart/form-data", body).is_err());
+    }
+}
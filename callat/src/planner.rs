@@ -0,0 +1,217 @@
+use anyhow::Result;
+use kinode_process_lib::NodeId;
+use serde::Serialize;
+
+use crate::{
+    error::ApiError,
+    recurrence,
+    state::{Circle, LocationFilter, State},
+};
+
+/// What to plan: who's coming, when it could happen, and for how long at least.
+#[derive(Debug, Clone)]
+pub struct PlanRequest {
+    pub nodes: Vec<NodeId>,
+    pub start: i64,
+    pub end: i64,
+    pub min_length: i64, // seconds
+    /// being at the destination already doesn't rule a window out
+    pub destination: Option<Circle>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct FreeWindow {
+    pub start: i64,
+    pub end: i64,
+}
+
+/// Intervals in the requested range where none of the nodes has a location
+/// elsewhere, at least `min_length` long.
+///
+/// Friends' times are only as precise as they were shared to us, so a day-rounded
+/// trip blocks the whole day.
+pub fn free_windows(state: &State, plan: &PlanRequest) -> Result<Vec<FreeWindow>> {
+    if plan.start > plan.end {
+        return Err(ApiError::Invalid("invalid range: start is after end".into()).into());
+    }
+    if plan.min_length <= 0 {
+        return Err(ApiError::Invalid(
+            "invalid min_length: expected a positive number of seconds".into(),
+        )
+        .into());
+    }
+
+    let mut busy: Vec<(i64, i64)> = Vec::new();
+    for node in &plan.nodes {
+        let filter = LocationFilter {
+            start: Some(plan.start),
+            end: Some(plan.end),
+            owner: Some(node.clone()),
+            ..Default::default()
+        };
+        for location in state.get_locations(&filter)?.locations {
+            if location.is_home {
+                continue; // being home is what a free window looks like
+            }
+            let at_destination = plan
+                .destination
+                .as_ref()
+                .is_some_and(|circle| circle.contains(location.longitude, location.latitude));
+            if !at_destination {
+                busy.push((
+                    location.start_date.max(plan.start),
+                    location.end_date.min(plan.end),
+                ));
+            }
+        }
+    }
+    busy.sort_unstable();
+
+    Ok(recurrence::gaps(plan.start, plan.end, &busy)
+        .into_iter()
+        .map(|(start, end)| FreeWindow { start, end })
+        .filter(|window| window.end - window.start >= plan.min_length)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geocity::GranularityProtocol;
+    use crate::state::{Location, Visibility};
+    use uuid::Uuid;
+
+    const DAY: i64 = 86_400;
+    const START: i64 = 1_720_000_000;
+
+    fn state() -> State {
+        State::in_memory(GranularityProtocol::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            [7; 32],
+        ))
+    }
+
+    /// `owner` in Lisbon, or Porto, from `from` to `to` days after START.
+    fn trip(state: &State, owner: &str, in_lisbon: bool, from: i64, to: i64) -> Location {
+        let (latitude, longitude) = if in_lisbon {
+            (38.72, -9.14)
+        } else {
+            (41.15, -8.61)
+        };
+        let location = Location {
+            uuid: Uuid::new_v4(),
+            start_date: START + from * DAY,
+            end_date: START + to * DAY,
+            owner: owner.into(),
+            description: String::new(),
+            latitude,
+            longitude,
+            photos: vec![],
+            visibility: Visibility::default(),
+            public_summary: String::new(),
+            timezone: "UTC".into(),
+            shared_as: None,
+            recurrence: None,
+            is_home: false,
+        };
+        state.db.insert_location(&location).unwrap();
+        location
+    }
+
+    fn plan(destination: Option<Circle>) -> PlanRequest {
+        PlanRequest {
+            nodes: vec!["alice.os".into(), "bob.os".into()],
+            start: START,
+            end: START + 10 * DAY,
+            min_length: DAY,
+            destination,
+        }
+    }
+
+    fn window(from: i64, to: i64) -> FreeWindow {
+        FreeWindow {
+            start: START + from * DAY,
+            end: START + to * DAY,
+        }
+    }
+
+    #[test]
+    fn homes_and_the_destination_leave_windows_free() {
+        let state = state();
+        let home = Location {
+            is_home: true,
+            ..trip(&state, "alice.os", false, -100, 100)
+        };
+        state.db.upsert_location(&home, None).unwrap();
+        trip(&state, "bob.os", false, 3, 5);
+        trip(&state, "alice.os", true, 7, 8);
+
+        assert_eq!(
+            free_windows(&state, &plan(None)).unwrap(),
+            vec![window(0, 3), window(5, 7), window(8, 10)]
+        );
+        // alice being in Lisbon already is no reason not to meet there
+        let lisbon = Circle::new(38.72, -9.14, 25_000.0).unwrap();
+        assert_eq!(
+            free_windows(&state, &plan(Some(lisbon))).unwrap(),
+            vec![window(0, 3), window(5, 10)]
+        );
+    }
+
+    #[test]
+    fn windows_are_clamped_to_the_range_and_at_least_min_length() {
+        let state = state();
+        trip(&state, "alice.os", false, -2, 1);
+        trip(&state, "bob.os", false, 9, 12);
+        // a gap of half a day
+        trip(&state, "bob.os", false, 3, 5);
+        let half_day = Location {
+            start_date: START + 5 * DAY + DAY / 2,
+            end_date: START + 7 * DAY,
+            ..trip(&state, "alice.os", false, 5, 5)
+        };
+        state.db.upsert_location(&half_day, None).unwrap();
+        // someone who isn't coming
+        trip(&state, "carol.os", false, 0, 10);
+
+        assert_eq!(
+            free_windows(&state, &plan(None)).unwrap(),
+            vec![window(1, 3), window(7, 9)]
+        );
+        let short = PlanRequest {
+            min_length: DAY / 4,
+            ..plan(None)
+        };
+        assert_eq!(
+            free_windows(&state, &short).unwrap(),
+            vec![
+                window(1, 3),
+                FreeWindow {
+                    start: START + 5 * DAY,
+                    end: START + 5 * DAY + DAY / 2
+                },
+                window(7, 9)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_backwards_ranges_and_empty_lengths() {
+        let state = state();
+        let invalid = |plan: PlanRequest| {
+            let e = free_windows(&state, &plan).unwrap_err();
+            matches!(e.downcast_ref(), Some(ApiError::Invalid(_)))
+        };
+        assert!(invalid(PlanRequest {
+            end: START - 1,
+            ..plan(None)
+        }));
+        assert!(invalid(PlanRequest {
+            min_length: 0,
+            ..plan(None)
+        }));
+    }
+}
@@ -0,0 +1,390 @@
+use anyhow::{anyhow, Error, Result};
+use chrono::{Duration, Months, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Most occurrences one series expands to in a single query, a daily rule over
+/// a long range would otherwise flood the response.
+pub const MAX_OCCURRENCES: usize = 1000;
+
+/// The RRULE subset we support: FREQ, INTERVAL, COUNT and UNTIL, e.g.
+/// "FREQ=YEARLY;UNTIL=20300101T000000Z" for "Lisbon every winter until 2030".
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Recurrence {
+    pub freq: Frequency,
+    pub interval: u32,
+    pub count: Option<u32>, // occurrences in total, the first one included
+    pub until: Option<i64>, // no occurrence starts after this
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl Frequency {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+            Frequency::Yearly => "YEARLY",
+        }
+    }
+}
+
+impl FromStr for Recurrence {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut freq = None;
+        let mut interval = 1;
+        let mut count = None;
+        let mut until = None;
+        for part in s.trim().trim_start_matches("RRULE:").split(';') {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid recurrence: expected KEY=VALUE, got {}", part))?;
+            match key.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    freq = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        _ => return Err(anyhow!("invalid recurrence: unsupported FREQ {}", value)),
+                    })
+                }
+                "INTERVAL" => {
+                    interval = value
+                        .parse()
+                        .ok()
+                        .filter(|interval| *interval > 0)
+                        .ok_or_else(|| anyhow!("invalid recurrence: bad INTERVAL {}", value))?
+                }
+                "COUNT" => {
+                    count = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|count| *count > 0)
+                            .ok_or_else(|| anyhow!("invalid recurrence: bad COUNT {}", value))?,
+                    )
+                }
+                "UNTIL" => until = Some(parse_until(value)?),
+                _ => return Err(anyhow!("invalid recurrence: unsupported {}", key)),
+            }
+        }
+        if count.is_some() && until.is_some() {
+            return Err(anyhow!("invalid recurrence: COUNT and UNTIL are exclusive"));
+        }
+        Ok(Self {
+            freq: freq.ok_or_else(|| anyhow!("invalid recurrence: FREQ is required"))?,
+            interval,
+            count,
+            until,
+        })
+    }
+}
+
+/// UNTIL as a UTC date-time ("20300101T000000Z") or a date, taken as its end in UTC.
+fn parse_until(value: &str) -> Result<i64> {
+    let invalid = || anyhow!("invalid recurrence: bad UNTIL {}", value);
+    if let Ok(datetime) = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ") {
+        return Ok(datetime.and_utc().timestamp());
+    }
+    let date = chrono::NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| invalid())?;
+    let end_of_day = date.and_hms_opt(23, 59, 59).ok_or_else(invalid)?;
+    Ok(end_of_day.and_utc().timestamp())
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FREQ={}", self.freq.as_str())?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        if let Some(until) = self
+            .until
+            .and_then(|until| chrono::DateTime::from_timestamp(until, 0))
+        {
+            write!(f, ";UNTIL={}", until.format("%Y%m%dT%H%M%SZ"))?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for Recurrence {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Recurrence> for String {
+    fn from(recurrence: Recurrence) -> Self {
+        recurrence.to_string()
+    }
+}
+
+impl Recurrence {
+    /// The (start, end) of each occurrence of `start..end` that overlaps
+    /// `range_start..=range_end`, the first one being `start..end` itself.
+    ///
+    /// Steps are taken on the wall clock in `tz`, so "every winter from the 1st of
+    /// December, 09:00" stays at 09:00 across daylight saving changes.
+    pub fn occurrences(
+        &self,
+        start: i64,
+        end: i64,
+        tz: Tz,
+        range_start: i64,
+        range_end: i64,
+    ) -> Vec<(i64, i64)> {
+        let Some(first) = tz.timestamp_opt(start, 0).single() else {
+            return Vec::new();
+        };
+        let first = first.naive_local();
+        let length = end - start;
+        let last_start = self.until.unwrap_or(i64::MAX).min(range_end);
+
+        // fixed-length steps can jump close to the range instead of walking there
+        let step_days = match self.freq {
+            Frequency::Daily => Some(self.interval as i64),
+            Frequency::Weekly => Some(self.interval as i64 * 7),
+            Frequency::Monthly | Frequency::Yearly => None,
+        };
+        let mut n: u32 = match step_days {
+            Some(days) => {
+                let step = days * 24 * 60 * 60;
+                // one step of slack for daylight saving shifts
+                // saturating, the range comes straight from a query string
+                let skip = range_start.saturating_sub(length).saturating_sub(start) / step - 1;
+                skip.clamp(0, u32::MAX as i64) as u32
+            }
+            None => 0,
+        };
+
+        let mut occurrences = Vec::new();
+        while occurrences.len() < MAX_OCCURRENCES {
+            if self.count.is_some_and(|count| n >= count) {
+                break;
+            }
+            let Some(local) = self.nth(first, n) else {
+                break;
+            };
+            let Some(occurrence_start) = from_local(local, tz) else {
+                break;
+            };
+            if occurrence_start > last_start {
+                break;
+            }
+            let occurrence_end = occurrence_start + length;
+            if occurrence_end >= range_start {
+                occurrences.push((occurrence_start, occurrence_end));
+            }
+            n += 1;
+        }
+        occurrences
+    }
+
+    /// The wall-clock start of the `n`th occurrence, None past chrono's range.
+    fn nth(&self, first: NaiveDateTime, n: u32) -> Option<NaiveDateTime> {
+        let steps = n.checked_mul(self.interval)?;
+        match self.freq {
+            Frequency::Daily => first.checked_add_signed(Duration::days(steps as i64)),
+            Frequency::Weekly => first.checked_add_signed(Duration::weeks(steps as i64)),
+            // the 31st in a shorter month lands on its last day
+            Frequency::Monthly => first.checked_add_months(Months::new(steps)),
+            Frequency::Yearly => first.checked_add_months(Months::new(steps.checked_mul(12)?)),
+        }
+    }
+}
+
+/// A wall-clock time in `tz`, an hour later if it falls in a daylight saving gap.
+fn from_local(local: NaiveDateTime, tz: Tz) -> Option<i64> {
+    tz.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|datetime| datetime.timestamp())
+}
+
+/// The parts of `start..end` not covered by any of `busy`, which must be sorted by start.
+pub fn gaps(start: i64, end: i64, busy: &[(i64, i64)]) -> Vec<(i64, i64)> {
+    let mut gaps = Vec::new();
+    let mut free_from = start;
+    for &(busy_start, busy_end) in busy {
+        if busy_start >= end {
+            break;
+        }
+        if busy_start > free_from {
+            gaps.push((free_from, busy_start));
+        }
+        free_from = free_from.max(busy_end);
+    }
+    if end > free_from {
+        gaps.push((free_from, end));
+    }
+    gaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, Utc};
+
+    const HOUR: i64 = 60 * 60;
+    const DAY: i64 = 24 * HOUR;
+
+    fn at(tz: Tz, y: i32, m: u32, d: u32, h: u32) -> i64 {
+        tz.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap().timestamp()
+    }
+
+    fn dates(occurrences: &[(i64, i64)], tz: Tz) -> Vec<NaiveDate> {
+        occurrences
+            .iter()
+            .map(|(start, _)| tz.timestamp_opt(*start, 0).unwrap().date_naive())
+            .collect()
+    }
+
+    fn rule(s: &str) -> Recurrence {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn steps_on_the_wall_clock_across_daylight_saving() {
+        let tz = chrono_tz::Europe::Lisbon;
+        let start = at(tz, 2024, 3, 29, 9);
+        let occurrences =
+            rule("FREQ=DAILY").occurrences(start, start + HOUR, tz, start, at(tz, 2024, 4, 1, 0));
+        let starts: Vec<i64> = occurrences.iter().map(|(start, _)| *start).collect();
+        // 09:00 every day, an hour earlier in UTC once summer time starts on the 31st
+        assert_eq!(
+            starts,
+            vec![
+                Utc.with_ymd_and_hms(2024, 3, 29, 9, 0, 0)
+                    .unwrap()
+                    .timestamp(),
+                Utc.with_ymd_and_hms(2024, 3, 30, 9, 0, 0)
+                    .unwrap()
+                    .timestamp(),
+                Utc.with_ymd_and_hms(2024, 3, 31, 8, 0, 0)
+                    .unwrap()
+                    .timestamp(),
+            ]
+        );
+        assert!(occurrences.iter().all(|(start, end)| end - start == HOUR));
+    }
+
+    #[test]
+    fn clamps_to_the_end_of_shorter_months() {
+        let tz = Tz::UTC;
+        let start = at(tz, 2024, 1, 31, 9);
+        let monthly = rule("FREQ=MONTHLY;COUNT=4").occurrences(start, start, tz, start, i64::MAX);
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        assert_eq!(
+            dates(&monthly, tz),
+            vec![
+                date(2024, 1, 31),
+                date(2024, 2, 29),
+                date(2024, 3, 31),
+                date(2024, 4, 30)
+            ]
+        );
+
+        let leap_day = at(tz, 2024, 2, 29, 9);
+        let yearly = rule("FREQ=YEARLY;COUNT=5").occurrences(leap_day, leap_day, tz, 0, i64::MAX);
+        assert_eq!(
+            dates(&yearly, tz),
+            vec![
+                date(2024, 2, 29),
+                date(2025, 2, 28),
+                date(2026, 2, 28),
+                date(2027, 2, 28),
+                date(2028, 2, 29)
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_count_until_and_the_cap() {
+        let tz = Tz::UTC;
+        let start = at(tz, 2024, 1, 1, 9);
+        let count = rule("FREQ=WEEKLY;COUNT=3").occurrences(start, start + HOUR, tz, 0, i64::MAX);
+        assert_eq!(count.len(), 3);
+        // COUNT includes occurrences before the range
+        let later =
+            rule("FREQ=WEEKLY;COUNT=3").occurrences(start, start + HOUR, tz, start + DAY, i64::MAX);
+        assert_eq!(later.len(), 2);
+
+        // an occurrence starting on UNTIL is the last
+        let until = rule("FREQ=DAILY;UNTIL=20240105T090000Z").occurrences(
+            start,
+            start + HOUR,
+            tz,
+            0,
+            i64::MAX,
+        );
+        assert_eq!(until.len(), 5);
+        assert_eq!(until.last().unwrap().0, at(tz, 2024, 1, 5, 9));
+
+        let forever = rule("FREQ=DAILY").occurrences(start, start + HOUR, tz, 0, i64::MAX);
+        assert_eq!(forever.len(), MAX_OCCURRENCES);
+    }
+
+    #[test]
+    fn skips_ahead_to_a_distant_range() {
+        let tz = chrono_tz::America::New_York;
+        let start = at(tz, 2000, 1, 2, 9);
+        let range_start = at(tz, 2024, 7, 4, 0);
+        let every_other_day = rule("FREQ=DAILY;INTERVAL=2");
+        let occurrences = every_other_day.occurrences(
+            start,
+            start + 3 * HOUR,
+            tz,
+            range_start,
+            range_start + 7 * DAY,
+        );
+        // 2000-01-02 plus an even number of days, still at 09:00 local after 24 years of DST
+        let date = |d| NaiveDate::from_ymd_opt(2024, 7, d).unwrap();
+        assert_eq!(
+            dates(&occurrences, tz),
+            vec![date(4), date(6), date(8), date(10)]
+        );
+        assert_eq!(occurrences[0].0, at(tz, 2024, 7, 4, 9));
+
+        // an occurrence that started before the range but runs into it is kept
+        let long =
+            every_other_day.occurrences(start, start + 2 * DAY, tz, range_start, range_start);
+        assert_eq!(dates(&long, tz), vec![date(2)]);
+
+        // any range a query string can hold
+        let extreme = every_other_day.occurrences(start, start + HOUR, tz, i64::MIN, i64::MAX);
+        assert_eq!(extreme.len(), MAX_OCCURRENCES);
+        assert_eq!(extreme[0].0, start);
+        assert!(every_other_day
+            .occurrences(start, start + HOUR, tz, i64::MAX, i64::MAX)
+            .is_empty());
+    }
+
+    #[test]
+    fn gaps_leave_out_overlapping_and_nested_busy_time() {
+        // overlapping, then one inside another
+        let busy = [(10, 20), (15, 30), (40, 60), (45, 50), (90, 120)];
+        assert_eq!(gaps(0, 100, &busy), vec![(0, 10), (30, 40), (60, 90)]);
+        assert_eq!(gaps(12, 25, &busy), vec![]);
+        assert_eq!(gaps(0, 100, &[]), vec![(0, 100)]);
+    }
+}
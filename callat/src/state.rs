@@ -1,22 +1,30 @@
 use anyhow::{anyhow, Result};
-use chrono::{DateTime, Datelike, Duration, Utc};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
 use kinode_process_lib::{
-    get_state, set_state,
-    sqlite::{self, Sqlite},
-    Address, NodeId, ProcessId, Request,
+    get_state, println, sqlite, Address, Message, NodeId, ProcessId, Request,
 };
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, str::FromStr};
+use std::{cmp::Ordering, collections::HashMap, str::FromStr};
 use uuid::Uuid;
 
 use crate::{
-    geocity::{load_cities_from_file, GranularityProtocol},
-    RemoteRequest,
+    error::ApiError,
+    events::Event,
+    geocity::{
+        load_cities_from_file, load_countries_from_file, load_regions_from_file,
+        load_timezones_from_file, GranularityProtocol,
+    },
+    migrations::{self, Database},
+    notifications::{self, Notification},
+    photos::{self, Fetch, FriendPhotoKey, FriendPhotos, Photo, PhotoSize, PhotoStore},
+    recurrence::{self, Recurrence},
+    LocationError, LocationResponse, RemoteRequest, TIMEOUT,
 };
 
 const PROCESS_ID: &str = "callat:callat:template.os";
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Location {
     pub uuid: Uuid,
     pub start_date: i64, // Store as Unix timestamp?
@@ -26,51 +34,534 @@ pub struct Location {
     pub latitude: f64,
     pub longitude: f64,
     pub photos: Vec<String>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// shown instead of `description` to tiers that don't get the full text
+    #[serde(default)]
+    pub public_summary: String,
+    /// IANA zone at the location, e.g. "America/New_York", resolved from its coordinates
+    #[serde(default)]
+    pub timezone: String,
+    /// how precise a friend's location is, set by the sender, None for our own
+    #[serde(default)]
+    pub shared_as: Option<SharedGranularity>,
+    /// repeats `start_date..end_date`, expanded when locations are listed for a range
+    #[serde(default)]
+    pub recurrence: Option<Recurrence>,
+    /// a home base, only shown where the owner's timeline has nothing else
+    #[serde(default)]
+    pub is_home: bool,
+}
+
+/// The granularity a location was fuzzed to before it was shared with us.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SharedGranularity {
+    pub location: LocationGranularity,
+    pub time: TimeGranularity,
 }
 
+impl Location {
+    /// The location's zone, UTC if it was never resolved.
+    pub fn tz(&self) -> Tz {
+        self.timezone.parse().unwrap_or(Tz::UTC)
+    }
+
+    /// Start and end as wall-clock times in `tz`.
+    pub fn times_in(&self, tz: Tz) -> LocalTimes {
+        let render = |ts: i64| {
+            tz.timestamp_opt(ts, 0)
+                .single()
+                .map(|datetime| datetime.to_rfc3339())
+                .unwrap_or_default()
+        };
+        LocalTimes {
+            timezone: tz.name().to_string(),
+            start: render(self.start_date),
+            end: render(self.end_date),
+        }
+    }
+}
+
+/// A location's times rendered in one zone, RFC 3339 with the offset, e.g.
+/// "2024-07-04T09:00:00-04:00".
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum FriendType {
-    Best,         // full granularity: exact dates and times, exact locations
-    CloseFriend,  // time-granularity of "day", location-granularity of "city"
-    Acquaintance, // time-granularity of "week", location-granularity of "country"
+pub struct LocalTimes {
+    pub timezone: String,
+    pub start: String,
+    pub end: String,
+}
+
+/// What the API returns for a location: the location itself plus its times where
+/// it is (`local`) and where the viewer is (`viewer`, when they said).
+#[derive(Debug, Serialize)]
+pub struct LocationView {
+    #[serde(flatten)]
+    pub location: Location,
+    pub local: LocalTimes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub viewer: Option<LocalTimes>,
+}
+
+impl LocationView {
+    pub fn new(location: Location, viewer: Option<Tz>) -> Self {
+        Self {
+            local: location.times_in(location.tz()),
+            viewer: viewer.map(|tz| location.times_in(tz)),
+            location,
+        }
+    }
+}
+
+/// Per-location sharing policy, applied before a location is fuzzed for a friend.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Visibility {
+    /// never shared with anyone
+    #[serde(default)]
+    pub private: bool,
+    /// coarsest tier anyone sees this at, e.g. "CloseFriend" = "city-level for everyone"
+    #[serde(default)]
+    pub max_tier: Option<TierName>,
+    /// if non-empty, only these friends receive the location
+    #[serde(default)]
+    pub allow: Vec<NodeId>,
+    /// these friends never receive the location
+    #[serde(default)]
+    pub deny: Vec<NodeId>,
+}
+
+impl Visibility {
+    /// Whether this location may be shared with `node_id` at all.
+    pub fn allows(&self, node_id: &NodeId) -> bool {
+        !self.private
+            && !self.deny.contains(node_id)
+            && (self.allow.is_empty() || self.allow.contains(node_id))
+    }
+}
+
+pub type TierName = String;
+
+// the built-in tiers, every install starts with these.
+pub const BEST: &str = "Best";
+pub const CLOSE_FRIEND: &str = "CloseFriend";
+pub const ACQUAINTANCE: &str = "Acquaintance";
+/// Tier given to incoming friend requests and to friends whose tier went missing.
+pub const DEFAULT_TIER: &str = ACQUAINTANCE;
+
+/// How precisely a location's coordinates are shared, from most to least precise.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum LocationGranularity {
+    Exact,
+    Neighbourhood { radius_m: f64 },
+    City,
+    Region,
+    Country,
+    Hidden, // location isn't shared at all
+}
+
+impl LocationGranularity {
+    fn rank(&self) -> u8 {
+        match self {
+            LocationGranularity::Exact => 0,
+            LocationGranularity::Neighbourhood { .. } => 1,
+            LocationGranularity::City => 2,
+            LocationGranularity::Region => 3,
+            LocationGranularity::Country => 4,
+            LocationGranularity::Hidden => 5,
+        }
+    }
+
+    /// The less precise of the two.
+    pub fn coarser(self, other: Self) -> Self {
+        match (&self, &other) {
+            (
+                LocationGranularity::Neighbourhood { radius_m: a },
+                LocationGranularity::Neighbourhood { radius_m: b },
+            ) => {
+                if a >= b {
+                    self
+                } else {
+                    other
+                }
+            }
+            _ if self.rank() >= other.rank() => self,
+            _ => other,
+        }
+    }
+}
+
+/// How precisely a location's dates are shared, from most to least precise.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TimeGranularity {
+    Exact,
+    Day,
+    Week,
+    Month,
+    Hidden, // location isn't shared at all
+}
+
+impl TimeGranularity {
+    /// Widens `[start, end]` outwards to this granularity in zone `tz`, None if time is hidden
+    /// or a timestamp is out of range. Rounding happens in the location's zone, or "arriving
+    /// July 4" would come out as July 3 for anyone west of UTC.
+    pub fn fuzz_range(&self, start: i64, end: i64, tz: Tz) -> Option<(i64, i64)> {
+        let round = |ts: i64| match self {
+            TimeGranularity::Day => round_to_day(ts, tz),
+            TimeGranularity::Week => round_to_week(ts, tz),
+            _ => round_to_month(ts, tz),
+        };
+        match self {
+            TimeGranularity::Exact => Some((start, end)),
+            TimeGranularity::Hidden => None,
+            _ => Some((round(start)?, round_up(end, round)?)),
+        }
+    }
+}
+
+/// What a tier sees of a location's free-text description, from most to least revealing.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DescriptionPolicy {
+    Full,
+    PublicSummary, // the location's public_summary instead of its description
+    #[default]
+    Hidden,
+}
+
+/// Which of a location's photos a tier sees.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub enum PhotoPolicy {
+    All,
+    Limit {
+        max: usize,
+    }, // only the first `max` photos
+    #[default]
+    Hidden,
+}
+
+impl PhotoPolicy {
+    fn limit(&self) -> Option<usize> {
+        match self {
+            PhotoPolicy::All => None,
+            PhotoPolicy::Limit { max } => Some(*max),
+            PhotoPolicy::Hidden => Some(0),
+        }
+    }
+
+    /// The more restrictive of the two.
+    pub fn coarser(self, other: Self) -> Self {
+        match (self.limit(), other.limit()) {
+            (Some(a), Some(b)) if b < a => other,
+            (None, Some(_)) => other,
+            _ => self,
+        }
+    }
+
+    pub fn apply(&self, photos: &[String]) -> Vec<String> {
+        let max = self.limit().unwrap_or(photos.len());
+        photos.iter().take(max).cloned().collect()
+    }
+}
+
+/// A user-defined friend category, defined by what its members see.
+///
+/// A stored or posted tier that leaves a policy out gets the one its namesake in
+/// `default_tiers` is seeded with, or the least revealing for a tier of the user's own.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(from = "PartialTier")]
+pub struct Tier {
+    pub name: TierName,
+    pub location: LocationGranularity,
+    pub time: TimeGranularity,
+    pub description: DescriptionPolicy,
+    pub photos: PhotoPolicy,
+    /// the most detailed rendition members may fetch of the photos they see
+    pub photo_size: PhotoSize,
+}
+
+/// A tier as written, its policies optional.
+#[derive(Deserialize)]
+struct PartialTier {
+    name: TierName,
+    location: LocationGranularity,
+    time: TimeGranularity,
+    description: Option<DescriptionPolicy>,
+    photos: Option<PhotoPolicy>,
+    photo_size: Option<PhotoSize>,
+}
+
+impl From<PartialTier> for Tier {
+    fn from(tier: PartialTier) -> Self {
+        let seeded = default_tiers().remove(&tier.name);
+        Tier {
+            description: tier
+                .description
+                .or_else(|| seeded.as_ref().map(|seeded| seeded.description.clone()))
+                .unwrap_or_default(),
+            photos: tier
+                .photos
+                .or_else(|| seeded.as_ref().map(|seeded| seeded.photos.clone()))
+                .unwrap_or_default(),
+            photo_size: tier
+                .photo_size
+                .or_else(|| seeded.as_ref().map(|seeded| seeded.photo_size))
+                .unwrap_or(PhotoSize::Thumb),
+            name: tier.name,
+            location: tier.location,
+            time: tier.time,
+        }
+    }
+}
+
+impl Tier {
+    /// Combines two tiers, keeping the less precise setting of each.
+    pub fn capped(&self, cap: &Tier) -> Tier {
+        Tier {
+            name: self.name.clone(),
+            location: self.location.clone().coarser(cap.location.clone()),
+            time: self.time.clone().max(cap.time.clone()),
+            description: self.description.clone().max(cap.description.clone()),
+            photos: self.photos.clone().coarser(cap.photos.clone()),
+            photo_size: self.photo_size.max(cap.photo_size),
+        }
+    }
+}
+
+pub fn default_tiers() -> Tiers {
+    [
+        // full granularity: exact dates and times, exact locations, everything attached
+        Tier {
+            name: BEST.to_string(),
+            location: LocationGranularity::Exact,
+            time: TimeGranularity::Exact,
+            description: DescriptionPolicy::Full,
+            photos: PhotoPolicy::All,
+            photo_size: PhotoSize::Original,
+        },
+        // time-granularity of "day", location-granularity of "city",
+        // descriptions often hold the address so they only get the public summary,
+        // photos at web size
+        Tier {
+            name: CLOSE_FRIEND.to_string(),
+            location: LocationGranularity::City,
+            time: TimeGranularity::Day,
+            description: DescriptionPolicy::PublicSummary,
+            photos: PhotoPolicy::All,
+            photo_size: PhotoSize::Web,
+        },
+        // time-granularity of "week", location-granularity of "country", no photos
+        Tier {
+            name: ACQUAINTANCE.to_string(),
+            location: LocationGranularity::Country,
+            time: TimeGranularity::Week,
+            description: DescriptionPolicy::PublicSummary,
+            photos: PhotoPolicy::Hidden,
+            photo_size: PhotoSize::Thumb,
+        },
+    ]
+    .into_iter()
+    .map(|tier| (tier.name.clone(), tier))
+    .collect()
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Friend {
     pub node_id: NodeId,
-    pub friend_type: FriendType,
+    // name of the friend's Tier. still called friend_type so saved state from the
+    // old Best/CloseFriend/Acquaintance enum deserializes into the matching default tier.
+    pub friend_type: TierName,
     pub last_pinged: i64, // unix timestamp
 }
 
 pub type Friends = HashMap<NodeId, Friend>;
 pub type CustomLists = HashMap<String, Vec<NodeId>>; // not in active use yet.
 pub type PendingFriendRequests = Vec<(Friend, bool)>; // (node_id, is_local)
+pub type Tiers = HashMap<TierName, Tier>;
+pub type FuzzKey = [u8; 32]; // secret for GranularityProtocol's keyed hash, never leaves the node
+
+/// Rejects coordinates off the globe and trips that end before they start.
+pub fn validate_location(
+    latitude: f64,
+    longitude: f64,
+    start_date: i64,
+    end_date: i64,
+) -> Result<()> {
+    if !latitude.is_finite() || !(-90.0..=90.0).contains(&latitude) {
+        return Err(ApiError::Invalid(format!(
+            "invalid latitude: {} is outside -90..90",
+            latitude
+        ))
+        .into());
+    }
+    if !longitude.is_finite() || !(-180.0..=180.0).contains(&longitude) {
+        return Err(ApiError::Invalid(format!(
+            "invalid longitude: {} is outside -180..180",
+            longitude
+        ))
+        .into());
+    }
+    // beyond these no date can be computed, let alone shown
+    let representable = DateTime::<Utc>::MIN_UTC.timestamp()..=DateTime::<Utc>::MAX_UTC.timestamp();
+    if !representable.contains(&start_date) || !representable.contains(&end_date) {
+        return Err(ApiError::Invalid("invalid dates: out of range".into()).into());
+    }
+    if end_date < start_date {
+        return Err(
+            ApiError::Invalid("invalid dates: end_date is before start_date".into()).into(),
+        );
+    }
+    Ok(())
+}
+
+/// Rejects tiers without a name, and neighbourhoods no point can be jittered within.
+pub fn validate_tier(tier: &Tier) -> Result<()> {
+    if tier.name.is_empty() {
+        return Err(ApiError::Invalid("invalid name: empty".into()).into());
+    }
+    if let LocationGranularity::Neighbourhood { radius_m } = tier.location {
+        if !radius_m.is_finite() || radius_m <= 0.0 {
+            return Err(ApiError::Invalid(format!(
+                "invalid radius_m: {} is not a positive number of metres",
+                radius_m
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// 244 random bits from two v4 uuids, without pulling in a separate rng.
+fn new_fuzz_key() -> FuzzKey {
+    let mut key = [0u8; 32];
+    key[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+    key[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+    key
+}
 
 pub struct State {
     pub db: DB,
     pub friends: Friends,
     pub pending_friend_requests: PendingFriendRequests,
     pub custom_lists: CustomLists,
+    pub tiers: Tiers,
     pub geo_protocol: GranularityProtocol,
+    /// queued while a message is handled, pushed to the UI after it
+    pub events: Vec<Event>,
+    pub photos: PhotoStore,
+    pub friend_photos: FriendPhotos,
 }
 
 impl State {
     pub fn new(our: &Address) -> Result<Self> {
         let cities = load_cities_from_file(our).expect("Failed to load cities json");
+        let regions = load_regions_from_file(our).expect("Failed to load regions json");
+        let countries = load_countries_from_file(our).expect("Failed to load countries json");
+        let timezones = load_timezones_from_file(our).expect("Failed to load timezones json");
 
-        let saved_state: SavedState = if let Some(state_bytes) = get_state() {
-            serde_json::from_slice(&state_bytes)?
-        } else {
-            SavedState::default()
+        let db = DB::connect(our)?;
+        db.import_saved_state()?;
+        db.ensure_default_tiers()?;
+        let fuzz_key = match db.get_setting(FUZZ_KEY)? {
+            Some(key) => serde_json::from_str(&key)?,
+            None => {
+                // persist a fresh key right away, or friends would see new samples after a restart
+                let key = new_fuzz_key();
+                db.set_setting(FUZZ_KEY, &serde_json::to_string(&key)?, None)?;
+                key
+            }
         };
 
-        Ok(Self {
-            db: DB::connect(our)?,
-            friends: saved_state.friends,
-            pending_friend_requests: saved_state.pending_friend_requests,
-            custom_lists: saved_state.custom_lists,
-            geo_protocol: GranularityProtocol::new(cities),
-        })
+        let state = Self {
+            friends: db.get_friends()?,
+            pending_friend_requests: db.get_pending_friend_requests()?,
+            custom_lists: db.get_custom_lists()?,
+            tiers: db.get_tiers()?,
+            geo_protocol: GranularityProtocol::new(cities, regions, countries, timezones, fuzz_key),
+            events: Vec::new(),
+            photos: PhotoStore::open(our)?,
+            friend_photos: FriendPhotos::default(),
+            db,
+        };
+        state.backfill_timezones()?;
+        Ok(state)
+    }
+
+    /// A state over a fresh database with the default tiers and no friends, for tests.
+    #[cfg(test)]
+    pub fn in_memory(geo_protocol: GranularityProtocol) -> Self {
+        let db = DB::in_memory();
+        db.ensure_default_tiers().unwrap();
+        Self {
+            friends: Friends::new(),
+            pending_friend_requests: Vec::new(),
+            custom_lists: CustomLists::new(),
+            tiers: db.get_tiers().unwrap(),
+            geo_protocol,
+            events: Vec::new(),
+            photos: PhotoStore::unopened(),
+            friend_photos: FriendPhotos::default(),
+            db,
+        }
+    }
+
+    /// Creates one of our own locations and shares it with friends.
+    pub fn create_location(
+        &mut self,
+        our: &NodeId,
+        uuid: Uuid,
+        new_location: NewLocation,
+    ) -> Result<Location> {
+        let location = self.own_location(our, uuid, new_location)?;
+        println!("adding location: {:?}", location);
+        self.add_location(&location)?;
+        self.share_location(&location)?;
+        // the location is saved either way, a failed check only costs a notification
+        if let Err(e) = notifications::on_own_location(self, our, &location) {
+            println!("failed to check for overlaps: {:?}", e);
+        }
+        Ok(location)
+    }
+
+    /// Validates `new_location` as one of ours, resolving its zone when not given.
+    fn own_location(
+        &self,
+        our: &NodeId,
+        uuid: Uuid,
+        new_location: NewLocation,
+    ) -> Result<Location> {
+        validate_location(
+            new_location.latitude,
+            new_location.longitude,
+            new_location.start_date,
+            new_location.end_date,
+        )?;
+        let location = Location {
+            uuid,
+            start_date: new_location.start_date,
+            end_date: new_location.end_date,
+            description: new_location.description,
+            latitude: new_location.latitude,
+            longitude: new_location.longitude,
+            owner: our.clone(),
+            photos: new_location.photos,
+            visibility: new_location.visibility,
+            public_summary: new_location.public_summary,
+            timezone: String::new(),
+            shared_as: None,
+            recurrence: new_location.recurrence,
+            is_home: new_location.is_home,
+        };
+        let location = Location {
+            timezone: match new_location.timezone {
+                Some(timezone) => {
+                    timezone.parse::<Tz>().map_err(|_| {
+                        ApiError::Invalid(format!("invalid timezone: {}", timezone))
+                    })?;
+                    timezone
+                }
+                None => self.resolve_timezone(&location),
+            },
+            ..location
+        };
+        Ok(location)
     }
 
     pub fn add_location(&mut self, location: &Location) -> Result<()> {
@@ -80,9 +571,15 @@ impl State {
 
     pub fn share_location(&mut self, location: &Location) -> Result<()> {
         for friend in self.friends.values() {
-            let fuzzed_location = self
-                .geo_protocol
-                .fuzz_location(location, &friend.friend_type);
+            let Some(tier) = self.tier_for(location, friend) else {
+                continue;
+            };
+            let Some(fuzzed_location) =
+                self.geo_protocol
+                    .fuzz_location(location, &friend.node_id, &tier)
+            else {
+                continue;
+            };
             let req = RemoteRequest::Sync {
                 locations: vec![fuzzed_location],
             };
@@ -98,8 +595,379 @@ impl State {
         Ok(())
     }
 
-    pub fn update_location(&mut self, location: Location) -> Result<()> {
-        self.db.update_location(&location)
+    /// Filters `locations` by their visibility policy for `friend` and fuzzes the rest.
+    pub fn locations_for_friend(&self, locations: Vec<Location>, friend: &Friend) -> Vec<Location> {
+        locations
+            .into_iter()
+            .filter_map(|location| {
+                let tier = self.tier_for(&location, friend)?;
+                self.geo_protocol
+                    .fuzz_location(&location, &friend.node_id, &tier)
+            })
+            .collect()
+    }
+
+    /// The tiers `friend` sees the locations of ours listing photo `id` at, empty if
+    /// none they are sent lists it.
+    fn photo_tiers(&self, our: &NodeId, friend: &Friend, id: &Uuid) -> Result<Vec<Tier>> {
+        let id = id.to_string();
+        let locations = self.get_locations_by_owner(our)?;
+        Ok(locations
+            .iter()
+            .filter(|location| location.photos.contains(&id))
+            .filter_map(|location| {
+                let tier = self.tier_for(location, friend)?;
+                let shared = self
+                    .geo_protocol
+                    .fuzz_location(location, &friend.node_id, &tier)?;
+                shared.photos.contains(&id).then_some(tier)
+            })
+            .collect())
+    }
+
+    /// The most detailed size `friend` may fetch photo `id` at, None if no location we
+    /// share with them lists it.
+    pub fn photo_size_for(
+        &self,
+        our: &NodeId,
+        friend: &Friend,
+        id: &Uuid,
+    ) -> Result<Option<PhotoSize>> {
+        let tiers = self.photo_tiers(our, friend, id)?;
+        Ok(tiers.iter().map(|tier| tier.photo_size).min())
+    }
+
+    /// Photo `id` as `friend` may see it: when it was taken only for a tier with exact
+    /// times, where only for one with exact locations. None if not shared with them.
+    pub fn photo_info_for(
+        &self,
+        our: &NodeId,
+        friend: &Friend,
+        id: &Uuid,
+    ) -> Result<Option<Photo>> {
+        let tiers = self.photo_tiers(our, friend, id)?;
+        if tiers.is_empty() {
+            return Ok(None);
+        }
+        let Some(mut photo) = self.db.get_photo(id)? else {
+            return Ok(None);
+        };
+        if !tiers.iter().any(|tier| tier.time == TimeGranularity::Exact) {
+            photo.taken_at = None;
+        }
+        if !tiers
+            .iter()
+            .any(|tier| matches!(tier.location, LocationGranularity::Exact))
+        {
+            photo.latitude = None;
+            photo.longitude = None;
+        }
+        Ok(Some(photo))
+    }
+
+    /// Stores uploaded photos, reading camera clocks in the zone at a photo's GPS
+    /// position, or in `viewer` for a photo without one.
+    pub fn save_photos(&self, files: &[&[u8]], viewer: Tz) -> Result<Vec<Photo>> {
+        self.photos
+            .save_all(&self.db, files, |position| match position {
+                Some((latitude, longitude)) => self.geo_protocol.timezone_at(longitude, latitude),
+                None => viewer,
+            })
+    }
+
+    /// Photo `id` as `size` with its mime type, from our store or, for a photo on a
+    /// friend's location, from that friend, who sends at most the size our tier allows.
+    /// None while it's being fetched from them.
+    pub fn get_photo(&mut self, id: &Uuid, size: PhotoSize) -> Result<Option<(String, Vec<u8>)>> {
+        if self.db.get_photo(id)?.is_some() {
+            return self.photos.load(&self.db, id, size).map(Some);
+        }
+        let Some((body, bytes)) = self.ask_photo_owner(id, Some(size))? else {
+            return Ok(None);
+        };
+        match serde_json::from_slice::<LocationResponse>(&body)? {
+            LocationResponse::Ok(()) => {
+                // served from our origin, so it has to really be an image
+                let mime = photos::check_image(&bytes)?;
+                Ok(Some((mime.to_string(), bytes)))
+            }
+            LocationResponse::Err(_) => Err(ApiError::NotFound("photo not found".into()).into()),
+        }
+    }
+
+    /// Photo `id`'s details, a friend's photo as far as their tier for us tells. None while
+    /// it's being fetched from them.
+    pub fn get_photo_info(&mut self, id: &Uuid) -> Result<Option<Photo>> {
+        if let Some(photo) = self.db.get_photo(id)? {
+            return Ok(Some(photo));
+        }
+        let Some((body, _)) = self.ask_photo_owner(id, None)? else {
+            return Ok(None);
+        };
+        serde_json::from_slice::<Result<Photo, LocationError>>(&body)?
+            .map(Some)
+            .map_err(|_| ApiError::NotFound("photo not found".into()).into())
+    }
+
+    /// The answer of the friend whose location lists photo `id` about its rendition `size`,
+    /// or its details for None. Asks them if there's none yet, None until they answer.
+    fn ask_photo_owner(
+        &mut self,
+        id: &Uuid,
+        size: Option<PhotoSize>,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let key = (*id, size);
+        let now = Utc::now().timestamp();
+        match self.friend_photos.get(&key, now) {
+            Some(Fetch::Pending) => return Ok(None),
+            Some(Fetch::Answered { body, bytes }) => {
+                return Ok(Some((body.clone(), bytes.clone())))
+            }
+            Some(Fetch::Unanswered) => {
+                return Err(
+                    ApiError::NotFound("photo not found, its owner didn't answer".into()).into(),
+                )
+            }
+            None => {}
+        }
+        let owner = self
+            .db
+            .photo_owner(id)?
+            .ok_or_else(|| ApiError::NotFound("photo not found".into()))?;
+        let request = match size {
+            Some(size) => RemoteRequest::Photo { id: *id, size },
+            None => RemoteRequest::PhotoInfo { id: *id },
+        };
+        let address = Address::new(owner.clone(), ProcessId::from_str(PROCESS_ID).unwrap());
+        Request::to(address)
+            .body(serde_json::to_vec(&request)?)
+            .expects_response(TIMEOUT)
+            .context(serde_json::to_vec(&key)?)
+            .send()?;
+        self.friend_photos.insert(key, owner, Fetch::Pending, now);
+        Ok(None)
+    }
+
+    /// Keeps a friend's answer to `ask_photo_owner`, `context` being what it was sent with.
+    pub fn photo_answered(&mut self, context: &[u8], answer: &Message) -> Result<()> {
+        let key: FriendPhotoKey = serde_json::from_slice(context)?;
+        let fetch = Fetch::Answered {
+            body: answer.body().to_vec(),
+            bytes: answer.blob().map(|blob| blob.bytes).unwrap_or_default(),
+        };
+        self.friend_photos
+            .answer(&key, fetch, Utc::now().timestamp());
+        Ok(())
+    }
+
+    /// Notes that a friend didn't answer `ask_photo_owner`, so it isn't asked again right away.
+    pub fn photo_unanswered(&mut self, context: &[u8]) -> Result<()> {
+        let key: FriendPhotoKey = serde_json::from_slice(context)?;
+        self.friend_photos
+            .answer(&key, Fetch::Unanswered, Utc::now().timestamp());
+        Ok(())
+    }
+
+    /// The tier `friend` sees `location` at after its visibility policy, None if not shared.
+    pub fn tier_for(&self, location: &Location, friend: &Friend) -> Option<Tier> {
+        if !location.visibility.allows(&friend.node_id) {
+            return None;
+        }
+        let tier = self.tiers.get(&friend.friend_type)?;
+        match &location.visibility.max_tier {
+            // a cap naming a tier that's gone hides the location rather than lifting the cap
+            Some(cap) => Some(tier.capped(self.tiers.get(cap)?)),
+            None => Some(tier.clone()),
+        }
+    }
+
+    pub fn upsert_tier(&mut self, tier: Tier) -> Result<()> {
+        self.db.upsert_tier(&tier, None)?;
+        self.tiers.insert(tier.name.clone(), tier);
+        Ok(())
+    }
+
+    pub fn remove_tier(&mut self, name: &str) -> Result<()> {
+        if name == DEFAULT_TIER {
+            return Err(ApiError::Conflict("cannot remove the default tier".into()).into());
+        }
+        let in_use = self.friends.values().any(|f| f.friend_type == name)
+            || self
+                .pending_friend_requests
+                .iter()
+                .any(|(f, _)| f.friend_type == name);
+        if in_use {
+            return Err(
+                ApiError::Conflict(format!("tier {} is still assigned to friends", name)).into(),
+            );
+        }
+        if self.db.tier_caps_locations(name)? {
+            return Err(ApiError::Conflict(format!(
+                "tier {} is still the max_tier of locations",
+                name
+            ))
+            .into());
+        }
+        if !self.tiers.contains_key(name) {
+            return Err(ApiError::NotFound("tier not found".into()).into());
+        }
+        self.db.delete_tier(name, None)?;
+        self.tiers.remove(name);
+        Ok(())
+    }
+
+    /// Stores a location synced from its owner, telling the UI if it's new or changed.
+    /// False if its uuid is already taken by someone else's location, ours included.
+    pub fn store_friend_location(&mut self, location: Location) -> Result<bool> {
+        let event = match self.db.get_location(&location.uuid)? {
+            Some(existing) if existing.owner != location.owner => {
+                println!(
+                    "{} tried to overwrite a location owned by {}",
+                    location.owner, existing.owner
+                );
+                return Ok(false);
+            }
+            None => Some(Event::LocationAdded {
+                location: location.clone(),
+            }),
+            Some(existing) if existing != location => Some(Event::LocationUpdated {
+                location: location.clone(),
+            }),
+            Some(_) => None, // friends re-send everything on every ping
+        };
+        // changed trips keep their uuid
+        self.db.upsert_location(&location, None)?;
+        self.events.extend(event);
+        Ok(true)
+    }
+
+    /// Deletes one of our locations and tells friends to drop their copies.
+    pub fn delete_location(&mut self, our: &NodeId, uuid: &Uuid) -> Result<()> {
+        let location = match self.db.get_location(uuid)? {
+            Some(location) if &location.owner == our => location,
+            _ => return Err(ApiError::NotFound("location not found".into()).into()),
+        };
+        self.db.delete_location(uuid)?;
+        // the location is gone either way, a failed cleanup only leaves files behind
+        if let Err(e) = self.photos.delete_orphans(&self.db, &location.photos) {
+            println!("failed to delete photos: {:?}", e);
+        }
+        // only friends it was shared with know it's there, and one unreachable friend
+        // doesn't keep the rest from hearing
+        let body = serde_json::to_vec(&RemoteRequest::Delete { uuid: *uuid })?;
+        for friend in self.friends.values() {
+            if self.tier_for(&location, friend).is_none() {
+                continue;
+            }
+            let address = Address::new(
+                friend.node_id.clone(),
+                ProcessId::from_str(PROCESS_ID).unwrap(),
+            );
+            if let Err(e) = Request::to(address).body(body.clone()).send() {
+                println!(
+                    "failed to send delete of {} to {}: {:?}",
+                    uuid, friend.node_id, e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops a friend's location they deleted.
+    pub fn delete_friend_location(&mut self, sender: &NodeId, uuid: &Uuid) -> Result<()> {
+        match self.db.get_location(uuid)? {
+            Some(location) if &location.owner == sender => {
+                self.db.delete_location(uuid)?;
+                self.events.push(Event::LocationDeleted {
+                    uuid: *uuid,
+                    owner: location.owner,
+                });
+            }
+            Some(location) => println!(
+                "{} tried to delete a location owned by {}",
+                sender, location.owner
+            ),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Replaces one of our locations and brings friends' copies up to date: re-sent to
+    /// those who may still see it, deleted from those who no longer may.
+    pub fn update_location(
+        &mut self,
+        our: &NodeId,
+        uuid: Uuid,
+        new_location: NewLocation,
+    ) -> Result<Location> {
+        let previous = match self.db.get_location(&uuid)? {
+            Some(location) if &location.owner == our => location,
+            _ => return Err(ApiError::NotFound("location not found".into()).into()),
+        };
+        let location = self.own_location(our, uuid, new_location)?;
+        self.db.update_location(&location)?;
+        let dropped: Vec<String> = previous
+            .photos
+            .iter()
+            .filter(|photo| !location.photos.contains(photo))
+            .cloned()
+            .collect();
+        if let Err(e) = self.photos.delete_orphans(&self.db, &dropped) {
+            println!("failed to delete photos: {:?}", e);
+        }
+        for friend in self.friends.values() {
+            let req = match self.shared_with(&location, friend) {
+                Some(shared) => RemoteRequest::Sync {
+                    locations: vec![shared],
+                },
+                None if self.shared_with(&previous, friend).is_some() => {
+                    RemoteRequest::Delete { uuid }
+                }
+                None => continue,
+            };
+            let address = Address::new(
+                friend.node_id.clone(),
+                ProcessId::from_str(PROCESS_ID).unwrap(),
+            );
+            if let Err(e) = Request::to(address).body(serde_json::to_vec(&req)?).send() {
+                println!(
+                    "failed to send update of {} to {}: {:?}",
+                    uuid, friend.node_id, e
+                );
+            }
+        }
+        Ok(location)
+    }
+
+    /// `location` as `friend` receives it, None if they don't.
+    fn shared_with(&self, location: &Location, friend: &Friend) -> Option<Location> {
+        let tier = self.tier_for(location, friend)?;
+        self.geo_protocol
+            .fuzz_location(location, &friend.node_id, &tier)
+    }
+
+    pub fn resolve_timezone(&self, location: &Location) -> String {
+        self.geo_protocol
+            .timezone_at(location.longitude, location.latitude)
+            .name()
+            .to_string()
+    }
+
+    /// Resolves the zone of locations stored before they had one.
+    pub fn backfill_timezones(&self) -> Result<()> {
+        let missing = self.db.get_locations_without_timezone()?;
+        if missing.is_empty() {
+            return Ok(());
+        }
+        println!("resolving timezones for {} locations", missing.len());
+        let tx_id = self.db.inner.begin_tx()?;
+        for location in missing {
+            let timezone = self.resolve_timezone(&location);
+            self.db
+                .set_timezone(&location.uuid, &timezone, Some(tx_id))?;
+        }
+        self.db.inner.commit_tx(tx_id)?;
+        Ok(())
     }
 
     pub fn get_location(&self, uuid: &Uuid) -> Result<Option<Location>> {
@@ -110,17 +978,136 @@ impl State {
         self.db.get_all_locations()
     }
 
-    pub fn get_locations_in_range(&self, start: i64, end: i64) -> Result<Vec<Location>> {
-        self.db.get_locations_in_range(start, end)
+    /// Locations matching `filter`. Given a start, recurring locations are expanded into
+    /// their occurrences in the range and homes fill the gaps in their owner's timeline;
+    /// without one, both are listed as stored.
+    pub fn get_locations(&self, filter: &LocationFilter) -> Result<LocationPage> {
+        let Some(start) = filter.start else {
+            return self.db.get_locations(filter);
+        };
+        let end = filter
+            .end
+            .unwrap_or(start.saturating_add(EXPANSION_HORIZON_SECS));
+        let page = self.db.get_locations(filter)?;
+
+        let mut expanded = Vec::new();
+        let mut busy_by_owner: HashMap<NodeId, Vec<(i64, i64)>> = HashMap::new();
+        for series in self.db.get_series(filter, start, end)? {
+            let intervals = series_intervals(&series, start, end);
+            let intervals = if series.is_home {
+                if !busy_by_owner.contains_key(&series.owner) {
+                    let busy = self.busy_intervals(&series.owner, start, end)?;
+                    busy_by_owner.insert(series.owner.clone(), busy);
+                }
+                let busy = &busy_by_owner[&series.owner];
+                intervals
+                    .into_iter()
+                    .flat_map(|(from, to)| recurrence::gaps(from.max(start), to.min(end), busy))
+                    .collect()
+            } else {
+                intervals
+            };
+            expanded.extend(
+                intervals
+                    .into_iter()
+                    .map(|(start_date, end_date)| Location {
+                        start_date,
+                        end_date,
+                        ..series.clone()
+                    }),
+            );
+        }
+
+        // expansions page along with the one-off rows: after the requested cursor, and not
+        // past the end of a full page, whose cursor the next request resumes from
+        let order = filter.order;
+        let key = |location: &Location| (filter.sort.key(location), location.uuid);
+        let after = |location: &Location, cursor: &Cursor| {
+            order.cmp(key(location), (cursor.key, cursor.uuid)) == Ordering::Greater
+        };
+        expanded.retain(|location| {
+            filter
+                .cursor
+                .as_ref()
+                .map_or(true, |cursor| after(location, cursor))
+                && page
+                    .next_cursor
+                    .as_ref()
+                    .map_or(true, |cursor| !after(location, cursor))
+        });
+        let mut locations = page.locations;
+        locations.extend(expanded);
+        locations.sort_by(|a, b| order.cmp(key(a), key(b)));
+
+        let next_cursor = match filter.limit {
+            Some(limit) if locations.len() > limit as usize => {
+                locations.truncate(limit as usize);
+                locations.last().map(|last| Cursor {
+                    key: filter.sort.key(last),
+                    uuid: last.uuid,
+                })
+            }
+            _ => page.next_cursor,
+        };
+        Ok(LocationPage {
+            locations,
+            next_cursor,
+        })
+    }
+
+    /// When `owner` is somewhere other than home in `start..=end`, sorted by start.
+    fn busy_intervals(&self, owner: &NodeId, start: i64, end: i64) -> Result<Vec<(i64, i64)>> {
+        let filter = LocationFilter {
+            start: Some(start),
+            end: Some(end),
+            owner: Some(owner.clone()),
+            ..Default::default()
+        };
+        let mut busy: Vec<(i64, i64)> = self
+            .db
+            .get_locations(&filter)?
+            .locations
+            .iter()
+            .map(|location| (location.start_date, location.end_date))
+            .collect();
+        for series in self.db.get_series(&filter, start, end)? {
+            if !series.is_home {
+                busy.extend(series_intervals(&series, start, end));
+            }
+        }
+        busy.sort_unstable();
+        Ok(busy)
     }
 
     pub fn get_locations_by_owner(&self, owner: &NodeId) -> Result<Vec<Location>> {
         self.db.get_locations_by_owner(owner)
     }
 
-    pub fn ping_node(&mut self, node_id: NodeId) {
+    /// The secret in the calendar feed URL, created on first use. Only for authenticated
+    /// routes, the feed itself checks against `existing_calendar_token`.
+    pub fn calendar_token(&self) -> Result<String> {
+        match self.existing_calendar_token()? {
+            Some(token) => Ok(token),
+            None => self.rotate_calendar_token(),
+        }
+    }
+
+    /// The calendar token, None until the user asked for their feed URL.
+    pub fn existing_calendar_token(&self) -> Result<Option<String>> {
+        self.db.get_setting(CALENDAR_TOKEN)
+    }
+
+    /// Replaces the calendar token, cutting off every existing subscription.
+    pub fn rotate_calendar_token(&self) -> Result<String> {
+        let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        self.db.set_setting(CALENDAR_TOKEN, &token, None)?;
+        Ok(token)
+    }
+
+    pub fn ping_node(&mut self, node_id: NodeId) -> Result<()> {
         if let Some(friend) = self.friends.get_mut(&node_id) {
             friend.last_pinged = Utc::now().timestamp();
+            self.db.upsert_friend(friend, None)?;
         }
         Request::to(Address::new(
             node_id,
@@ -129,17 +1116,27 @@ impl State {
         .body(serde_json::to_vec(&RemoteRequest::Ping).unwrap())
         .send()
         .unwrap();
+        Ok(())
     }
 
-    pub fn add_friend(&mut self, node_id: NodeId, friend_type: FriendType) {
-        self.friends.insert(
-            node_id.clone(),
-            Friend {
-                node_id: node_id.clone(),
-                friend_type,
-                last_pinged: Utc::now().timestamp(),
-            },
-        );
+    /// Turns our pending request with `node_id` (`is_local` for one we sent) into a
+    /// friendship in one transaction, then tells them. Memory follows the database only
+    /// once the transaction is committed.
+    fn add_friend(&mut self, node_id: NodeId, friend_type: TierName, is_local: bool) -> Result<()> {
+        let friend = Friend {
+            node_id: node_id.clone(),
+            friend_type,
+            last_pinged: Utc::now().timestamp(),
+        };
+        let tx_id = self.db.inner.begin_tx()?;
+        self.db
+            .delete_pending_friend_request(&node_id, is_local, Some(tx_id))?;
+        self.db.upsert_friend(&friend, Some(tx_id))?;
+        self.db.inner.commit_tx(tx_id)?;
+
+        self.pending_friend_requests
+            .retain(|(pending, local)| !(pending.node_id == node_id && *local == is_local));
+        self.friends.insert(node_id.clone(), friend);
         Request::to(Address::new(
             node_id,
             ProcessId::from_str(PROCESS_ID).unwrap(),
@@ -147,19 +1144,31 @@ impl State {
         .body(serde_json::to_vec(&RemoteRequest::FriendResponse).unwrap())
         .send()
         .unwrap();
-
-        let _ = self.save();
+        Ok(())
     }
 
-    pub fn send_friend_request(&mut self, node_id: NodeId, friend_type: FriendType) {
-        self.pending_friend_requests.push((
-            Friend {
-                node_id: node_id.clone(),
-                friend_type,
-                last_pinged: Utc::now().timestamp(),
-            },
-            true, // is_local
-        ));
+    pub fn send_friend_request(&mut self, node_id: NodeId, friend_type: TierName) -> Result<()> {
+        if self.friends.contains_key(&node_id) {
+            return Err(ApiError::Conflict(format!("{} is already a friend", node_id)).into());
+        }
+        if self
+            .pending_friend_requests
+            .iter()
+            .any(|(friend, _)| friend.node_id == node_id)
+        {
+            return Err(ApiError::Conflict(format!(
+                "a request with {} is already pending",
+                node_id
+            ))
+            .into());
+        }
+        let friend = Friend {
+            node_id: node_id.clone(),
+            friend_type,
+            last_pinged: Utc::now().timestamp(),
+        };
+        self.db.upsert_pending_friend_request(&friend, true, None)?;
+        self.pending_friend_requests.push((friend, true)); // is_local
         println!("sending friend request to {}", node_id);
         Request::to(Address::new(
             node_id,
@@ -168,72 +1177,82 @@ impl State {
         .body(serde_json::to_vec(&RemoteRequest::FriendRequest).unwrap())
         .send()
         .unwrap();
-        let _ = self.save();
+        Ok(())
     }
 
-    pub fn add_pending_friend_request(&mut self, node_id: NodeId) {
-        self.pending_friend_requests.push((
-            Friend {
-                node_id,
-                friend_type: FriendType::Acquaintance, // default, user can choose other when confirming.
-                last_pinged: Utc::now().timestamp(),
-            },
-            false, // is_local
-        ));
-        let _ = self.save();
+    pub fn add_pending_friend_request(&mut self, node_id: NodeId) -> Result<()> {
+        let friend = Friend {
+            node_id,
+            friend_type: DEFAULT_TIER.to_string(), // default, user can choose other when confirming.
+            last_pinged: Utc::now().timestamp(),
+        };
+        self.db
+            .upsert_pending_friend_request(&friend, false, None)?;
+        self.pending_friend_requests.push((friend.clone(), false)); // is_local
+        self.events.push(Event::FriendRequest {
+            node_id: friend.node_id,
+        });
+        Ok(())
     }
 
-    pub fn accept_friend_request(
-        &mut self,
-        node_id: NodeId,
-        friend_type: FriendType,
-    ) -> Result<()> {
-        if let Some(index) = self
+    pub fn accept_friend_request(&mut self, node_id: NodeId, friend_type: TierName) -> Result<()> {
+        if let Some((_, is_local)) = self
             .pending_friend_requests
             .iter()
-            .position(|(friend, _)| friend.node_id == node_id)
+            .find(|(friend, _)| friend.node_id == node_id)
         {
-            let (friend, is_local) = self.pending_friend_requests.remove(index);
-            if !is_local {
-                self.add_friend(friend.node_id, friend_type);
-                Ok(())
-            } else {
-                Err(anyhow!("Cannot accept a local friend request"))
+            if *is_local {
+                return Err(
+                    ApiError::Conflict("cannot accept a request we sent ourselves".into()).into(),
+                );
             }
+            self.add_friend(node_id, friend_type, false)
         } else {
-            Err(anyhow!(
-                "No pending friend request found for the given NodeId"
-            ))
+            Err(ApiError::NotFound(format!("no pending friend request from {}", node_id)).into())
         }
     }
 
-    pub fn reject_friend_request(&mut self, node_id: NodeId) {
+    pub fn reject_friend_request(&mut self, node_id: NodeId) -> Result<()> {
         if let Some(index) = self
             .pending_friend_requests
             .iter()
-            .position(|(friend, _)| friend.node_id == node_id)
+            .position(|(friend, is_local)| friend.node_id == node_id && !is_local)
         {
+            self.db
+                .delete_pending_friend_request(&node_id, false, None)?;
             self.pending_friend_requests.remove(index);
-            let _ = self.save();
         }
+        Ok(())
+    }
+
+    pub fn cancel_friend_request(&mut self, node_id: &NodeId) -> Result<()> {
+        let tx_id = self.db.inner.begin_tx()?;
+        for is_local in [true, false] {
+            self.db
+                .delete_pending_friend_request(node_id, is_local, Some(tx_id))?;
+        }
+        self.db.inner.commit_tx(tx_id)?;
+        self.pending_friend_requests
+            .retain(|(friend, _)| &friend.node_id != node_id);
+        Ok(())
     }
 
     //hmmm...
-    pub fn handle_friend_request(&mut self, node_id: NodeId, friend_type: FriendType) {
+    pub fn handle_friend_request(&mut self, node_id: NodeId, friend_type: TierName) -> Result<()> {
         if self
             .pending_friend_requests
             .iter()
             .any(|(friend, _)| friend.node_id == node_id)
         {
             // If we already have a pending request from this node, accept it
-            self.accept_friend_request(node_id, friend_type).unwrap();
+            self.accept_friend_request(node_id, friend_type)
         } else {
             // Otherwise, add it as a received request
-            self.add_pending_friend_request(node_id);
+            self.add_pending_friend_request(node_id)
         }
     }
 
-    pub fn ping_friend(&mut self, node_id: NodeId) {
+    pub fn ping_friend(&mut self, node_id: NodeId) -> Result<()> {
         if let Some(friend) = self.friends.get_mut(&node_id) {
             Request::to(Address::new(
                 node_id,
@@ -243,82 +1262,386 @@ impl State {
             .send()
             .unwrap();
             friend.last_pinged = Utc::now().timestamp();
+            self.db.upsert_friend(friend, None)?;
         }
+        Ok(())
     }
 
-    pub fn handle_friend_response(&mut self, node_id: NodeId) {
-        if let Some(index) = self
+    pub fn handle_friend_response(&mut self, node_id: NodeId) -> Result<()> {
+        if let Some((friend, _)) = self
             .pending_friend_requests
             .iter()
-            .position(|(friend, is_local)| friend.node_id == node_id && *is_local)
+            .find(|(friend, is_local)| friend.node_id == node_id && *is_local)
         {
-            let (friend, _) = self.pending_friend_requests.remove(index);
-            self.add_friend(node_id, friend.friend_type);
+            self.add_friend(node_id.clone(), friend.friend_type.clone(), true)?;
+            self.events.push(Event::FriendAccepted { node_id });
         }
+        Ok(())
     }
-    pub fn remove_friend(&mut self, node_id: &NodeId) {
+
+    pub fn remove_friend(&mut self, node_id: &NodeId) -> Result<()> {
+        let tx_id = self.db.inner.begin_tx()?;
+        self.db.delete_friend(node_id, Some(tx_id))?;
+        self.db.remove_from_all_custom_lists(node_id, Some(tx_id))?;
+        self.db.inner.commit_tx(tx_id)?;
+
         self.friends.remove(node_id);
         for list in self.custom_lists.values_mut() {
             list.retain(|id| id != node_id);
         }
-        let _ = self.save();
+        Ok(())
     }
 
     pub fn get_friend(&self, node_id: &NodeId) -> Option<&Friend> {
         self.friends.get(node_id)
     }
 
-    pub fn add_to_custom_list(&mut self, list_name: String, node_id: NodeId) {
-        self.custom_lists
-            .entry(list_name)
-            .or_default()
-            .push(node_id);
-        let _ = self.save();
+    pub fn add_to_custom_list(&mut self, list_name: String, node_id: NodeId) -> Result<()> {
+        self.db.add_to_custom_list(&list_name, &node_id, None)?;
+        let list = self.custom_lists.entry(list_name).or_default();
+        if !list.contains(&node_id) {
+            list.push(node_id);
+        }
+        Ok(())
     }
 
-    pub fn remove_from_custom_list(&mut self, list_name: &str, node_id: &NodeId) {
+    pub fn remove_from_custom_list(&mut self, list_name: &str, node_id: &NodeId) -> Result<()> {
+        self.db.remove_from_custom_list(list_name, node_id, None)?;
         if let Some(list) = self.custom_lists.get_mut(list_name) {
             list.retain(|id| id != node_id);
         }
+        Ok(())
     }
 
     pub fn get_custom_list(&self, list_name: &str) -> Option<&Vec<NodeId>> {
         self.custom_lists.get(list_name)
     }
-
-    pub fn save(&self) -> Result<()> {
-        let saved_state = SavedState {
-            friends: self.friends.clone(),
-            pending_friend_requests: self.pending_friend_requests.clone(),
-            custom_lists: self.custom_lists.clone(),
-        };
-        let bytes = serde_json::to_vec(&saved_state)?;
-        set_state(&bytes);
-        Ok(())
-    }
 }
 
-// helper struct to not save DB and in-memory structs.
+// friends, pending requests, custom lists, tiers and the fuzz key used to live in
+// process state as this JSON blob, kept only for DB::import_saved_state.
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct SavedState {
     pub friends: Friends,
     pub pending_friend_requests: PendingFriendRequests,
     pub custom_lists: CustomLists,
+    #[serde(default)]
+    pub tiers: Tiers,
+    #[serde(default)]
+    pub fuzz_key: Option<FuzzKey>,
 }
 
+// settings keys
+pub const FUZZ_KEY: &str = "fuzz_key";
+pub const SAVED_STATE_IMPORTED: &str = "saved_state_imported";
+pub const CALENDAR_TOKEN: &str = "calendar_token";
+/// process on this node that overlap notifications are forwarded to, e.g. "notify:notify:sys"
+pub const NOTIFY_PROCESS: &str = "notify_process";
+
 pub struct DB {
-    pub inner: Sqlite,
+    pub inner: Box<dyn Database>,
 }
 
-impl DB {
-    pub fn connect(our: &Address) -> Result<Self> {
-        let inner = sqlite::open(our.package_id(), "nomad_social.sqlite", Some(10))?;
-        inner.write(CREATE_LOCATIONS_TABLE.to_string(), vec![], None)?;
-        Ok(Self { inner })
+impl DB {
+    pub fn connect(our: &Address) -> Result<Self> {
+        let inner = sqlite::open(our.package_id(), "nomad_social.sqlite", Some(10))?;
+        migrations::run(&inner)?;
+        Ok(Self {
+            inner: Box::new(inner),
+        })
+    }
+
+    /// A fresh database at the latest schema, for tests.
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        let inner = crate::native_db::NativeDb::new();
+        migrations::run(&inner).unwrap();
+        Self {
+            inner: Box::new(inner),
+        }
+    }
+
+    /// One-time import of the SavedState blob older versions kept in process state.
+    /// A blob that doesn't parse is logged and left in place rather than crashing
+    /// the process, so a later version can still pick it up.
+    pub fn import_saved_state(&self) -> Result<()> {
+        if self.get_setting(SAVED_STATE_IMPORTED)?.is_some() {
+            return Ok(());
+        }
+        let saved_state = match get_state() {
+            None => SavedState::default(),
+            Some(bytes) => match serde_json::from_slice::<SavedState>(&bytes) {
+                Ok(saved_state) => saved_state,
+                Err(e) => {
+                    println!("skipping import of unreadable saved state: {:?}", e);
+                    return Ok(());
+                }
+            },
+        };
+
+        let tx_id = self.inner.begin_tx()?;
+        for friend in saved_state.friends.values() {
+            self.upsert_friend(friend, Some(tx_id))?;
+        }
+        for (friend, is_local) in &saved_state.pending_friend_requests {
+            self.upsert_pending_friend_request(friend, *is_local, Some(tx_id))?;
+        }
+        for (list_name, node_ids) in &saved_state.custom_lists {
+            for node_id in node_ids {
+                self.add_to_custom_list(list_name, node_id, Some(tx_id))?;
+            }
+        }
+        for tier in saved_state.tiers.values() {
+            self.upsert_tier(tier, Some(tx_id))?;
+        }
+        if let Some(fuzz_key) = saved_state.fuzz_key {
+            self.set_setting(FUZZ_KEY, &serde_json::to_string(&fuzz_key)?, Some(tx_id))?;
+        }
+        self.set_setting(SAVED_STATE_IMPORTED, "1", Some(tx_id))?;
+        self.inner.commit_tx(tx_id)?;
+        Ok(())
+    }
+
+    /// Seeds the default tiers on first run, and moves friends whose tier no longer
+    /// exists (e.g. from the old fixed FriendType enum) to the default tier.
+    pub fn ensure_default_tiers(&self) -> Result<()> {
+        let tiers = self.get_tiers()?;
+        let defaults = default_tiers();
+
+        let tx_id = self.inner.begin_tx()?;
+        if tiers.is_empty() {
+            for tier in defaults.values() {
+                self.upsert_tier(tier, Some(tx_id))?;
+            }
+        } else if !tiers.contains_key(DEFAULT_TIER) {
+            self.upsert_tier(&defaults[DEFAULT_TIER], Some(tx_id))?;
+        }
+        for table in ["friends", "pending_friend_requests"] {
+            let query = format!(
+                "UPDATE {table} SET friend_type = ? WHERE friend_type NOT IN (SELECT name FROM tiers)"
+            );
+            self.inner
+                .write(query, vec![DEFAULT_TIER.into()], Some(tx_id))?;
+        }
+        self.inner.commit_tx(tx_id)?;
+        Ok(())
+    }
+
+    pub fn get_friends(&self) -> Result<Friends> {
+        let query = "SELECT * FROM friends";
+        let results = self.inner.read(query.to_string(), vec![])?;
+        results
+            .into_iter()
+            .map(|row| {
+                let friend = self.row_to_friend(&row)?;
+                Ok((friend.node_id.clone(), friend))
+            })
+            .collect()
+    }
+
+    pub fn upsert_friend(&self, friend: &Friend, tx_id: Option<u64>) -> Result<()> {
+        let query =
+            "INSERT OR REPLACE INTO friends (node_id, friend_type, last_pinged) VALUES (?, ?, ?)";
+        let params = vec![
+            friend.node_id.clone().into(),
+            friend.friend_type.clone().into(),
+            friend.last_pinged.into(),
+        ];
+        self.inner.write(query.to_string(), params, tx_id)?;
+        Ok(())
+    }
+
+    pub fn delete_friend(&self, node_id: &NodeId, tx_id: Option<u64>) -> Result<()> {
+        let query = "DELETE FROM friends WHERE node_id = ?";
+        self.inner
+            .write(query.to_string(), vec![node_id.clone().into()], tx_id)?;
+        Ok(())
+    }
+
+    pub fn get_pending_friend_requests(&self) -> Result<PendingFriendRequests> {
+        let query = "SELECT * FROM pending_friend_requests";
+        let results = self.inner.read(query.to_string(), vec![])?;
+        results
+            .into_iter()
+            .map(|row| {
+                let is_local = row["is_local"]
+                    .as_i64()
+                    .ok_or_else(|| anyhow!("Invalid is_local"))?
+                    != 0;
+                Ok((self.row_to_friend(&row)?, is_local))
+            })
+            .collect()
+    }
+
+    pub fn upsert_pending_friend_request(
+        &self,
+        friend: &Friend,
+        is_local: bool,
+        tx_id: Option<u64>,
+    ) -> Result<()> {
+        let query = "INSERT OR REPLACE INTO pending_friend_requests (node_id, is_local, friend_type, last_pinged) VALUES (?, ?, ?, ?)";
+        let params = vec![
+            friend.node_id.clone().into(),
+            (is_local as i64).into(),
+            friend.friend_type.clone().into(),
+            friend.last_pinged.into(),
+        ];
+        self.inner.write(query.to_string(), params, tx_id)?;
+        Ok(())
+    }
+
+    pub fn delete_pending_friend_request(
+        &self,
+        node_id: &NodeId,
+        is_local: bool,
+        tx_id: Option<u64>,
+    ) -> Result<()> {
+        let query = "DELETE FROM pending_friend_requests WHERE node_id = ? AND is_local = ?";
+        let params = vec![node_id.clone().into(), (is_local as i64).into()];
+        self.inner.write(query.to_string(), params, tx_id)?;
+        Ok(())
+    }
+
+    pub fn get_custom_lists(&self) -> Result<CustomLists> {
+        let query = "SELECT * FROM custom_lists";
+        let results = self.inner.read(query.to_string(), vec![])?;
+        let mut custom_lists = CustomLists::new();
+        for row in results {
+            let list_name = row["list_name"]
+                .as_str()
+                .ok_or_else(|| anyhow!("Invalid list_name"))?;
+            let node_id = row["node_id"]
+                .as_str()
+                .ok_or_else(|| anyhow!("Invalid node_id"))?;
+            custom_lists
+                .entry(list_name.to_string())
+                .or_default()
+                .push(node_id.to_string());
+        }
+        Ok(custom_lists)
+    }
+
+    pub fn add_to_custom_list(
+        &self,
+        list_name: &str,
+        node_id: &NodeId,
+        tx_id: Option<u64>,
+    ) -> Result<()> {
+        let query = "INSERT OR IGNORE INTO custom_lists (list_name, node_id) VALUES (?, ?)";
+        let params = vec![list_name.into(), node_id.clone().into()];
+        self.inner.write(query.to_string(), params, tx_id)?;
+        Ok(())
+    }
+
+    pub fn remove_from_custom_list(
+        &self,
+        list_name: &str,
+        node_id: &NodeId,
+        tx_id: Option<u64>,
+    ) -> Result<()> {
+        let query = "DELETE FROM custom_lists WHERE list_name = ? AND node_id = ?";
+        let params = vec![list_name.into(), node_id.clone().into()];
+        self.inner.write(query.to_string(), params, tx_id)?;
+        Ok(())
+    }
+
+    pub fn remove_from_all_custom_lists(&self, node_id: &NodeId, tx_id: Option<u64>) -> Result<()> {
+        let query = "DELETE FROM custom_lists WHERE node_id = ?";
+        self.inner
+            .write(query.to_string(), vec![node_id.clone().into()], tx_id)?;
+        Ok(())
+    }
+
+    pub fn get_tiers(&self) -> Result<Tiers> {
+        let query = "SELECT * FROM tiers";
+        let results = self.inner.read(query.to_string(), vec![])?;
+        results
+            .into_iter()
+            .map(|row| {
+                let tier: Tier = serde_json::from_str(
+                    row["definition"]
+                        .as_str()
+                        .ok_or_else(|| anyhow!("Invalid tier definition"))?,
+                )?;
+                Ok((tier.name.clone(), tier))
+            })
+            .collect()
+    }
+
+    pub fn upsert_tier(&self, tier: &Tier, tx_id: Option<u64>) -> Result<()> {
+        let query = "INSERT OR REPLACE INTO tiers (name, definition) VALUES (?, ?)";
+        let params = vec![
+            tier.name.clone().into(),
+            serde_json::to_string(tier)?.into(),
+        ];
+        self.inner.write(query.to_string(), params, tx_id)?;
+        Ok(())
+    }
+
+    pub fn delete_tier(&self, name: &str, tx_id: Option<u64>) -> Result<()> {
+        let query = "DELETE FROM tiers WHERE name = ?";
+        self.inner
+            .write(query.to_string(), vec![name.into()], tx_id)?;
+        Ok(())
+    }
+
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        let query = "SELECT value FROM settings WHERE key = ?";
+        let results = self.inner.read(query.to_string(), vec![key.into()])?;
+        Ok(results
+            .first()
+            .and_then(|row| row["value"].as_str())
+            .map(|value| value.to_string()))
+    }
+
+    pub fn get_settings(&self) -> Result<HashMap<String, String>> {
+        let query = "SELECT * FROM settings";
+        let results = self.inner.read(query.to_string(), vec![])?;
+        results
+            .into_iter()
+            .map(|row| {
+                let key = row["key"].as_str().ok_or_else(|| anyhow!("Invalid key"))?;
+                let value = row["value"]
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Invalid value"))?;
+                Ok((key.to_string(), value.to_string()))
+            })
+            .collect()
+    }
+
+    pub fn set_setting(&self, key: &str, value: &str, tx_id: Option<u64>) -> Result<()> {
+        let query = "INSERT OR REPLACE INTO settings (key, value) VALUES (?, ?)";
+        let params = vec![key.into(), value.into()];
+        self.inner.write(query.to_string(), params, tx_id)?;
+        Ok(())
+    }
+
+    pub fn delete_setting(&self, key: &str, tx_id: Option<u64>) -> Result<()> {
+        let query = "DELETE FROM settings WHERE key = ?";
+        self.inner
+            .write(query.to_string(), vec![key.into()], tx_id)?;
+        Ok(())
+    }
+
+    /// Deletes a location and the notifications about it.
+    pub fn delete_location(&self, uuid: &Uuid) -> Result<()> {
+        let tx_id = self.inner.begin_tx()?;
+        self.inner.write(
+            "DELETE FROM locations WHERE uuid = ?".to_string(),
+            vec![uuid.to_string().into()],
+            Some(tx_id),
+        )?;
+        self.inner.write(
+            "DELETE FROM notifications WHERE our_location = ? OR friend_location = ?".to_string(),
+            vec![uuid.to_string().into(), uuid.to_string().into()],
+            Some(tx_id),
+        )?;
+        self.inner.commit_tx(tx_id)?;
+        Ok(())
     }
 
     pub fn insert_location(&self, location: &Location) -> Result<()> {
-        let query = "INSERT INTO locations (uuid, start_date, end_date, owner, description, latitude, longitude, photos) VALUES (?, ?, ?, ?, ?, ?, ?, ?)";
+        let query = "INSERT INTO locations (uuid, start_date, end_date, owner, description, latitude, longitude, photos, visibility, public_summary, timezone, shared_as, recurrence, is_home) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
         let params = vec![
             location.uuid.to_string().into(),
             location.start_date.into(),
@@ -328,13 +1651,46 @@ impl DB {
             location.latitude.into(),
             location.longitude.into(),
             serde_json::to_string(&location.photos)?.into(),
+            serde_json::to_string(&location.visibility)?.into(),
+            location.public_summary.clone().into(),
+            location.timezone.clone().into(),
+            serde_json::to_string(&location.shared_as)?.into(),
+            location.recurrence.as_ref().map(|r| r.to_string()).into(),
+            (location.is_home as i64).into(),
         ];
         self.inner.write(query.to_string(), params, None)?;
         Ok(())
     }
 
+    /// Inserts a location or replaces the one with the same uuid.
+    pub fn upsert_location(&self, location: &Location, tx_id: Option<u64>) -> Result<()> {
+        // ON CONFLICT rather than INSERT OR REPLACE, which deletes without firing the FTS triggers.
+        // only the owner's own copy is replaced, a uuid never changes hands
+        let query = "INSERT INTO locations (uuid, start_date, end_date, owner, description, latitude, longitude, photos, visibility, public_summary, timezone, shared_as, recurrence, is_home) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (uuid) DO UPDATE SET start_date = excluded.start_date, end_date = excluded.end_date, description = excluded.description, latitude = excluded.latitude, longitude = excluded.longitude, photos = excluded.photos, visibility = excluded.visibility, public_summary = excluded.public_summary, timezone = excluded.timezone, shared_as = excluded.shared_as, recurrence = excluded.recurrence, is_home = excluded.is_home
+            WHERE locations.owner = excluded.owner";
+        let params = vec![
+            location.uuid.to_string().into(),
+            location.start_date.into(),
+            location.end_date.into(),
+            location.owner.to_string().into(),
+            location.description.clone().into(),
+            location.latitude.into(),
+            location.longitude.into(),
+            serde_json::to_string(&location.photos)?.into(),
+            serde_json::to_string(&location.visibility)?.into(),
+            location.public_summary.clone().into(),
+            location.timezone.clone().into(),
+            serde_json::to_string(&location.shared_as)?.into(),
+            location.recurrence.as_ref().map(|r| r.to_string()).into(),
+            (location.is_home as i64).into(),
+        ];
+        self.inner.write(query.to_string(), params, tx_id)?;
+        Ok(())
+    }
+
     pub fn update_location(&self, location: &Location) -> Result<()> {
-        let query = "UPDATE locations SET start_date = ?, end_date = ?, description = ?, latitude = ?, longitude = ?, photos = ? WHERE uuid = ?";
+        let query = "UPDATE locations SET start_date = ?, end_date = ?, description = ?, latitude = ?, longitude = ?, photos = ?, visibility = ?, public_summary = ?, timezone = ?, shared_as = ?, recurrence = ?, is_home = ? WHERE uuid = ?";
         let params = vec![
             location.start_date.into(),
             location.end_date.into(),
@@ -342,6 +1698,12 @@ impl DB {
             location.latitude.into(),
             location.longitude.into(),
             serde_json::to_string(&location.photos)?.into(),
+            serde_json::to_string(&location.visibility)?.into(),
+            location.public_summary.clone().into(),
+            location.timezone.clone().into(),
+            serde_json::to_string(&location.shared_as)?.into(),
+            location.recurrence.as_ref().map(|r| r.to_string()).into(),
+            (location.is_home as i64).into(),
             location.uuid.to_string().into(),
         ];
         self.inner.write(query.to_string(), params, None)?;
@@ -368,17 +1730,191 @@ impl DB {
         }
     }
 
-    pub fn get_locations_in_range(&self, start: i64, end: i64) -> Result<Vec<Location>> {
-        let query =
-            "SELECT * FROM locations WHERE start_date <= ? AND (end_date >= ? OR end_date IS NULL)";
-        let params = vec![end.into(), start.into()];
-        let results = self.inner.read(query.to_string(), params)?;
+    pub fn get_locations(&self, filter: &LocationFilter) -> Result<LocationPage> {
+        let mut clauses: Vec<String> = Vec::new();
+        let mut params: Vec<serde_json::Value> = Vec::new();
+
+        match (filter.start, filter.end) {
+            (Some(start), end) => {
+                // `end_date >= start` alone can't use an index well: it matches everything from
+                // years back that is still open. No location is longer than the longest one, so
+                // overlapping rows also start within that duration of `start`, which bounds the
+                // start_date index scan to the window plus that margin.
+                let end = end.unwrap_or(i64::MAX);
+                let earliest_start = start.saturating_sub(self.max_duration()?);
+                // series and homes don't fit that bound, State::get_locations expands them
+                clauses.push("recurrence IS NULL AND is_home = 0".into());
+                clauses.push(
+                    "((start_date BETWEEN ? AND ? AND end_date >= ?) OR (end_date IS NULL AND start_date <= ?))"
+                        .into(),
+                );
+                params.extend([earliest_start.into(), end.into(), start.into(), end.into()]);
+            }
+            (None, Some(end)) => {
+                clauses.push("start_date <= ?".into());
+                params.push(end.into());
+            }
+            (None, None) => {}
+        }
+        self.push_filter_clauses(filter, &mut clauses, &mut params)?;
+
+        let column = filter.sort.column();
+        let descending = filter.order == SortOrder::Desc;
+        if let Some(cursor) = &filter.cursor {
+            // keyset pagination: strictly after the last row of the previous page
+            let cmp = if descending { "<" } else { ">" };
+            clauses.push(format!(
+                "({column} {cmp} ? OR ({column} = ? AND uuid {cmp} ?))"
+            ));
+            params.extend([
+                cursor.key.into(),
+                cursor.key.into(),
+                cursor.uuid.to_string().into(),
+            ]);
+        }
+
+        let mut query = "SELECT * FROM locations".to_string();
+        if !clauses.is_empty() {
+            query = format!("{query} WHERE {}", clauses.join(" AND "));
+        }
+        let direction = if descending { "DESC" } else { "ASC" };
+        query = format!("{query} ORDER BY {column} {direction}, uuid {direction}");
+        if let Some(limit) = filter.limit {
+            query = format!("{query} LIMIT ?");
+            params.push(limit.into());
+        }
+
+        let results = self.inner.read(query, params)?;
+        let locations = results
+            .into_iter()
+            .map(|row| self.row_to_location(&row))
+            .collect::<Result<Vec<_>>>()?;
+
+        // taken before the radius check, which may drop rows from a full page
+        let next_cursor = match (filter.limit, locations.last()) {
+            (Some(limit), Some(last)) if locations.len() as u32 == limit => Some(Cursor {
+                key: filter.sort.key(last),
+                uuid: last.uuid,
+            }),
+            _ => None,
+        };
+        let locations = match &filter.near {
+            Some(circle) => locations
+                .into_iter()
+                .filter(|location| circle.contains(location.longitude, location.latitude))
+                .collect(),
+            None => locations,
+        };
+        Ok(LocationPage {
+            locations,
+            next_cursor,
+        })
+    }
+
+    /// WHERE clauses for everything in `filter` but its times and cursor.
+    fn push_filter_clauses(
+        &self,
+        filter: &LocationFilter,
+        clauses: &mut Vec<String>,
+        params: &mut Vec<serde_json::Value>,
+    ) -> Result<()> {
+        // a radius is prefiltered by its bounding box, then checked exactly by the caller
+        let boxes = filter
+            .bbox
+            .iter()
+            .copied()
+            .chain(filter.near.as_ref().map(Circle::bounding_box));
+        for bbox in boxes {
+            clauses.push("latitude BETWEEN ? AND ?".into());
+            params.extend([bbox.south.into(), bbox.north.into()]);
+            if bbox.crosses_antimeridian() {
+                clauses.push("(longitude >= ? OR longitude <= ?)".into());
+            } else {
+                clauses.push("longitude BETWEEN ? AND ?".into());
+            }
+            params.extend([bbox.west.into(), bbox.east.into()]);
+        }
+        if let Some(owner) = &filter.owner {
+            clauses.push("owner = ?".into());
+            params.push(owner.clone().into());
+        }
+        if let Some(tier) = &filter.tier {
+            clauses.push("owner IN (SELECT node_id FROM friends WHERE friend_type = ?)".into());
+            params.push(tier.clone().into());
+        }
+        if let Some(list) = &filter.list {
+            clauses.push("owner IN (SELECT node_id FROM custom_lists WHERE list_name = ?)".into());
+            params.push(list.clone().into());
+        }
+        if let Some(text) = &filter.text {
+            clauses.push(
+                "uuid IN (SELECT uuid FROM locations_fts WHERE locations_fts MATCH ?)".into(),
+            );
+            params.push(fts_query(text)?.into());
+        }
+        Ok(())
+    }
+
+    /// Recurring and home locations that may show up in `start..=end`, matching the
+    /// rest of `filter`, for State::get_locations to expand.
+    pub fn get_series(
+        &self,
+        filter: &LocationFilter,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<Location>> {
+        let mut clauses: Vec<String> = vec![
+            "(recurrence IS NOT NULL OR is_home = 1)".into(),
+            "start_date <= ?".into(),
+            "(recurrence IS NOT NULL OR end_date >= ?)".into(),
+        ];
+        let mut params: Vec<serde_json::Value> = vec![end.into(), start.into()];
+        self.push_filter_clauses(filter, &mut clauses, &mut params)?;
+
+        let query = format!("SELECT * FROM locations WHERE {}", clauses.join(" AND "));
+        let results = self.inner.read(query, params)?;
+        let locations = results
+            .into_iter()
+            .map(|row| self.row_to_location(&row))
+            .collect::<Result<Vec<Location>>>()?;
+        Ok(locations
+            .into_iter()
+            .filter(|location| match &filter.near {
+                Some(circle) => circle.contains(location.longitude, location.latitude),
+                None => true,
+            })
+            .collect())
+    }
+
+    pub fn get_locations_without_timezone(&self) -> Result<Vec<Location>> {
+        let query = "SELECT * FROM locations WHERE timezone IS NULL OR timezone = ''";
+        let results = self.inner.read(query.to_string(), vec![])?;
         results
             .into_iter()
             .map(|row| self.row_to_location(&row))
             .collect()
     }
 
+    pub fn set_timezone(&self, uuid: &Uuid, timezone: &str, tx_id: Option<u64>) -> Result<()> {
+        let query = "UPDATE locations SET timezone = ? WHERE uuid = ?";
+        let params = vec![timezone.into(), uuid.to_string().into()];
+        self.inner.write(query.to_string(), params, tx_id)?;
+        Ok(())
+    }
+
+    /// The longest `end_date - start_date` of any one-off location, a lookup on
+    /// idx_locations_duration_one_off. Series and homes are matched separately.
+    fn max_duration(&self) -> Result<i64> {
+        let query = "SELECT MAX(end_date - start_date) AS duration FROM locations WHERE recurrence IS NULL AND is_home = 0";
+        let results = self.inner.read(query.to_string(), vec![])?;
+        Ok(results
+            .first()
+            .and_then(|row| row.get("duration"))
+            .and_then(|duration| duration.as_i64())
+            .unwrap_or(0)
+            .max(0))
+    }
+
     pub fn get_locations_by_owner(&self, owner: &NodeId) -> Result<Vec<Location>> {
         let query = "SELECT * FROM locations WHERE owner = ?";
         let params = vec![owner.to_string().into()];
@@ -389,6 +1925,194 @@ impl DB {
             .collect()
     }
 
+    pub fn insert_notification(&self, notification: &Notification) -> Result<()> {
+        let query = "INSERT INTO notifications (created_at, friend, our_location, friend_location, match_kind, overlap_start, overlap_end, message, read) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (our_location, friend_location, overlap_start) DO NOTHING";
+        let params = vec![
+            notification.created_at.into(),
+            notification.friend.clone().into(),
+            notification.our_location.to_string().into(),
+            notification.friend_location.to_string().into(),
+            serde_json::to_string(&notification.match_kind)?.into(),
+            notification.overlap_start.into(),
+            notification.overlap_end.into(),
+            notification.message.clone().into(),
+            (notification.read as i64).into(),
+        ];
+        self.inner.write(query.to_string(), params, None)?;
+        Ok(())
+    }
+
+    pub fn notification_exists(
+        &self,
+        our_location: &Uuid,
+        friend_location: &Uuid,
+        overlap_start: i64,
+    ) -> Result<bool> {
+        let query = "SELECT id FROM notifications WHERE our_location = ? AND friend_location = ? AND overlap_start = ?";
+        let params = vec![
+            our_location.to_string().into(),
+            friend_location.to_string().into(),
+            overlap_start.into(),
+        ];
+        Ok(!self.inner.read(query.to_string(), params)?.is_empty())
+    }
+
+    /// Newest first.
+    pub fn get_notifications(&self, unread_only: bool, limit: u32) -> Result<Vec<Notification>> {
+        let query = if unread_only {
+            "SELECT * FROM notifications WHERE read = 0 ORDER BY created_at DESC, id DESC LIMIT ?"
+        } else {
+            "SELECT * FROM notifications ORDER BY created_at DESC, id DESC LIMIT ?"
+        };
+        let results = self.inner.read(query.to_string(), vec![limit.into()])?;
+        results
+            .into_iter()
+            .map(|row| self.row_to_notification(&row))
+            .collect()
+    }
+
+    /// Marks the given notifications read, or all of them when `ids` is None.
+    pub fn mark_notifications_read(&self, ids: Option<&[i64]>) -> Result<()> {
+        let (query, params) = match ids {
+            Some([]) => return Ok(()),
+            Some(ids) => (
+                format!(
+                    "UPDATE notifications SET read = 1 WHERE id IN ({})",
+                    vec!["?"; ids.len()].join(", ")
+                ),
+                ids.iter().map(|id| (*id).into()).collect(),
+            ),
+            None => ("UPDATE notifications SET read = 1".to_string(), vec![]),
+        };
+        self.inner.write(query, params, None)?;
+        Ok(())
+    }
+
+    pub fn insert_photo(&self, photo: &Photo) -> Result<()> {
+        let query = "INSERT INTO photos (id, mime, size, created_at, taken_at, latitude, longitude) VALUES (?, ?, ?, ?, ?, ?, ?)";
+        let params = vec![
+            photo.id.to_string().into(),
+            photo.mime.clone().into(),
+            photo.size.into(),
+            photo.created_at.into(),
+            photo.taken_at.into(),
+            photo.latitude.into(),
+            photo.longitude.into(),
+        ];
+        self.inner.write(query.to_string(), params, None)?;
+        Ok(())
+    }
+
+    pub fn get_photo(&self, id: &Uuid) -> Result<Option<Photo>> {
+        let query = "SELECT * FROM photos WHERE id = ?";
+        let results = self
+            .inner
+            .read(query.to_string(), vec![id.to_string().into()])?;
+        let Some(row) = results.first() else {
+            return Ok(None);
+        };
+        let int = |key: &str| -> Result<i64> {
+            row.get(key)
+                .and_then(|v| v.as_i64())
+                .ok_or_else(|| anyhow!("Invalid {}", key))
+        };
+        Ok(Some(Photo {
+            id: *id,
+            mime: row
+                .get("mime")
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| anyhow!("Invalid mime"))?
+                .to_string(),
+            size: int("size")?,
+            created_at: int("created_at")?,
+            taken_at: row.get("taken_at").and_then(|v| v.as_i64()),
+            latitude: row.get("latitude").and_then(|v| v.as_f64()),
+            longitude: row.get("longitude").and_then(|v| v.as_f64()),
+        }))
+    }
+
+    /// Whether any location, ours or a friend's, lists the photo.
+    pub fn photo_in_use(&self, id: &Uuid) -> Result<bool> {
+        let query = "SELECT 1 FROM locations, json_each(locations.photos) WHERE json_each.value = ? LIMIT 1";
+        let results = self
+            .inner
+            .read(query.to_string(), vec![id.to_string().into()])?;
+        Ok(!results.is_empty())
+    }
+
+    /// Whether any location's visibility caps it at tier `name`.
+    pub fn tier_caps_locations(&self, name: &str) -> Result<bool> {
+        let query =
+            "SELECT 1 FROM locations WHERE json_extract(visibility, '$.max_tier') = ? LIMIT 1";
+        let results = self.inner.read(query.to_string(), vec![name.into()])?;
+        Ok(!results.is_empty())
+    }
+
+    /// Who lists the photo on a location, for photos we only know from friends' locations.
+    pub fn photo_owner(&self, id: &Uuid) -> Result<Option<NodeId>> {
+        let query = "SELECT owner FROM locations, json_each(locations.photos) WHERE json_each.value = ? LIMIT 1";
+        let results = self
+            .inner
+            .read(query.to_string(), vec![id.to_string().into()])?;
+        Ok(results
+            .first()
+            .and_then(|row| row["owner"].as_str())
+            .map(|owner| owner.to_string()))
+    }
+
+    pub fn delete_photo(&self, id: &Uuid) -> Result<()> {
+        let query = "DELETE FROM photos WHERE id = ?";
+        self.inner
+            .write(query.to_string(), vec![id.to_string().into()], None)?;
+        Ok(())
+    }
+
+    fn row_to_notification(
+        &self,
+        row: &HashMap<String, serde_json::Value>,
+    ) -> Result<Notification> {
+        let text = |key: &str| -> Result<String> {
+            row.get(key)
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("Invalid {}", key))
+        };
+        let int = |key: &str| -> Result<i64> {
+            row.get(key)
+                .and_then(|v| v.as_i64())
+                .ok_or_else(|| anyhow!("Invalid {}", key))
+        };
+        Ok(Notification {
+            id: int("id")?,
+            created_at: int("created_at")?,
+            friend: text("friend")?,
+            our_location: text("our_location")?.parse()?,
+            friend_location: text("friend_location")?.parse()?,
+            match_kind: serde_json::from_str(&text("match_kind")?)?,
+            overlap_start: int("overlap_start")?,
+            overlap_end: int("overlap_end")?,
+            message: text("message")?,
+            read: int("read")? != 0,
+        })
+    }
+
+    fn row_to_friend(&self, row: &HashMap<String, serde_json::Value>) -> Result<Friend> {
+        Ok(Friend {
+            node_id: row["node_id"]
+                .as_str()
+                .ok_or_else(|| anyhow!("Invalid node_id"))?
+                .to_string(),
+            friend_type: row["friend_type"]
+                .as_str()
+                .ok_or_else(|| anyhow!("Invalid friend_type"))?
+                .to_string(),
+            last_pinged: row["last_pinged"]
+                .as_i64()
+                .ok_or_else(|| anyhow!("Invalid last_pinged"))?,
+        })
+    }
+
     fn row_to_location(&self, row: &HashMap<String, serde_json::Value>) -> Result<Location> {
         Ok(Location {
             uuid: Uuid::parse_str(
@@ -421,27 +2145,112 @@ impl DB {
                 .map(|s| serde_json::from_str(s))
                 .transpose()?
                 .unwrap_or_default(), // Default to empty Vec if NULL
+            visibility: row
+                .get("visibility")
+                .and_then(|v| v.as_str())
+                .map(serde_json::from_str)
+                .transpose()?
+                .unwrap_or_default(),
+            public_summary: row
+                .get("public_summary")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            timezone: row
+                .get("timezone")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            shared_as: row
+                .get("shared_as")
+                .and_then(|v| v.as_str())
+                .and_then(|v| serde_json::from_str(v).ok())
+                .flatten(),
+            recurrence: row
+                .get("recurrence")
+                .and_then(|v| v.as_str())
+                .map(str::parse)
+                .transpose()?,
+            is_home: row
+                .get("is_home")
+                .and_then(|v| v.as_i64())
+                .is_some_and(|v| v != 0),
         })
     }
 }
 
 // time granularity helpers
 
-/// Rounds a timestamp to the nearest day. For FriendType::CloseFriend
-pub fn round_to_day(timestamp: i64) -> i64 {
-    let datetime = DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
-    let rounded = datetime.date_naive().and_hms_opt(0, 0, 0).unwrap();
-    rounded.and_utc().timestamp()
+// each None for a timestamp outside the dates chrono can represent
+
+/// Rounds a timestamp down to midnight in `tz`. For TimeGranularity::Day
+pub fn round_to_day(timestamp: i64, tz: Tz) -> Option<i64> {
+    let date = tz.timestamp_opt(timestamp, 0).single()?.date_naive();
+    local_midnight(date, tz)
+}
+
+/// Rounds a timestamp down to Monday midnight in `tz`. For TimeGranularity::Week
+pub fn round_to_week(timestamp: i64, tz: Tz) -> Option<i64> {
+    let date = tz.timestamp_opt(timestamp, 0).single()?.date_naive();
+    let weekday = date.weekday().num_days_from_monday() as i64;
+    local_midnight(date.checked_sub_signed(Duration::days(weekday))?, tz)
+}
+
+/// Rounds a timestamp to the start of its month in `tz`. For TimeGranularity::Month
+pub fn round_to_month(timestamp: i64, tz: Tz) -> Option<i64> {
+    let date = tz.timestamp_opt(timestamp, 0).single()?.date_naive();
+    local_midnight(date.with_day(1)?, tz)
 }
 
-/// Rounds a timestamp to the nearest week. For FriendType::Aacquaintance
-pub fn round_to_week(timestamp: i64) -> i64 {
-    let datetime = DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
-    let weekday = datetime.weekday().num_days_from_monday() as i64;
-    let rounded = datetime.date_naive().and_hms_opt(0, 0, 0).unwrap() - Duration::days(weekday);
-    rounded.and_utc().timestamp()
+/// The first instant of `date` in `tz`. Where a DST change skips midnight
+/// (e.g. America/Santiago) the day starts at 01:00.
+fn local_midnight(date: NaiveDate, tz: Tz) -> Option<i64> {
+    let midnight = date.and_hms_opt(0, 0, 0)?;
+    let timestamp = tz
+        .from_local_datetime(&midnight)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&midnight.checked_add_signed(Duration::hours(1))?)
+                .earliest()
+        })
+        .map(|datetime| datetime.timestamp())
+        .unwrap_or_else(|| midnight.and_utc().timestamp());
+    Some(timestamp)
+}
+
+/// Rounds a timestamp up to the next boundary of `round`, so ranges only ever widen.
+fn round_up(timestamp: i64, round: impl Fn(i64) -> Option<i64>) -> Option<i64> {
+    let rounded = round(timestamp)?;
+    if rounded == timestamp {
+        return Some(timestamp);
+    }
+    // step forward until we land in the next period; a month is at most 31 days,
+    // and a day is 23 to 25 hours long around DST changes.
+    let mut next = rounded;
+    while round(next)? == rounded {
+        next += Duration::days(1).num_seconds();
+    }
+    round(next)
+}
+
+/// How far past its start an open-ended range expands recurring and home locations.
+pub const EXPANSION_HORIZON_SECS: i64 = 366 * 24 * 60 * 60;
+
+/// The intervals a series or home row covers around `start..=end`.
+fn series_intervals(location: &Location, start: i64, end: i64) -> Vec<(i64, i64)> {
+    match &location.recurrence {
+        Some(recurrence) => recurrence.occurrences(
+            location.start_date,
+            location.end_date,
+            location.tz(),
+            start,
+            end,
+        ),
+        None => vec![(location.start_date, location.end_date)],
+    }
 }
 
+/// One of our locations as the API creates or replaces it.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewLocation {
     pub start_date: i64,
@@ -450,16 +2259,475 @@ pub struct NewLocation {
     pub latitude: f64,
     pub longitude: f64,
     pub photos: Vec<String>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    #[serde(default)]
+    pub public_summary: String,
+    /// IANA zone, resolved from the coordinates when not given
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub recurrence: Option<Recurrence>,
+    #[serde(default)]
+    pub is_home: bool,
+}
+
+/// Filters for listing locations, combined with AND. Empty matches everything.
+#[derive(Debug, Clone, Default)]
+pub struct LocationFilter {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub bbox: Option<BoundingBox>,
+    pub near: Option<Circle>,
+    pub owner: Option<NodeId>,
+    pub tier: Option<TierName>, // locations owned by friends in this tier
+    pub list: Option<String>,   // locations owned by members of this custom list
+    pub text: Option<String>,   // full-text search over description and public summary
+    pub sort: SortBy,
+    pub order: SortOrder,
+    pub limit: Option<u32>,
+    pub cursor: Option<Cursor>,
+}
+
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SortBy {
+    #[default]
+    StartDate,
+    EndDate,
+}
+
+impl SortBy {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "start_date" => Ok(Self::StartDate),
+            "end_date" => Ok(Self::EndDate),
+            _ => Err(
+                ApiError::BadRequest("invalid sort: expected start_date or end_date".into()).into(),
+            ),
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            Self::StartDate => "start_date",
+            Self::EndDate => "end_date",
+        }
+    }
+
+    fn key(&self, location: &Location) -> i64 {
+        match self {
+            Self::StartDate => location.start_date,
+            Self::EndDate => location.end_date,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "asc" => Ok(Self::Asc),
+            "desc" => Ok(Self::Desc),
+            _ => Err(ApiError::BadRequest("invalid order: expected asc or desc".into()).into()),
+        }
+    }
+
+    /// Compares two (sort key, uuid) pairs the way the SQL ORDER BY does.
+    pub fn cmp(&self, a: (i64, Uuid), b: (i64, Uuid)) -> Ordering {
+        match self {
+            SortOrder::Asc => a.cmp(&b),
+            SortOrder::Desc => b.cmp(&a),
+        }
+    }
+}
+
+/// Where the next page starts: the sort key and uuid of the last location returned.
+/// Only meaningful with the same filter and sort it was returned for.
+#[derive(Debug, Clone, Copy)]
+pub struct Cursor {
+    pub key: i64,
+    pub uuid: Uuid,
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || ApiError::BadRequest("invalid cursor".into());
+        let (key, uuid) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            key: key.parse().map_err(|_| invalid())?,
+            uuid: uuid.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.key, self.uuid)
+    }
+}
+
+pub struct LocationPage {
+    pub locations: Vec<Location>,
+    pub next_cursor: Option<Cursor>, // None on the last page, or when no limit was given
+}
+
+/// Turns free text into an FTS5 query matching every word, as prefixes.
+/// Quoting each word keeps user input from being read as FTS syntax (AND, NEAR, column:...).
+fn fts_query(text: &str) -> Result<String> {
+    let terms = text
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    if terms.is_empty() {
+        return Err(ApiError::Invalid("invalid q: empty search".into()).into());
+    }
+    Ok(terms.join(" "))
+}
+
+/// A latitude/longitude box. `west > east` means it crosses the antimeridian,
+/// e.g. west 170, east -170 covers the 20 degrees around +-180.
+#[derive(Debug, Clone, Copy)]
+pub struct BoundingBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl BoundingBox {
+    /// Parses "west,south,east,north" in degrees, the GeoJSON bbox order.
+    pub fn parse(s: &str) -> Result<Self> {
+        let parts = s
+            .split(',')
+            .map(|part| part.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| {
+                ApiError::BadRequest("invalid bbox: expected west,south,east,north".into())
+            })?;
+        let [west, south, east, north] = parts[..] else {
+            return Err(ApiError::BadRequest(
+                "invalid bbox: expected west,south,east,north".into(),
+            )
+            .into());
+        };
+        if !(-180.0..=180.0).contains(&west) || !(-180.0..=180.0).contains(&east) {
+            return Err(ApiError::Invalid(
+                "invalid bbox: longitudes must be within -180..180".into(),
+            )
+            .into());
+        }
+        if !(-90.0..=90.0).contains(&south) || !(-90.0..=90.0).contains(&north) || south > north {
+            return Err(ApiError::Invalid(
+                "invalid bbox: latitudes must be within -90..90 with south <= north".into(),
+            )
+            .into());
+        }
+        Ok(Self {
+            west,
+            south,
+            east,
+            north,
+        })
+    }
+
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.west > self.east
+    }
+}
+
+/// Everything within `radius_m` metres of a point, measured along the earth's surface.
+#[derive(Debug, Clone, Copy)]
+pub struct Circle {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub radius_m: f64,
+}
+
+impl Circle {
+    pub fn new(latitude: f64, longitude: f64, radius_m: f64) -> Result<Self> {
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(ApiError::Invalid("invalid lat/lon: out of range".into()).into());
+        }
+        if radius_m.is_nan() || radius_m <= 0.0 {
+            return Err(ApiError::Invalid("invalid radius: must be positive".into()).into());
+        }
+        Ok(Self {
+            latitude,
+            longitude,
+            radius_m,
+        })
+    }
+
+    /// The smallest box containing the circle, widened to all longitudes near the poles.
+    pub fn bounding_box(&self) -> BoundingBox {
+        let angular = self.radius_m / EARTH_RADIUS_M;
+        let south = (self.latitude - angular.to_degrees()).max(-90.0);
+        let north = (self.latitude + angular.to_degrees()).min(90.0);
+        let lat = self.latitude.to_radians();
+        if south <= -90.0 || north >= 90.0 || angular.sin() >= lat.cos() {
+            // the circle covers a pole, every longitude is in range
+            return BoundingBox {
+                west: -180.0,
+                south,
+                east: 180.0,
+                north,
+            };
+        }
+        let delta = (angular.sin() / lat.cos()).asin().to_degrees();
+        let wrap = |lon: f64| (lon + 540.0).rem_euclid(360.0) - 180.0;
+        BoundingBox {
+            west: wrap(self.longitude - delta),
+            south,
+            east: wrap(self.longitude + delta),
+            north,
+        }
+    }
+
+    pub fn contains(&self, longitude: f64, latitude: f64) -> bool {
+        haversine_m(self.latitude, self.longitude, latitude, longitude) <= self.radius_m
+    }
 }
 
-const CREATE_LOCATIONS_TABLE: &str = "
-CREATE TABLE IF NOT EXISTS locations (
-    uuid TEXT PRIMARY KEY,
-    start_date INTEGER NOT NULL,
-    end_date INTEGER,
-    owner TEXT NOT NULL,
-    description TEXT,
-    latitude REAL NOT NULL,
-    longitude REAL NOT NULL,
-    photos TEXT
-);";
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// Great-circle distance in metres.
+pub fn haversine_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiers_leaving_out_policies_get_the_seeded_ones() {
+        let policies = |json: &str| {
+            let tier: Tier = serde_json::from_str(json).unwrap();
+            (tier.description, tier.photos, tier.photo_size)
+        };
+        assert_eq!(
+            policies(r#"{"name":"Best","location":"Exact","time":"Exact"}"#),
+            (
+                DescriptionPolicy::Full,
+                PhotoPolicy::All,
+                PhotoSize::Original
+            )
+        );
+        assert_eq!(
+            policies(r#"{"name":"CloseFriend","location":"City","time":"Day"}"#),
+            (
+                DescriptionPolicy::PublicSummary,
+                PhotoPolicy::All,
+                PhotoSize::Web
+            )
+        );
+        // a tier of the user's own gets the least, what it sets itself is kept
+        assert_eq!(
+            policies(r#"{"name":"Family","location":"City","time":"Day","photos":"All"}"#),
+            (
+                DescriptionPolicy::Hidden,
+                PhotoPolicy::All,
+                PhotoSize::Thumb
+            )
+        );
+    }
+
+    const DAY: i64 = 86_400;
+
+    fn state() -> State {
+        State::in_memory(GranularityProtocol::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            [7; 32],
+        ))
+    }
+
+    fn location(latitude: f64, longitude: f64, start_date: i64) -> Location {
+        Location {
+            uuid: Uuid::new_v4(),
+            start_date,
+            end_date: start_date + 3600,
+            owner: "alice.os".into(),
+            description: String::new(),
+            latitude,
+            longitude,
+            photos: vec![],
+            visibility: Visibility::default(),
+            public_summary: String::new(),
+            timezone: "UTC".into(),
+            shared_as: None,
+            recurrence: None,
+            is_home: false,
+        }
+    }
+
+    /// The uuids `filter` lists, in order.
+    fn listed(state: &State, filter: &LocationFilter) -> Vec<Uuid> {
+        let page = state.get_locations(filter).unwrap();
+        page.locations
+            .iter()
+            .map(|location| location.uuid)
+            .collect()
+    }
+
+    #[test]
+    fn a_bbox_can_cross_the_antimeridian() {
+        let state = state();
+        let fiji = location(-17.7, 178.0, 0);
+        let samoa = location(-13.8, -172.0, DAY);
+        let greenwich = location(-15.0, 0.0, 2 * DAY);
+        for location in [&fiji, &samoa, &greenwich] {
+            state.db.insert_location(location).unwrap();
+        }
+
+        let across = BoundingBox::parse("170,-20,-170,-10").unwrap();
+        assert!(across.crosses_antimeridian());
+        let filter = LocationFilter {
+            bbox: Some(across),
+            ..Default::default()
+        };
+        assert_eq!(listed(&state, &filter), vec![fiji.uuid, samoa.uuid]);
+
+        let around_greenwich = LocationFilter {
+            bbox: Some(BoundingBox::parse("-10,-20,10,-10").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(listed(&state, &around_greenwich), vec![greenwich.uuid]);
+        assert!(BoundingBox::parse("0,-10,10,-20").is_err()); // south of its own south
+    }
+
+    #[test]
+    fn circles_reach_over_the_poles_and_across_the_antimeridian() {
+        let near_pole = Circle::new(89.9, 0.0, 50_000.0).unwrap();
+        let bbox = near_pole.bounding_box();
+        assert_eq!((bbox.west, bbox.east), (-180.0, 180.0));
+        // across the pole from the centre, 0.3 degrees away
+        assert!(near_pole.contains(179.0, 89.8));
+        assert!(!near_pole.contains(0.0, 89.0));
+
+        let dateline = Circle::new(0.0, 179.9, 50_000.0).unwrap();
+        let bbox = dateline.bounding_box();
+        assert!(bbox.crosses_antimeridian());
+        assert!(bbox.west < 179.9 && bbox.east > -180.0 && bbox.east < -179.0);
+
+        let state = state();
+        let pole = location(89.8, 179.0, 0);
+        let east = location(0.0, -179.9, DAY);
+        let too_far = location(0.0, 179.0, 2 * DAY);
+        for location in [&pole, &east, &too_far] {
+            state.db.insert_location(location).unwrap();
+        }
+        let within = |circle: Circle| {
+            listed(
+                &state,
+                &LocationFilter {
+                    near: Some(circle),
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(within(near_pole), vec![pole.uuid]);
+        assert_eq!(within(dateline), vec![east.uuid]);
+    }
+
+    #[test]
+    fn pages_through_one_offs_and_occurrences_starting_together() {
+        let state = state();
+        let start = 1_720_000_000;
+        // a one-off at 09:00 every day, and a daily series at the same time
+        let mut expected: Vec<(i64, Uuid)> = Vec::new();
+        for day in 0..5 {
+            let one_off = location(0.0, 0.0, start + day * DAY);
+            state.db.insert_location(&one_off).unwrap();
+            expected.push((one_off.start_date, one_off.uuid));
+        }
+        let series = Location {
+            recurrence: Some("FREQ=DAILY;COUNT=5".parse().unwrap()),
+            ..location(0.0, 0.0, start)
+        };
+        state.db.insert_location(&series).unwrap();
+        expected.extend((0..5).map(|day| (start + day * DAY, series.uuid)));
+
+        for order in [SortOrder::Asc, SortOrder::Desc] {
+            expected.sort_by(|a, b| order.cmp(*a, *b));
+            let mut seen = Vec::new();
+            let mut cursor = None;
+            for _ in 0..10 {
+                let page = state
+                    .get_locations(&LocationFilter {
+                        start: Some(start - DAY),
+                        end: Some(start + 10 * DAY),
+                        order,
+                        limit: Some(3),
+                        cursor,
+                        ..Default::default()
+                    })
+                    .unwrap();
+                assert!(page.locations.len() <= 3);
+                seen.extend(
+                    page.locations
+                        .iter()
+                        .map(|location| (location.start_date, location.uuid)),
+                );
+                cursor = page.next_cursor;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+            assert_eq!(seen, expected, "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn searches_for_words_not_fts_syntax() {
+        assert_eq!(fts_query("lisbon  porto").unwrap(), r#""lisbon"* "porto"*"#);
+        assert_eq!(
+            fts_query(r#"say "hi" OR *"#).unwrap(),
+            r#""say"* """hi"""* "OR"* "*"*"#
+        );
+        assert!(fts_query("  ").is_err());
+
+        let state = state();
+        let both = Location {
+            description: "Lisbon OR Porto".into(),
+            ..location(0.0, 0.0, 0)
+        };
+        let quoted = Location {
+            description: r#"the "Porto" trip"#.into(),
+            ..location(0.0, 0.0, DAY)
+        };
+        state.db.insert_location(&both).unwrap();
+        state.db.insert_location(&quoted).unwrap();
+        let search = |text: &str| {
+            listed(
+                &state,
+                &LocationFilter {
+                    text: Some(text.into()),
+                    ..Default::default()
+                },
+            )
+        };
+        // OR is a word to find, not an operator, and every word has to match
+        assert_eq!(search("Porto OR"), vec![both.uuid]);
+        assert_eq!(search("port"), vec![both.uuid, quoted.uuid]);
+        assert_eq!(search(r#""Porto" trip"#), vec![quoted.uuid]);
+        assert!(search("NEAR(lisbon porto)").is_empty());
+        assert!(search("*").is_empty());
+    }
+}
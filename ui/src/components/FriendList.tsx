@@ -2,16 +2,17 @@ import React, { useState, useEffect } from 'react';
 import useStore from '../store';
 
 const FriendList: React.FC = () => {
-    const { friends, pendingRequests, fetchFriends, fetchPendingRequests, addFriend, acceptFriendRequest, rejectFriendRequest, removeFriend, pingFriend, cancelPendingRequest } = useStore();
+    const { friends, pendingRequests, tiers, fetchFriends, fetchPendingRequests, fetchTiers, addFriend, acceptFriendRequest, rejectFriendRequest, removeFriend, pingFriend, cancelPendingRequest } = useStore();
     const [showAddFriend, setShowAddFriend] = useState(false);
     const [newFriendId, setNewFriendId] = useState('');
-    const [newFriendType, setNewFriendType] = useState<'Best' | 'CloseFriend' | 'Acquaintance'>('Acquaintance');
-    const [acceptFriendType, setAcceptFriendType] = useState<{ [key: string]: 'Best' | 'CloseFriend' | 'Acquaintance' }>({});
+    const [newFriendType, setNewFriendType] = useState<string>('Acquaintance');
+    const [acceptFriendType, setAcceptFriendType] = useState<{ [key: string]: string }>({});
 
     useEffect(() => {
         fetchFriends();
         fetchPendingRequests();
-    }, [fetchFriends, fetchPendingRequests]);
+        fetchTiers();
+    }, [fetchFriends, fetchPendingRequests, fetchTiers]);
 
     const handleAddFriend = async (e: React.FormEvent) => {
         e.preventDefault();
@@ -40,7 +41,7 @@ const FriendList: React.FC = () => {
         }
     };
 
-    const getFriendTypeForRequest = (nodeId: string): 'Best' | 'CloseFriend' | 'Acquaintance' => {
+    const getFriendTypeForRequest = (nodeId: string): string => {
         return acceptFriendType[nodeId] || 'CloseFriend';
     };
 
@@ -70,11 +71,11 @@ const FriendList: React.FC = () => {
                     />
                     <select
                         value={newFriendType}
-                        onChange={(e) => setNewFriendType(e.target.value as 'Best' | 'CloseFriend' | 'Acquaintance')}
+                        onChange={(e) => setNewFriendType(e.target.value)}
                     >
-                        <option value="Best">Best</option>
-                        <option value="CloseFriend">Close Friend</option>
-                        <option value="Acquaintance">Acquaintance</option>
+                        {tiers.map((tier) => (
+                            <option key={tier.name} value={tier.name}>{tier.name}</option>
+                        ))}
                     </select>
                     <button type="submit">Add</button>
                     <button type="button" onClick={() => setShowAddFriend(false)}>Cancel</button>
@@ -92,12 +93,12 @@ const FriendList: React.FC = () => {
                                     value={getFriendTypeForRequest(request.node_id)}
                                     onChange={(e) => setAcceptFriendType({
                                         ...acceptFriendType,
-                                        [request.node_id]: e.target.value as 'Best' | 'CloseFriend' | 'Acquaintance'
+                                        [request.node_id]: e.target.value
                                     })}
                                 >
-                                    <option value="Best">Best</option>
-                                    <option value="CloseFriend">Close Friend</option>
-                                    <option value="Acquaintance">Acquaintance</option>
+                                    {tiers.map((tier) => (
+                                        <option key={tier.name} value={tier.name}>{tier.name}</option>
+                                    ))}
                                 </select>
                                 <button onClick={() => acceptFriendRequest(request.node_id, getFriendTypeForRequest(request.node_id))}>Accept</button>
                                 <button onClick={() => rejectFriendRequest(request.node_id)}>Reject</button>
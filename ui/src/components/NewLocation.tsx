@@ -1,7 +1,7 @@
 import React from 'react';
 import { MapBrowserEvent } from 'ol';
 import DateSlider from './DateSlider';
-import { NewLocationData } from '../store';
+import { NewLocationData, uploadPhotos } from '../store';
 
 interface NewLocationProps {
     onSubmit: (e: React.FormEvent) => void;
@@ -20,20 +20,28 @@ const NewLocation: React.FC<NewLocationProps> = ({
     setNewLocation,
     handleNewLocationDateChange
 }) => {
-    const handlePhotoUpload = (event: React.ChangeEvent<HTMLInputElement>) => {
+    const handlePhotoUpload = async (event: React.ChangeEvent<HTMLInputElement>) => {
         const files = event.target.files;
-        if (files) {
-            Array.from(files).forEach(file => {
-                const reader = new FileReader();
-                reader.onloadend = () => {
-                    const base64String = reader.result as string;
-                    setNewLocation(prev => ({
-                        ...prev,
-                        photos: [...prev.photos, base64String.split(',')[1]]
-                    }));
-                };
-                reader.readAsDataURL(file);
-            });
+        if (files && files.length > 0) {
+            try {
+                const { photos, suggestion } = await uploadPhotos(Array.from(files));
+                // the photos' EXIF fills in what hasn't been set yet
+                setNewLocation(prev => ({
+                    ...prev,
+                    photos: [...prev.photos, ...photos],
+                    ...(prev.photos.length === 0 && suggestion?.start_date != null && {
+                        start_date: suggestion.start_date,
+                        end_date: Math.max(suggestion.end_date ?? 0, suggestion.start_date + 60 * 60),
+                    }),
+                    ...(prev.latitude === null && suggestion?.latitude != null && {
+                        latitude: suggestion.latitude,
+                        longitude: suggestion.longitude,
+                    }),
+                    description: prev.description || suggestion?.city || '',
+                }));
+            } catch (error) {
+                console.error('Error uploading photos:', error);
+            }
         }
     };
 
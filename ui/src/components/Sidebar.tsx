@@ -1,5 +1,5 @@
 import React from 'react';
-import { Location, NewLocationData } from '../store';
+import { Location, NewLocationData, photoUrl, retryPhoto } from '../store';
 import { MapBrowserEvent } from 'ol';
 import NewLocation from './NewLocation';
 
@@ -57,12 +57,16 @@ const Sidebar: React.FC<SidebarProps> = ({
                         <h3>{selectedLocation.owner}: {selectedLocation.description}</h3>
                         <p>Coordinates: ({selectedLocation.latitude.toFixed(4)}, {selectedLocation.longitude.toFixed(4)})</p>
                         <p>Date: {formatDate(selectedLocation.start_date)} - {formatDate(selectedLocation.end_date)}</p>
+                        {selectedLocation.local && selectedLocation.local.timezone !== selectedLocation.viewer?.timezone && (
+                            <p>Local time ({selectedLocation.local.timezone}): {selectedLocation.local.start.slice(0, 16).replace('T', ' ')} - {selectedLocation.local.end.slice(0, 16).replace('T', ' ')}</p>
+                        )}
                         {selectedLocation.photos.length > 0 && (
                             <div className="photo-gallery">
                                 {selectedLocation.photos.map((photo, index) => (
                                     <img
                                         key={index}
-                                        src={`data:image/jpeg;base64,${photo}`}
+                                        src={photoUrl(photo)}
+                                        onError={(e) => retryPhoto(e.currentTarget)}
                                         alt={`Photo ${index + 1}`}
                                         className="location-photo"
                                     />
@@ -80,7 +84,8 @@ const Sidebar: React.FC<SidebarProps> = ({
                             >
                                 {location.photos.length > 0 && (
                                     <img
-                                        src={`data:image/jpeg;base64,${location.photos[0]}`}
+                                        src={photoUrl(location.photos[0], 'thumb')}
+                                        onError={(e) => retryPhoto(e.currentTarget)}
                                         alt="Location thumbnail"
                                         className="location-thumbnail"
                                     />
@@ -1,4 +1,4 @@
-import React, { useCallback, useState } from 'react';
+import React, { useCallback, useEffect, useState } from 'react';
 import { MapBrowserEvent } from 'ol';
 import { toLonLat } from 'ol/proj';
 import DateSlider from '../components/DateSlider';
@@ -25,11 +25,12 @@ function Home(): JSX.Element {
 
   const [activeTab, setActiveTab] = useState<'map' | 'calendar' | 'friends'>('map');
 
-  const { locations, selectedLocation, dateRange, setLocations, setSelectedLocation, setDateRange } = useStore();
+  const { locations, selectedLocation, dateRange, setLocations, setSelectedLocation, setDateRange, fetchFriends, fetchPendingRequests } = useStore();
 
   const fetchLocations = useCallback(async (start: Date, end: Date): Promise<void> => {
     try {
-      const response = await fetch(`${BASE_URL}/api/locations?start=${Math.floor(start.getTime() / 1000)}&end=${Math.floor(end.getTime() / 1000)}`);
+      const tz = encodeURIComponent(Intl.DateTimeFormat().resolvedOptions().timeZone);
+      const response = await fetch(`${BASE_URL}/api/locations?start=${Math.floor(start.getTime() / 1000)}&end=${Math.floor(end.getTime() / 1000)}&tz=${tz}`);
       const data = await response.json();
       setLocations(data);
     } catch (error) {
@@ -62,6 +63,31 @@ function Home(): JSX.Element {
     fetchLocations(start, end);
   }, [setDateRange, fetchLocations]);
 
+  // live updates from the node, refetch whatever an event touches instead of polling
+  useEffect(() => {
+    const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
+    const ws = new WebSocket(`${protocol}//${window.location.host}${BASE_URL}/ws`);
+    ws.onmessage = (message) => {
+      const event = JSON.parse(message.data);
+      switch (event.type) {
+        case 'friend_request':
+          fetchPendingRequests();
+          break;
+        case 'friend_accepted':
+          fetchFriends();
+          fetchPendingRequests();
+          break;
+        case 'location_added':
+        case 'location_updated':
+        case 'location_deleted':
+        case 'sync_completed':
+          fetchLocations(dateRange.start, dateRange.end);
+          break;
+      }
+    };
+    return () => ws.close();
+  }, [dateRange, fetchLocations, fetchFriends, fetchPendingRequests]);
+
   const handleNewLocationDateChange = useCallback((start: Date, end: Date) => {
     setNewLocation(prev => ({
       ...prev,
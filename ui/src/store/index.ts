@@ -1,6 +1,13 @@
 import { create } from 'zustand';
 import { addYears } from 'date-fns';
 
+export interface Visibility {
+    private: boolean;
+    max_tier: string | null;
+    allow: string[];
+    deny: string[];
+}
+
 export interface Location {
     id: string;
     description: string;
@@ -10,6 +17,20 @@ export interface Location {
     start_date: number;
     end_date: number;
     photos: string[];
+    visibility?: Visibility;
+    public_summary?: string;
+    timezone?: string;
+    local?: LocalTimes;
+    viewer?: LocalTimes;
+    recurrence?: string; // RRULE subset, e.g. "FREQ=YEARLY;UNTIL=20300101T000000Z"
+    is_home?: boolean;
+}
+
+// a location's times rendered in one zone, RFC 3339 with the offset
+export interface LocalTimes {
+    timezone: string;
+    start: string;
+    end: string;
 }
 
 export interface DateRange {
@@ -19,13 +40,38 @@ export interface DateRange {
 
 export interface Friend {
     node_id: string;
-    friend_type: 'Best' | 'CloseFriend' | 'Acquaintance';
+    friend_type: string;
     last_pinged: number;
 }
 
+export type LocationGranularity =
+    | 'Exact'
+    | { Neighbourhood: { radius_m: number } }
+    | 'City'
+    | 'Region'
+    | 'Country'
+    | 'Hidden';
+
+export type TimeGranularity = 'Exact' | 'Day' | 'Week' | 'Month' | 'Hidden';
+
+export type DescriptionPolicy = 'Full' | 'PublicSummary' | 'Hidden';
+
+export type PhotoPolicy = 'All' | { Limit: { max: number } } | 'Hidden';
+
+export type PhotoSize = 'Original' | 'Web' | 'Thumb';
+
+export interface Tier {
+    name: string;
+    location: LocationGranularity;
+    time: TimeGranularity;
+    description: DescriptionPolicy;
+    photos: PhotoPolicy;
+    photo_size: PhotoSize;
+}
+
 export interface PendingRequest {
     node_id: string;
-    friend_type: 'Best' | 'CloseFriend' | 'Acquaintance';
+    friend_type: string;
     last_pinged: number;
     is_local: boolean;
 }
@@ -37,6 +83,8 @@ export interface NewLocationData {
     start_date: number;
     end_date: number;
     photos: string[];
+    visibility?: Visibility;
+    public_summary?: string;
 }
 
 
@@ -46,6 +94,7 @@ interface AppState {
     dateRange: DateRange;
     friends: Friend[];
     pendingRequests: PendingRequest[];
+    tiers: Tier[];
     setLocations: (locations: Location[]) => void;
     setSelectedLocation: (location: Location | null) => void;
     setDateRange: (range: DateRange) => void;
@@ -53,8 +102,9 @@ interface AppState {
     setPendingRequests: (requests: PendingRequest[]) => void;
     fetchFriends: () => Promise<void>;
     fetchPendingRequests: () => Promise<void>;
-    addFriend: (nodeId: string, friendType: 'Best' | 'CloseFriend' | 'Acquaintance') => Promise<void>;
-    acceptFriendRequest: (nodeId: string, friendType: 'Best' | 'CloseFriend' | 'Acquaintance') => Promise<void>;
+    fetchTiers: () => Promise<void>;
+    addFriend: (nodeId: string, friendType: string) => Promise<void>;
+    acceptFriendRequest: (nodeId: string, friendType: string) => Promise<void>;
     rejectFriendRequest: (nodeId: string) => Promise<void>;
     removeFriend: (nodeId: string) => Promise<void>;
     pingFriend: (nodeId: string) => Promise<void>;
@@ -64,6 +114,53 @@ interface AppState {
 // @ts-ignore
 const BASE_URL = import.meta.env.BASE_URL;
 
+const PHOTO_ID = /^[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}$/i;
+
+// photos are uploaded ids, older locations still carry inline base64 jpegs
+export const photoUrl = (photo: string, size: 'original' | 'web' | 'thumb' = 'web') =>
+    PHOTO_ID.test(photo)
+        ? `${BASE_URL}/api/photos/${photo}?size=${size}`
+        : `data:image/jpeg;base64,${photo}`;
+
+// a friend's photo answers 202 while it's fetched from them, so a failed <img> tries again
+// a few times, further apart each time
+const PHOTO_RETRIES = 5;
+export const retryPhoto = (img: HTMLImageElement) => {
+    const tries = Number(img.dataset.tries ?? 0);
+    if (tries >= PHOTO_RETRIES || img.src.startsWith('data:')) return;
+    img.dataset.tries = String(tries + 1);
+    const url = new URL(img.src);
+    url.searchParams.set('retry', String(tries + 1));
+    setTimeout(() => { img.src = url.toString(); }, 1000 * (tries + 1));
+};
+
+// where and when uploaded photos were taken per their EXIF, null where none tells
+export interface LocationSuggestion {
+    start_date: number | null;
+    end_date: number | null;
+    latitude: number | null;
+    longitude: number | null;
+    city: string | null;
+}
+
+export interface PhotoUpload {
+    photos: string[];
+    suggestion: LocationSuggestion | null;
+}
+
+// uploads images, resolving to their ids for Location.photos
+export const uploadPhotos = async (files: File[]): Promise<PhotoUpload> => {
+    const form = new FormData();
+    files.forEach(file => form.append('file', file, file.name));
+    const tz = encodeURIComponent(Intl.DateTimeFormat().resolvedOptions().timeZone);
+    const response = await fetch(`${BASE_URL}/api/photos?tz=${tz}`, { method: 'POST', body: form });
+    const data = await response.json();
+    if (!response.ok) {
+        throw new Error(data.error);
+    }
+    return data;
+};
+
 const useStore = create<AppState>((set, get) => ({
     locations: [],
     selectedLocation: null,
@@ -73,6 +170,7 @@ const useStore = create<AppState>((set, get) => ({
     },
     friends: [],
     pendingRequests: [],
+    tiers: [],
     setLocations: (locations) => set({ locations }),
     setSelectedLocation: (location) => set({ selectedLocation: location }),
     setDateRange: (range) => set({ dateRange: range }),
@@ -105,6 +203,16 @@ const useStore = create<AppState>((set, get) => ({
         }
     },
 
+    fetchTiers: async () => {
+        try {
+            const response = await fetch(`${BASE_URL}/api/tiers`);
+            const data = await response.json();
+            set({ tiers: data });
+        } catch (error) {
+            console.error('Error fetching tiers:', error);
+        }
+    },
+
     addFriend: async (nodeId, friendType) => {
         try {
             const response = await fetch(`${BASE_URL}/api/friends`, {